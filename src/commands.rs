@@ -22,6 +22,22 @@ pub enum ImageEffectContext {
     Transition,
 }
 
+/// Error metric for CompareImages, calculated separately for each
+/// channel
+#[derive(clap::ValueEnum, Deserialize, Serialize, Copy, Clone, Debug, Default)]
+pub enum CompareMetric {
+    /// Largest absolute difference
+    #[default]
+    MaxAbsError,
+    /// Root mean square error
+    Rmse,
+    /// Peak signal to noise ratio, in dB. Tolerance is the minimum
+    /// acceptable value.
+    Psnr,
+    /// Number of pixels whose difference is greater than `threshold`
+    PixelsOverThreshold,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct RenderLayout {
     pub project_dims: (f64, f64),
@@ -39,22 +55,279 @@ pub enum PropertyValue {
     Int(c_int),
 }
 
-fn default_origin() -> (i32, i32) {
-    (0, 0)
+/// File format for reading and writing images
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    Exr,
+    Png,
+    Tiff,
+    /// Headerless little-endian f32 samples, with dimensions in a
+    /// JSON sidecar file
+    Raw,
 }
 
-#[derive(Deserialize, Serialize)]
+/// Layer and channels to read from an EXR file, written as
+/// "layer.R,G,B,A". The layer prefix is optional. One channel is read
+/// as Alpha, two as grey and alpha, three as RGB and four as RGBA.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(try_from = "String", into = "String")]
+pub struct ChannelSpec {
+    pub layer: Option<String>,
+    pub channels: Vec<String>,
+}
+
+impl TryFrom<String> for ChannelSpec {
+    type Error = String;
+
+    fn try_from(spec: String) -> Result<Self, Self::Error> {
+        let mut channels: Vec<String> = spec.split(',').map(str::to_string).collect();
+        let layer = match channels[0].rsplit_once('.') {
+            Some((layer, first)) => {
+                let layer = layer.to_string();
+                channels[0] = first.to_string();
+                Some(layer)
+            }
+            None => None,
+        };
+        if channels.len() > 4 || channels.iter().any(|c| c.is_empty()) {
+            return Err(format!("Invalid channel spec \"{}\"", spec));
+        }
+        Ok(ChannelSpec { layer, channels })
+    }
+}
+
+impl From<ChannelSpec> for String {
+    fn from(spec: ChannelSpec) -> String {
+        let channels = spec.channels.join(",");
+        match spec.layer {
+            Some(layer) => format!("{}.{}", layer, channels),
+            None => channels,
+        }
+    }
+}
+
+/// Sample type used when writing images
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleDepth {
+    U8,
+    U16,
+    F32,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+pub enum ImageComponents {
+    #[default]
+    #[serde(rename = "RGBA")]
+    Rgba,
+    Alpha,
+}
+
+/// Pattern for a generated image. Colours are RGBA; Alpha images use
+/// the last component.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(tag = "type")]
+pub enum Pattern {
+    Constant {
+        colour: [f32; 4],
+    },
+    /// Squares of `size` pixels, alternating between two colours
+    Checkerboard {
+        size: u32,
+        colour1: [f32; 4],
+        colour2: [f32; 4],
+    },
+    /// Horizontal ramp from `start` at the left edge to `end` at the
+    /// right
+    Ramp {
+        start: [f32; 4],
+        end: [f32; 4],
+    },
+    /// Uniform random values in [0, 1) in every channel
+    Noise {
+        seed: u64,
+    },
+    /// Black image with a single pixel set. Position is in pixel
+    /// coordinates, so it includes the input origin.
+    HotPixel {
+        position: (i32, i32),
+        colour: [f32; 4],
+    },
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Generator {
+    pub pattern: Pattern,
+    pub size: (usize, usize),
+    #[serde(default)]
+    pub components: ImageComponents,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum InputSource {
+    /// Read the image from a file. The format is taken from the
+    /// extension unless given explicitly.
+    File {
+        filename: String,
+        #[serde(default)]
+        format: Option<ImageFormat>,
+        /// Layer and channels to read from an EXR file
+        #[serde(default)]
+        channels: Option<ChannelSpec>,
+    },
+    /// Generate the image in memory
+    Generated { generator: Generator },
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Input {
-    pub filename: String,
+    #[serde(flatten)]
+    pub source: InputSource,
     pub rowbytes: Option<usize>,
-    #[serde(default = "default_origin")]
-    pub origin: (i32, i32),
+    /// Position of the bottom left corner. Defaults to the data
+    /// window offset for EXR files, and (0, 0) otherwise.
+    #[serde(default)]
+    pub origin: Option<(i32, i32)>,
 }
 
 fn default_frame_range() -> (FrameNumber, FrameNumber) {
     (FrameNumber(0), FrameNumber(1))
 }
 
+/// Condition which makes the abort() suite function start returning
+/// true during a render
+#[derive(Deserialize, Serialize, Debug, Copy, Clone)]
+pub enum AbortTrigger {
+    /// Abort after abort() has been called this many times
+    AfterCalls(u32),
+    /// Abort after this many milliseconds have passed since the
+    /// render started
+    AfterMillis(u64),
+    /// Abort while rendering the given frame
+    OnFrame(FrameNumber),
+}
+
+fn default_abort_limit_ms() -> u64 {
+    1000
+}
+
+/// Where and how to write rendered images. Nothing is written unless
+/// `output_directory` or `output` is given.
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct OutputOptions {
+    pub output_directory: Option<String>,
+    /// Filename template, e.g. "{instance}_{frame:04}.{ext}". Relative
+    /// to output_directory if that is also given. Defaults to
+    /// "{frame}.{ext}".
+    #[serde(rename = "output")]
+    pub template: Option<String>,
+    /// Format of output images. Defaults to the template's extension,
+    /// or EXR.
+    pub output_format: Option<ImageFormat>,
+    /// Sample type of output images. Defaults to the widest type the
+    /// format supports, except for PNG which uses u8.
+    pub output_depth: Option<SampleDepth>,
+    /// Write each frame as a named part of a multi-part EXR, adding to
+    /// the file if it already exists
+    pub output_layer: Option<String>,
+    /// Fixed zero-padding for "{frame}". By default it's wide enough
+    /// for the last frame of the render.
+    pub frame_padding: Option<usize>,
+}
+
+/// How a failed output check is reported. Warnings are written to the
+/// output, errors fail the render.
+#[derive(Deserialize, Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CheckLevel {
+    Warning,
+    Error,
+}
+
+/// Checks on each rendered output image. Each check is only run if
+/// it's given a level.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct OutputChecks {
+    pub nan: Option<CheckLevel>,
+    pub infinity: Option<CheckLevel>,
+    pub negative_alpha: Option<CheckLevel>,
+    // Colour greater than alpha in a premultiplied image
+    pub premultiplication: Option<CheckLevel>,
+}
+
+/// Render more than once and check that every render gives the same
+/// output
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct DeterministicCheck {
+    /// Number of renders, used if thread_counts is empty
+    #[serde(default = "default_check_renders")]
+    pub renders: u32,
+    /// Render once with each of these thread counts, instead of
+    /// `renders` times with the render's thread_count
+    #[serde(default)]
+    pub thread_counts: Vec<u32>,
+    /// Largest difference allowed between samples of different
+    /// renders. With zero, the renders must match bit for bit.
+    #[serde(default)]
+    pub tolerance: f64,
+}
+
+fn default_check_renders() -> u32 {
+    2
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct RenderOptions {
+    #[serde(default = "default_frame_range")]
+    pub frame_range: (FrameNumber, FrameNumber),
+    // Split the frame range between this many threads. No more
+    // threads are used than there are frames.
+    #[serde(default)]
+    pub thread_count: u32,
+    // Give each render thread its own copy of the effect instance,
+    // instead of sharing one instance between threads
+    #[serde(default)]
+    pub instance_per_thread: bool,
+    pub abort: Option<AbortTrigger>,
+    // Maximum time the plugin may take to return from the render
+    // action after it has been told to abort
+    #[serde(default = "default_abort_limit_ms")]
+    pub abort_limit_ms: u64,
+    // Inputs for upstream instances connected with ConnectClip,
+    // keyed by instance name
+    #[serde(default)]
+    pub node_inputs: HashMap<String, HashMap<String, Input>>,
+    // Pass input images to the plugin in read-only memory, so writes
+    // to them crash with a report naming the clip
+    #[serde(default)]
+    pub protect_inputs: bool,
+    // Surround output buffers with guard bands, and fail if the
+    // plugin writes to them
+    #[serde(default)]
+    pub guard_output: bool,
+    #[serde(default)]
+    pub output_checks: OutputChecks,
+    pub deterministic_check: Option<DeterministicCheck>,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            frame_range: default_frame_range(),
+            thread_count: 0,
+            instance_per_thread: false,
+            abort: None,
+            abort_limit_ms: default_abort_limit_ms(),
+            node_inputs: HashMap::new(),
+            protect_inputs: false,
+            guard_output: false,
+            output_checks: OutputChecks::default(),
+            deterministic_check: None,
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 #[serde(tag = "type")]
 pub enum Command {
@@ -75,12 +348,11 @@ pub enum Command {
     Render {
         instance_name: String,
         inputs: HashMap<String, Input>,
-        output_directory: Option<String>,
+        #[serde(flatten)]
+        output: OutputOptions,
         layout: Option<RenderLayout>,
-        #[serde(default = "default_frame_range")]
-        frame_range: (FrameNumber, FrameNumber),
-        #[serde(default)]
-        thread_count: u32,
+        #[serde(flatten)]
+        options: RenderOptions,
     },
     /// Print params of an effect instance.
     PrintParams { instance_name: String },
@@ -123,8 +395,128 @@ pub enum Command {
         instance_name: String,
         responses: Vec<MessageSuiteResponses>,
     },
+    /// Connect the Output clip of one instance to an input clip of
+    /// another. Rendering the downstream instance will render the
+    /// upstream one first, and pass its output in memory.
+    ConnectClip {
+        source_instance: String,
+        instance_name: String,
+        clip_name: String,
+    },
+    /// Compare an image file with a reference, and fail if the
+    /// difference exceeds the tolerance. Optionally write the
+    /// difference to an image file on failure.
+    CompareImages {
+        image: String,
+        reference: String,
+        #[serde(default)]
+        metric: CompareMetric,
+        #[serde(default)]
+        tolerance: f64,
+        #[serde(default)]
+        threshold: f32,
+        region: Option<OfxRectI>,
+        diff_file: Option<String>,
+    },
+    /// Save the values of all params of an instance to a JSON preset
+    /// file
+    SaveParams {
+        instance_name: String,
+        filename: String,
+    },
+    /// Apply a preset saved by SaveParams. Optionally call
+    /// BeginInstanceChanged, InstanceChanged, and EndInstanceChanged,
+    /// as SetParams does.
+    LoadParams {
+        instance_name: String,
+        filename: String,
+        #[serde(default)]
+        call_instance_changed: bool,
+    },
+    /// Save an instance as a host would when saving a project: call
+    /// SyncPrivateData, then write the plugin, context and all param
+    /// values to a JSON file
+    SaveProject {
+        instance_name: String,
+        filename: String,
+    },
+    /// Load an instance saved by SaveProject, replacing any existing
+    /// instance with the same name. Params are restored before the
+    /// CreateInstance action. The saved instance name is used unless
+    /// one is given.
+    LoadProject {
+        filename: String,
+        instance_name: Option<String>,
+    },
+    /// Render an instance, save and reload it with SaveProject and
+    /// LoadProject, then render again and check that the output is
+    /// identical
+    CheckProjectRoundTrip {
+        instance_name: String,
+        filename: String,
+        inputs: HashMap<String, Input>,
+        layout: Option<RenderLayout>,
+        #[serde(flatten)]
+        options: RenderOptions,
+    },
+    /// Set a timeout for plugin actions in the following commands. If
+    /// an action takes longer, the run is aborted. Timeouts for
+    /// individual actions, keyed by action name, override the
+    /// default. A null timeout_ms removes the default. The settings
+    /// persist until the next SetActionTimeout.
+    SetActionTimeout {
+        timeout_ms: Option<u64>,
+        #[serde(default)]
+        actions: HashMap<String, u64>,
+    },
+    /// Run a list of commands as a single named test case in
+    /// reports. Stops at the first command which fails.
+    Group {
+        name: String,
+        commands: Vec<Command>,
+    },
     /// Set properties of the OfxHost instance
     SetHostProperties {
         props: HashMap<String, Vec<PropertyValue>>,
     },
 }
+
+impl Command {
+    /// Name of the command, as given in its "type" field
+    pub fn type_name(&self) -> &'static str {
+        use Command::*;
+        match self {
+            CreatePlugin { .. } => "CreatePlugin",
+            CreateInstance { .. } => "CreateInstance",
+            Render { .. } => "Render",
+            PrintParams { .. } => "PrintParams",
+            DestroyInstance { .. } => "DestroyInstance",
+            UnloadPlugin { .. } => "UnloadPlugin",
+            SetParams { .. } => "SetParams",
+            ListPlugins { .. } => "ListPlugins",
+            Describe { .. } => "Describe",
+            DescribeInContext { .. } => "DescribeInContext",
+            PrintRoIs { .. } => "PrintRoIs",
+            PrintRoD { .. } => "PrintRoD",
+            ConfigureMessageSuiteResponses { .. } => "ConfigureMessageSuiteResponses",
+            ConnectClip { .. } => "ConnectClip",
+            CompareImages { .. } => "CompareImages",
+            SaveParams { .. } => "SaveParams",
+            LoadParams { .. } => "LoadParams",
+            SaveProject { .. } => "SaveProject",
+            LoadProject { .. } => "LoadProject",
+            CheckProjectRoundTrip { .. } => "CheckProjectRoundTrip",
+            SetActionTimeout { .. } => "SetActionTimeout",
+            Group { .. } => "Group",
+            SetHostProperties { .. } => "SetHostProperties",
+        }
+    }
+
+    /// Name of a Group command
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Command::Group { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+}
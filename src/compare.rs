@@ -0,0 +1,321 @@
+//! Image comparison for golden-image regression tests.
+use crate::commands::CompareMetric;
+use crate::image_io::{read_image, write_image};
+use crate::{output, GenericResult, Image, ImagePixels, Rect};
+use anyhow::{bail, Result};
+use openfx_rs::types::OfxRectI;
+use serde::Serialize;
+use std::cmp::{max, min};
+use std::collections::BTreeMap;
+use std::error::Error;
+
+/// Error returned when images don't match within tolerance. Kept
+/// distinct from other errors so the runner can exit with its own
+/// status code.
+#[derive(Debug)]
+pub struct ComparisonFailed(pub String);
+
+impl std::fmt::Display for ComparisonFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for ComparisonFailed {}
+
+#[derive(Serialize)]
+struct ComparisonReport<'a> {
+    image: &'a str,
+    reference: &'a str,
+    metric: CompareMetric,
+    tolerance: f64,
+    region: OfxRectI,
+    // Value of the metric for each channel
+    channels: BTreeMap<&'static str, f64>,
+    passed: bool,
+}
+
+fn channel_names(pixels: &ImagePixels) -> &'static [&'static str] {
+    match pixels {
+        ImagePixels::Rgba(_) => &["R", "G", "B", "A"],
+        ImagePixels::Alpha(_) => &["A"],
+    }
+}
+
+fn channel_value(image: &Image, x: usize, y: usize, channel: usize) -> f32 {
+    let index = y * image.stride + x;
+    match &image.pixels {
+        ImagePixels::Rgba(v) => {
+            let p = &v[index];
+            [p.r, p.g, p.b, p.a][channel]
+        }
+        ImagePixels::Alpha(v) => v[index],
+    }
+}
+
+/// Absolute difference between two values. Matching NaNs and
+/// infinities count as equal, any other NaN as infinitely different.
+fn abs_diff(a: f32, b: f32) -> f64 {
+    if a == b || (a.is_nan() && b.is_nan()) {
+        0.0
+    } else {
+        let d = (a - b).abs();
+        if d.is_nan() {
+            f64::INFINITY
+        } else {
+            d as f64
+        }
+    }
+}
+
+fn metric_value(metric: CompareMetric, threshold: f32, diffs: &[f64]) -> f64 {
+    let mean_square = || diffs.iter().map(|d| d * d).sum::<f64>() / diffs.len() as f64;
+    match metric {
+        CompareMetric::MaxAbsError => diffs.iter().copied().fold(0.0, f64::max),
+        CompareMetric::Rmse => mean_square().sqrt(),
+        // Peak value is 1.0 for float images
+        CompareMetric::Psnr => -10.0 * mean_square().log10(),
+        CompareMetric::PixelsOverThreshold => {
+            diffs.iter().filter(|d| **d > threshold as f64).count() as f64
+        }
+    }
+}
+
+fn passes(metric: CompareMetric, tolerance: f64, value: f64) -> bool {
+    match metric {
+        // Higher PSNR is better, tolerance is the minimum
+        CompareMetric::Psnr => value >= tolerance,
+        _ => value <= tolerance,
+    }
+}
+
+fn same_layout(image: &Image, reference: &Image) -> bool {
+    let (a, b) = (image.bounds, reference.bounds);
+    (a.x1, a.y1, a.x2, a.y2) == (b.x1, b.y1, b.x2, b.y2)
+        && channel_names(&image.pixels) == channel_names(&reference.pixels)
+}
+
+/// Largest absolute difference between the samples of two images, or
+/// None if their bounds or components differ
+pub fn max_difference(image: &Image, reference: &Image) -> Option<f64> {
+    if !same_layout(image, reference) {
+        return None;
+    }
+
+    let a = image.bounds;
+    let channels = channel_names(&image.pixels);
+    let mut max_diff = 0.0;
+    for y in 0..a.height() {
+        for x in 0..a.width() {
+            for c in 0..channels.len() {
+                max_diff = f64::max(
+                    max_diff,
+                    abs_diff(
+                        channel_value(image, x, y, c),
+                        channel_value(reference, x, y, c),
+                    ),
+                );
+            }
+        }
+    }
+    Some(max_diff)
+}
+
+/// Summarise the samples of two images which differ by more than the
+/// tolerance, or None if there aren't any. With zero tolerance samples
+/// must match bit for bit, so NaN payloads and the sign of zero count.
+pub fn describe_differences(
+    image: &Image,
+    reference: &Image,
+    tolerance: f64,
+) -> Option<String> {
+    if !same_layout(image, reference) {
+        return Some("different size or components".to_string());
+    }
+
+    let a = image.bounds;
+    let channels = channel_names(&image.pixels);
+    let mut count = 0;
+    let mut max_diff = 0.0;
+    let mut first = None;
+    for y in 0..a.height() {
+        for x in 0..a.width() {
+            for (c, name) in channels.iter().enumerate() {
+                let value = channel_value(image, x, y, c);
+                let expected = channel_value(reference, x, y, c);
+                let diff = abs_diff(value, expected);
+                let differs = if tolerance == 0.0 {
+                    value.to_bits() != expected.to_bits()
+                } else {
+                    // The difference is NaN if either is NaN
+                    value.is_nan() != expected.is_nan() || diff > tolerance
+                };
+                if differs {
+                    count += 1;
+                    // Once a NaN differs, the max difference is NaN
+                    if diff.is_nan() || diff > max_diff {
+                        max_diff = diff;
+                    }
+                    first.get_or_insert((a.x1 + x as i32, a.y1 + y as i32, *name));
+                }
+            }
+        }
+    }
+    first.map(|(x, y, channel)| {
+        format!(
+            "{} samples differ, max difference {}, first at ({}, {}) in {}",
+            count, max_diff, x, y, channel
+        )
+    })
+}
+
+/// Write the absolute difference of two images with the same
+/// dimensions and components.
+fn write_diff_image(filename: &str, image: &Image, reference: &Image) -> GenericResult {
+    let bounds = OfxRectI {
+        x1: 0,
+        y1: 0,
+        x2: image.bounds.width() as i32,
+        y2: image.bounds.height() as i32,
+    };
+    let alpha = matches!(image.pixels, ImagePixels::Alpha(_));
+    let mut diff = Image::empty("diff", &bounds, None, alpha);
+    let stride = diff.stride;
+    for y in 0..bounds.height() {
+        for x in 0..bounds.width() {
+            let d = |c| {
+                abs_diff(
+                    channel_value(image, x, y, c),
+                    channel_value(reference, x, y, c),
+                ) as f32
+            };
+            match &mut diff.pixels {
+                ImagePixels::Rgba(v) => {
+                    let p = &mut v[y * stride + x];
+                    (p.r, p.g, p.b, p.a) = (d(0), d(1), d(2), d(3));
+                }
+                ImagePixels::Alpha(v) => v[y * stride + x] = d(0),
+            }
+        }
+    }
+    write_image(filename, None, None, diff)
+}
+
+/// Compare an image with a reference, print a JSON report, and fail
+/// with ComparisonFailed if any channel is out of tolerance.
+///
+/// `region` is in pixel coordinates relative to the bottom left of
+/// the image. The whole image is compared if it's missing.
+pub fn compare_images(
+    image_file: &str,
+    reference_file: &str,
+    metric: CompareMetric,
+    tolerance: f64,
+    threshold: f32,
+    region: Option<OfxRectI>,
+    diff_file: Option<&str>,
+) -> GenericResult {
+    let image = read_image("image", image_file, None, None, None, Some((0, 0)))?;
+    let reference =
+        read_image("reference", reference_file, None, None, None, Some((0, 0)))?;
+
+    let fail = |message: String| -> Result<()> {
+        if let Some(f) = diff_file {
+            if image.bounds.width() == reference.bounds.width()
+                && image.bounds.height() == reference.bounds.height()
+                && channel_names(&image.pixels) == channel_names(&reference.pixels)
+            {
+                write_diff_image(f, &image, &reference)?;
+            }
+        }
+        Err(ComparisonFailed(message).into())
+    };
+
+    if image.bounds.width() != reference.bounds.width()
+        || image.bounds.height() != reference.bounds.height()
+    {
+        return fail(format!(
+            "Image size {}x{} does not match reference {}x{}",
+            image.bounds.width(),
+            image.bounds.height(),
+            reference.bounds.width(),
+            reference.bounds.height()
+        ));
+    }
+    let channels = channel_names(&image.pixels);
+    if channels != channel_names(&reference.pixels) {
+        return fail(format!(
+            "Image channels {:?} do not match reference {:?}",
+            channels,
+            channel_names(&reference.pixels)
+        ));
+    }
+
+    let full = OfxRectI {
+        x1: 0,
+        y1: 0,
+        x2: image.bounds.width() as i32,
+        y2: image.bounds.height() as i32,
+    };
+    let region = region
+        .map(|r| OfxRectI {
+            x1: max(r.x1, full.x1),
+            y1: max(r.y1, full.y1),
+            x2: min(r.x2, full.x2),
+            y2: min(r.y2, full.y2),
+        })
+        .unwrap_or(full);
+    // Otherwise nothing would be compared, and the comparison would
+    // pass whatever the images contain
+    if region.x2 <= region.x1 || region.y2 <= region.y1 {
+        bail!(
+            "Region ({}, {}, {}, {}) is empty or outside the {}x{} image",
+            region.x1,
+            region.y1,
+            region.x2,
+            region.y2,
+            full.x2,
+            full.y2
+        );
+    }
+
+    let mut values = BTreeMap::new();
+    for (c, name) in channels.iter().enumerate() {
+        let mut diffs = Vec::new();
+        for y in region.y1..region.y2 {
+            for x in region.x1..region.x2 {
+                let (x, y) = (x as usize, y as usize);
+                diffs.push(abs_diff(
+                    channel_value(&image, x, y, c),
+                    channel_value(&reference, x, y, c),
+                ));
+            }
+        }
+        if !diffs.is_empty() {
+            values.insert(*name, metric_value(metric, threshold, &diffs));
+        }
+    }
+
+    let passed = values.values().all(|v| passes(metric, tolerance, *v));
+    output!(
+        "{}",
+        serde_json::to_string(&ComparisonReport {
+            image: image_file,
+            reference: reference_file,
+            metric,
+            tolerance,
+            region,
+            channels: values,
+            passed,
+        })?
+    );
+
+    if passed {
+        Ok(())
+    } else {
+        fail(format!(
+            "{} does not match {} within tolerance",
+            image_file, reference_file
+        ))
+    }
+}
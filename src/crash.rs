@@ -0,0 +1,258 @@
+//! Crash reports. When a plugin crashes the runner, a signal handler
+//! writes the action being run and the last few suite calls to
+//! stderr. Everything the handler reads is kept in atomics or
+//! constant-initialised thread locals, so it's safe to use from a
+//! signal handler.
+use std::cell::Cell;
+use std::ffi::{c_char, CStr};
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+// Number of suite calls kept for crash reports
+const CALL_HISTORY_LEN: usize = 16;
+
+static CALL_HISTORY: [AtomicPtr<&'static str>; CALL_HISTORY_LEN] =
+    [const { AtomicPtr::new(std::ptr::null_mut()) }; CALL_HISTORY_LEN];
+static NEXT_CALL: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // Action running on this thread, if any. A crash signal is handled
+    // on the thread which crashed, so the report names its action.
+    static CURRENT_ACTION: Cell<*const c_char> = const { Cell::new(std::ptr::null()) };
+    // Suite function running on this thread, if any
+    static CURRENT_FUNCTION: Cell<Option<&'static str>> = const { Cell::new(None) };
+}
+
+/// Start of a crash report in stderr
+pub const CRASH_MARKER: &str = "Crashed with ";
+
+// Read-only input buffers, so a write to one can be reported with the
+// name of its clip. A slot is free when its start is zero.
+const READ_ONLY_LEN: usize = 64;
+
+struct ReadOnlyRegion {
+    start: AtomicUsize,
+    len: AtomicUsize,
+    clip: AtomicPtr<c_char>,
+}
+
+static READ_ONLY: [ReadOnlyRegion; READ_ONLY_LEN] = [const {
+    ReadOnlyRegion {
+        start: AtomicUsize::new(0),
+        len: AtomicUsize::new(0),
+        clip: AtomicPtr::new(std::ptr::null_mut()),
+    }
+}; READ_ONLY_LEN];
+
+/// Record a read-only region of memory used by a clip. Returns the
+/// slot to pass to unregister_read_only, or None if there are too many
+/// regions to track (in which case a write is still reported, but
+/// without the clip).
+pub fn register_read_only(
+    start: usize,
+    len: usize,
+    clip: *const c_char,
+) -> Option<usize> {
+    let slot = READ_ONLY.iter().position(|region| {
+        region
+            .start
+            .compare_exchange(0, usize::MAX, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    })?;
+    let region = &READ_ONLY[slot];
+    region.len.store(len, Ordering::Relaxed);
+    region.clip.store(clip as *mut _, Ordering::Relaxed);
+    region.start.store(start, Ordering::Release);
+    Some(slot)
+}
+
+pub fn unregister_read_only(slot: usize) {
+    READ_ONLY[slot].start.store(0, Ordering::Release);
+}
+
+/// The clip whose read-only region contains an address. The name is
+/// only valid while the region is registered.
+pub fn read_only_clip(address: usize) -> Option<&'static CStr> {
+    READ_ONLY.iter().find_map(|region| {
+        let start = region.start.load(Ordering::Acquire);
+        let len = region.len.load(Ordering::Relaxed);
+        if start != 0 && start != usize::MAX && (start..start + len).contains(&address) {
+            let clip = region.clip.load(Ordering::Relaxed);
+            Some(unsafe { CStr::from_ptr(clip) })
+        } else {
+            None
+        }
+    })
+}
+
+/// Record a call to a suite function. The name is a reference to a
+/// static so it can be stored in a single atomic.
+pub fn record_call(name: &'static &'static str) {
+    let index = NEXT_CALL.fetch_add(1, Ordering::Relaxed) % CALL_HISTORY_LEN;
+    CALL_HISTORY[index].store(name as *const _ as *mut _, Ordering::Relaxed);
+}
+
+/// Names of the most recent suite calls, oldest first
+pub fn last_calls() -> Vec<&'static str> {
+    let next = NEXT_CALL.load(Ordering::Relaxed);
+    (0..CALL_HISTORY_LEN)
+        .map(|i| CALL_HISTORY[(next + i) % CALL_HISTORY_LEN].load(Ordering::Relaxed))
+        .filter(|call| !call.is_null())
+        .map(|call| unsafe { *call })
+        .collect()
+}
+
+/// Marks a suite function as running on the current thread, until
+/// it's dropped
+pub struct FunctionCall {
+    // Function which was running, if this one was called from it
+    outer: Option<&'static str>,
+}
+
+pub fn enter_function(name: &'static str) -> FunctionCall {
+    FunctionCall {
+        outer: CURRENT_FUNCTION.replace(Some(name)),
+    }
+}
+
+impl Drop for FunctionCall {
+    fn drop(&mut self) {
+        CURRENT_FUNCTION.set(self.outer);
+    }
+}
+
+/// Name of the suite function running on the current thread. Unlike
+/// the call history, this isn't affected by other threads.
+pub fn current_function() -> Option<&'static str> {
+    CURRENT_FUNCTION.get()
+}
+
+/// Record a call to a suite function, and trace it if tracing is
+/// enabled. Must be used at the start of the function, since the
+/// trace entry is written when the function returns.
+///
+/// With a body, runs the body as the function and records the status
+/// it returns in the trace.
+macro_rules! suite_call {
+    ($name:literal) => {
+        static NAME: &str = $name;
+        crate::crash::record_call(&NAME);
+        let _function = crate::crash::enter_function($name);
+        let _trace = crate::trace::call($name);
+    };
+    ($name:literal, $body:block) => {{
+        crate::crash::suite_call!($name);
+        let status: openfx_rs::types::OfxStatus = (|| $body)();
+        crate::trace::status(status);
+        status
+    }};
+}
+pub(crate) use suite_call;
+
+/// Run an action, recording its name for crash reports. The action
+/// string must be static.
+pub fn in_action<T>(action: *const c_char, f: impl FnOnce() -> T) -> T {
+    let previous = CURRENT_ACTION.replace(action);
+    let result = f();
+    CURRENT_ACTION.set(previous);
+    result
+}
+
+/// Name of the action being run on the current thread, if any
+pub fn current_action() -> Option<&'static str> {
+    let action = CURRENT_ACTION.get();
+    if action.is_null() {
+        None
+    } else {
+        unsafe { CStr::from_ptr(action) }.to_str().ok()
+    }
+}
+
+#[cfg(unix)]
+mod handler {
+    use super::*;
+    use std::ffi::{c_int, c_void};
+
+    const SIGNALS: [(c_int, &str); 5] = [
+        (libc::SIGSEGV, "SIGSEGV"),
+        (libc::SIGBUS, "SIGBUS"),
+        (libc::SIGABRT, "SIGABRT"),
+        (libc::SIGFPE, "SIGFPE"),
+        (libc::SIGILL, "SIGILL"),
+    ];
+
+    fn write_stderr(bytes: &[u8]) {
+        unsafe { libc::write(2, bytes.as_ptr() as *const c_void, bytes.len()) };
+    }
+
+    extern "C" fn crash_handler(
+        signal: c_int,
+        info: *mut libc::siginfo_t,
+        _context: *mut c_void,
+    ) {
+        let name = SIGNALS
+            .iter()
+            .find(|(s, _)| *s == signal)
+            .map_or("signal", |(_, name)| *name);
+        write_stderr(b"\n");
+        write_stderr(CRASH_MARKER.as_bytes());
+        write_stderr(name.as_bytes());
+        if signal == libc::SIGSEGV || signal == libc::SIGBUS {
+            let address = unsafe { (*info).si_addr() } as usize;
+            if let Some(clip) = read_only_clip(address) {
+                write_stderr(b" writing to read-only input image ");
+                write_stderr(clip.to_bytes());
+            }
+        }
+        let action = CURRENT_ACTION.get();
+        if !action.is_null() {
+            write_stderr(b" during ");
+            write_stderr(unsafe { CStr::from_ptr(action) }.to_bytes());
+        }
+
+        write_stderr(b"\nLast suite calls:");
+        let next = NEXT_CALL.load(Ordering::Relaxed);
+        let mut first = true;
+        for i in 0..CALL_HISTORY_LEN {
+            let call =
+                CALL_HISTORY[(next + i) % CALL_HISTORY_LEN].load(Ordering::Relaxed);
+            if !call.is_null() {
+                if !first {
+                    write_stderr(b",");
+                }
+                write_stderr(b" ");
+                write_stderr(unsafe { *call }.as_bytes());
+                first = false;
+            }
+        }
+        if first {
+            write_stderr(b" none");
+        }
+        write_stderr(b"\n");
+
+        // Crash with the default handler, so the exit status shows the
+        // signal
+        unsafe {
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+    }
+
+    pub fn install() {
+        for (signal, _) in SIGNALS {
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = crash_handler as libc::sighandler_t;
+                action.sa_flags = libc::SA_SIGINFO;
+                libc::sigemptyset(&mut action.sa_mask);
+                libc::sigaction(signal, &action, std::ptr::null_mut());
+            }
+        }
+    }
+}
+
+/// Install signal handlers to write a crash report. Only supported on
+/// Unix.
+pub fn install_handler() {
+    #[cfg(unix)]
+    handler::install();
+}
@@ -0,0 +1,104 @@
+//! Synthetic input images, generated in memory instead of read from
+//! files.
+use crate::commands::{Generator, ImageComponents, Pattern};
+use crate::{Image, ImagePixels, Pixel};
+use anyhow::{bail, Result};
+use openfx_rs::types::OfxRectI;
+
+/// Small deterministic random number generator (SplitMix64), so noise
+/// images are reproducible from a seed.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform value in [0, 1)
+    fn next_f32(&mut self) -> f32 {
+        // Use the top 24 bits, which fit exactly in an f32 mantissa
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+fn lerp(a: [f32; 4], b: [f32; 4], t: f32) -> [f32; 4] {
+    [0, 1, 2, 3].map(|i| a[i] + (b[i] - a[i]) * t)
+}
+
+/// Generate an image from a pattern. Pixels are filled row by row
+/// from the bottom, matching the layout of images read from files.
+pub fn generate_image(
+    name: &str,
+    generator: &Generator,
+    rowbytes: Option<usize>,
+    origin: (i32, i32),
+) -> Result<Image> {
+    let (width, height) = generator.size;
+    if width == 0 || height == 0 {
+        bail!("Generated image {} has zero size", name);
+    }
+    if let Pattern::Checkerboard { size: 0, .. } = generator.pattern {
+        bail!("Checkerboard size must be non-zero");
+    }
+
+    let (x1, y1) = origin;
+    let bounds = OfxRectI {
+        x1,
+        y1,
+        x2: x1 + width as i32,
+        y2: y1 + height as i32,
+    };
+    let alpha = matches!(generator.components, ImageComponents::Alpha);
+    let mut image = Image::empty(name, &bounds, rowbytes, alpha);
+    let stride = image.stride;
+
+    let mut rng = match generator.pattern {
+        Pattern::Noise { seed } => SplitMix64(seed),
+        _ => SplitMix64(0),
+    };
+
+    for row in 0..height {
+        for col in 0..width {
+            // Pixel coordinates
+            let (x, y) = (x1 + col as i32, y1 + row as i32);
+            let [r, g, b, a] = match generator.pattern {
+                Pattern::Constant { colour } => colour,
+                Pattern::Checkerboard {
+                    size,
+                    colour1,
+                    colour2,
+                } => {
+                    let size = size as i32;
+                    if (x.div_euclid(size) + y.div_euclid(size)) % 2 == 0 {
+                        colour1
+                    } else {
+                        colour2
+                    }
+                }
+                Pattern::Ramp { start, end } => {
+                    lerp(start, end, (col as f32 + 0.5) / width as f32)
+                }
+                Pattern::Noise { .. } => [0; 4].map(|_| rng.next_f32()),
+                Pattern::HotPixel { position, colour } => {
+                    if position == (x, y) {
+                        colour
+                    } else {
+                        [0.0; 4]
+                    }
+                }
+            };
+
+            let index = row * stride + col;
+            match &mut image.pixels {
+                ImagePixels::Rgba(v) => v[index] = Pixel { r, g, b, a },
+                ImagePixels::Alpha(v) => v[index] = a,
+            }
+        }
+    }
+
+    Ok(image)
+}
@@ -1,6 +1,11 @@
+use crate::trace::{self, DebugName};
+use crate::{crash, log_error};
+use openfx_rs::constants::ofxstatus;
+use openfx_rs::types::OfxStatus;
 use serde::{Serialize, Serializer};
 use std::collections::HashMap;
 use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, LazyLock, Mutex, MutexGuard, Weak};
 
 // ========= Handles =========
@@ -137,34 +142,99 @@ where
     }
 }
 
+// ========= Bad handles =========
+
+static RECOVER_BAD_HANDLES: AtomicBool = AtomicBool::new(false);
+static BAD_HANDLES: Mutex<Vec<BadHandle>> = Mutex::new(Vec::new());
+
+/// Return kOfxStatErrBadHandle from suite functions which are passed
+/// a bad handle, instead of panicking. Each one is recorded so it can
+/// be reported at the end of the run.
+pub fn set_recover_bad_handles(recover: bool) {
+    RECOVER_BAD_HANDLES.store(recover, Ordering::Relaxed);
+}
+
+/// A bad handle which was passed to a suite function
+#[derive(Clone, Debug, Serialize)]
+pub struct BadHandle {
+    pub function: &'static str,
+    pub handle_type: &'static str,
+    pub error: String,
+}
+
+impl std::fmt::Display for BadHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} ({})",
+            self.function, self.error, self.handle_type
+        )
+    }
+}
+
+/// Handle an invalid handle of the given type. Panics unless bad
+/// handles are recoverable, in which case it's recorded and
+/// kOfxStatErrBadHandle is returned.
+pub fn bad_handle(handle_type: &'static str, error: String) -> OfxStatus {
+    if !RECOVER_BAD_HANDLES.load(Ordering::Relaxed) {
+        panic!("{}", error);
+    }
+    let bad = BadHandle {
+        function: crash::current_function().unwrap_or("unknown"),
+        handle_type,
+        error,
+    };
+    log_error!("{}", bad);
+    BAD_HANDLES.lock().unwrap().push(bad);
+    trace::status(ofxstatus::ErrBadHandle);
+    ofxstatus::ErrBadHandle
+}
+
+/// All the bad handles recorded so far
+pub fn bad_handles() -> Vec<BadHandle> {
+    BAD_HANDLES.lock().unwrap().clone()
+}
+
 /// A trait for handles to OFX objects.
 ///
 /// Provides methods to access the underlying objects referred to by a
 /// handle.
 pub trait Handle: Sized + Eq + std::hash::Hash + std::fmt::Debug + 'static {
-    type Object;
+    type Object: DebugName;
     fn handle_manager() -> &'static LazyLock<Mutex<HandleManager<Self::Object, Self>>>;
 
-    /// Get the underlying object of a handle.
-    ///
-    /// Panics if the handle is invalid or points to a deallocated
-    /// object (these are errors in the plugin and if they occur we
-    /// can't reasonably recover, so it's best to fail immediately
-    /// with the option of backtrace).
-    fn as_arc(&self) -> Object<Self::Object> {
+    /// Get the underlying object of a handle, or an error message if
+    /// the handle is invalid or points to a deallocated object.
+    fn try_as_arc(&self) -> Result<Object<Self::Object>, String> {
         if let Some(weak) = Self::handle_manager()
             .lock()
             .unwrap()
             .handle_to_ptr
             .get(self)
         {
-            Object(weak.upgrade().unwrap_or_else(|| {
-                panic!("Handle {:?} points to deallocated object", self)
-            }))
+            weak.upgrade()
+                .map(Object)
+                .ok_or_else(|| format!("Handle {:?} points to deallocated object", self))
         } else {
-            panic!("Bad handle {:?}", self);
+            Err(format!("Bad handle {:?}", self))
         }
     }
+
+    /// Get the underlying object of a handle.
+    ///
+    /// Panics if the handle is invalid or points to a deallocated
+    /// object (these are errors in the plugin and if they occur we
+    /// can't reasonably recover, so it's best to fail immediately
+    /// with the option of backtrace).
+    fn as_arc(&self) -> Object<Self::Object> {
+        self.try_as_arc().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Name of the handle type, for error reports
+    fn type_name() -> &'static str {
+        let name = std::any::type_name::<Self>();
+        name.rsplit("::").next().unwrap_or(name)
+    }
 }
 
 pub trait WithObject<Obj> {
@@ -174,6 +244,14 @@ pub trait WithObject<Obj> {
     fn with_object<F, T>(self, callback: F) -> T
     where
         F: FnOnce(&mut Obj) -> T;
+
+    /// Run a function on the underlying object, from a suite
+    /// function. A bad handle is passed to bad_handle(), which panics
+    /// or returns the status for the suite function to return. The
+    /// object is recorded in the trace of the suite call.
+    fn try_with_object<F, T>(self, callback: F) -> Result<T, OfxStatus>
+    where
+        F: FnOnce(&mut Obj) -> T;
 }
 
 // Blanket impl for all handles
@@ -189,6 +267,18 @@ where
         let guard = &mut mutex.lock();
         callback(guard)
     }
+
+    fn try_with_object<F, T>(self, callback: F) -> Result<T, OfxStatus>
+    where
+        F: FnOnce(&mut H::Object) -> T,
+    {
+        let mutex = self
+            .try_as_arc()
+            .map_err(|e| bad_handle(H::type_name(), e))?;
+        let guard = &mut mutex.lock();
+        trace::handle(&**guard);
+        Ok(callback(guard))
+    }
 }
 
 pub trait ToHandle: Clone {
@@ -239,6 +329,13 @@ macro_rules! impl_handle {
             {
                 $handle_name::from(self).with_object(callback)
             }
+
+            fn try_with_object<F, T>(self, callback: F) -> Result<T, OfxStatus>
+            where
+                F: FnOnce(&mut $object_name) -> T,
+            {
+                $handle_name::from(self).try_with_object(callback)
+            }
         }
     };
 }
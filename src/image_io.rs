@@ -0,0 +1,611 @@
+//! Format-neutral image reading and writing. EXR is handled by
+//! read_exr/write_exr; other formats are converted through
+//! interleaved f32 samples.
+use crate::commands::{ChannelSpec, ImageComponents, ImageFormat, SampleDepth};
+use crate::{
+    exr_bounds, exr_layer_position, read_exr, write_exr, GenericResult, Image,
+    ImagePixels, Pixel, Rect,
+};
+use anyhow::{bail, Context, Result};
+use exr::prelude::{
+    read_all_flat_layers_from_file, AnyChannel, AnyChannels, Encoding, FlatSamples,
+    ImageAttributes, IntegerBounds, Layer, LayerAttributes, WritableImage,
+};
+use openfx_rs::types::OfxRectI;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+/// Interleaved samples with rows ordered top to bottom, as they are
+/// stored in files. 1 channel is alpha, 2 is grey + alpha, 3 is RGB
+/// and 4 is RGBA.
+struct Samples {
+    width: usize,
+    height: usize,
+    channels: usize,
+    data: Vec<f32>,
+}
+
+/// Dimensions of a raw image, stored alongside it in "<file>.json"
+#[derive(Deserialize, Serialize)]
+struct RawSidecar {
+    width: usize,
+    height: usize,
+    components: ImageComponents,
+}
+
+impl ImageFormat {
+    pub fn from_path(path: &str) -> Result<Self> {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        Ok(match extension.as_deref() {
+            Some("exr") => ImageFormat::Exr,
+            Some("png") => ImageFormat::Png,
+            Some("tif") | Some("tiff") => ImageFormat::Tiff,
+            Some("raw") => ImageFormat::Raw,
+            _ => bail!("Can't determine image format of \"{}\"", path),
+        })
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Exr => "exr",
+            ImageFormat::Png => "png",
+            ImageFormat::Tiff => "tiff",
+            ImageFormat::Raw => "raw",
+        }
+    }
+
+    fn default_depth(self) -> SampleDepth {
+        match self {
+            ImageFormat::Png => SampleDepth::U8,
+            _ => SampleDepth::F32,
+        }
+    }
+}
+
+fn raw_sidecar_path(path: &str) -> String {
+    format!("{}.json", path)
+}
+
+fn normalize<T: Into<f32>>(value: T, max: f32) -> f32 {
+    value.into() / max
+}
+
+// Clamp to [0, 1] and scale to integer range. NaN becomes 0.
+fn quantize(value: f32, max: f32) -> f32 {
+    (value.clamp(0.0, 1.0) * max).round()
+}
+
+fn to_u8(data: &[f32]) -> Vec<u8> {
+    data.iter()
+        .map(|&v| quantize(v, u8::MAX as f32) as u8)
+        .collect()
+}
+
+fn to_u16(data: &[f32]) -> Vec<u16> {
+    data.iter()
+        .map(|&v| quantize(v, u16::MAX as f32) as u16)
+        .collect()
+}
+
+fn read_png(path: &str) -> Result<Samples> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    // Expand palettes and bit depths below 8, and convert
+    // transparency chunks to an alpha channel
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    buffer.truncate(info.buffer_size());
+
+    let data = match info.bit_depth {
+        png::BitDepth::Sixteen => buffer
+            .chunks_exact(2)
+            .map(|b| normalize(u16::from_be_bytes([b[0], b[1]]), u16::MAX as f32))
+            .collect(),
+        _ => buffer
+            .into_iter()
+            .map(|b| normalize(b, u8::MAX as f32))
+            .collect(),
+    };
+
+    Ok(Samples {
+        width: info.width as usize,
+        height: info.height as usize,
+        channels: info.color_type.samples(),
+        data,
+    })
+}
+
+fn write_png(path: &str, samples: &Samples, depth: SampleDepth) -> GenericResult {
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(File::create(path)?),
+        samples.width as u32,
+        samples.height as u32,
+    );
+    encoder.set_color(if samples.channels == 1 {
+        png::ColorType::Grayscale
+    } else {
+        png::ColorType::Rgba
+    });
+    let data = match depth {
+        SampleDepth::U8 => {
+            encoder.set_depth(png::BitDepth::Eight);
+            to_u8(&samples.data)
+        }
+        SampleDepth::U16 => {
+            encoder.set_depth(png::BitDepth::Sixteen);
+            to_u16(&samples.data)
+                .into_iter()
+                .flat_map(u16::to_be_bytes)
+                .collect()
+        }
+        SampleDepth::F32 => bail!("PNG doesn't support f32 samples"),
+    };
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish()?;
+    Ok(())
+}
+
+fn read_tiff(path: &str) -> Result<Samples> {
+    use tiff::decoder::{Decoder, DecodingResult};
+    use tiff::ColorType;
+
+    let mut decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+    let (width, height) = decoder.dimensions()?;
+    let channels = match decoder.colortype()? {
+        ColorType::Gray(_) => 1,
+        ColorType::GrayA(_) => 2,
+        ColorType::RGB(_) => 3,
+        ColorType::RGBA(_) => 4,
+        other => bail!("Unsupported TIFF colour type {:?}", other),
+    };
+    let data = match decoder.read_image()? {
+        DecodingResult::U8(v) => v
+            .into_iter()
+            .map(|s| normalize(s, u8::MAX as f32))
+            .collect(),
+        DecodingResult::U16(v) => v
+            .into_iter()
+            .map(|s| normalize(s, u16::MAX as f32))
+            .collect(),
+        DecodingResult::F32(v) => v,
+        _ => bail!("Unsupported TIFF sample format"),
+    };
+    // Planar or subsampled images decode to a different number of
+    // samples
+    let expected = width as usize * height as usize * channels;
+    if data.len() != expected {
+        bail!(
+            "TIFF image has {} samples, expected {} for {}x{} with {} channels",
+            data.len(),
+            expected,
+            width,
+            height,
+            channels
+        );
+    }
+
+    Ok(Samples {
+        width: width as usize,
+        height: height as usize,
+        channels,
+        data,
+    })
+}
+
+fn write_tiff(path: &str, samples: &Samples, depth: SampleDepth) -> GenericResult {
+    use tiff::encoder::{colortype, TiffEncoder};
+
+    let mut encoder = TiffEncoder::new(BufWriter::new(File::create(path)?))?;
+    let (width, height) = (samples.width as u32, samples.height as u32);
+    let data = &samples.data;
+    match (samples.channels, depth) {
+        (1, SampleDepth::U8) => {
+            encoder.write_image::<colortype::Gray8>(width, height, &to_u8(data))
+        }
+        (1, SampleDepth::U16) => {
+            encoder.write_image::<colortype::Gray16>(width, height, &to_u16(data))
+        }
+        (1, SampleDepth::F32) => {
+            encoder.write_image::<colortype::Gray32Float>(width, height, data)
+        }
+        (_, SampleDepth::U8) => {
+            encoder.write_image::<colortype::RGBA8>(width, height, &to_u8(data))
+        }
+        (_, SampleDepth::U16) => {
+            encoder.write_image::<colortype::RGBA16>(width, height, &to_u16(data))
+        }
+        (_, SampleDepth::F32) => {
+            encoder.write_image::<colortype::RGBA32Float>(width, height, data)
+        }
+    }?;
+    Ok(())
+}
+
+fn read_raw(path: &str) -> Result<Samples> {
+    let sidecar_path = raw_sidecar_path(path);
+    let sidecar: RawSidecar =
+        serde_json::from_reader(BufReader::new(File::open(&sidecar_path)?))
+            .with_context(|| format!("Read sidecar \"{}\"", sidecar_path))?;
+    let channels = match sidecar.components {
+        ImageComponents::Rgba => 4,
+        ImageComponents::Alpha => 1,
+    };
+
+    let bytes = std::fs::read(path)?;
+    let expected = sidecar.width * sidecar.height * channels * std::mem::size_of::<f32>();
+    if bytes.len() != expected {
+        bail!(
+            "Raw image is {} bytes, expected {} from sidecar",
+            bytes.len(),
+            expected
+        );
+    }
+
+    Ok(Samples {
+        width: sidecar.width,
+        height: sidecar.height,
+        channels,
+        data: bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+    })
+}
+
+fn write_raw(path: &str, samples: &Samples, depth: SampleDepth) -> GenericResult {
+    if depth != SampleDepth::F32 {
+        bail!("Raw images only support f32 samples");
+    }
+    let sidecar = RawSidecar {
+        width: samples.width,
+        height: samples.height,
+        components: if samples.channels == 1 {
+            ImageComponents::Alpha
+        } else {
+            ImageComponents::Rgba
+        },
+    };
+    std::fs::write(
+        raw_sidecar_path(path),
+        serde_json::to_string_pretty(&sidecar)?,
+    )?;
+    let bytes: Vec<u8> = samples.data.iter().flat_map(|v| v.to_le_bytes()).collect();
+    std::fs::write(path, bytes)?;
+    Ok(())
+}
+
+type FlatLayer = Layer<AnyChannels<FlatSamples>>;
+
+/// Find the requested channels in an EXR layer. Returns None if any
+/// are missing.
+fn find_exr_channels<'a>(
+    layer: &'a FlatLayer,
+    names: &[String],
+) -> Option<Vec<&'a FlatSamples>> {
+    names
+        .iter()
+        .map(|name| {
+            layer
+                .channel_data
+                .list
+                .iter()
+                .find(|c| c.name.eq(name))
+                .map(|c| &c.sample_data)
+        })
+        .collect()
+}
+
+/// Read specific channels from an EXR file. The layer in the spec can
+/// either be the name of a part in a multi-part file, or a prefix of
+/// the channel names (e.g. "diffuse.R").
+fn read_exr_channels(
+    name: &str,
+    path: &str,
+    spec: &ChannelSpec,
+    rowbytes: Option<usize>,
+    origin: Option<(i32, i32)>,
+) -> Result<Image> {
+    let exr_image = read_all_flat_layers_from_file(path)?;
+    let prefixed: Vec<String> = match &spec.layer {
+        Some(layer) => spec
+            .channels
+            .iter()
+            .map(|c| format!("{}.{}", layer, c))
+            .collect(),
+        None => spec.channels.clone(),
+    };
+
+    let (layer, channels) = exr_image
+        .layer_data
+        .iter()
+        .find_map(|layer| {
+            let in_part = match (&spec.layer, &layer.attributes.layer_name) {
+                (Some(wanted), Some(name)) if name.eq(wanted) => {
+                    find_exr_channels(layer, &spec.channels)
+                }
+                _ => None,
+            };
+            in_part
+                .or_else(|| find_exr_channels(layer, &prefixed))
+                .map(|channels| (layer, channels))
+        })
+        .with_context(|| format!("No channels \"{}\"", String::from(spec.clone())))?;
+
+    let (width, height) = (layer.size.0, layer.size.1);
+    let values: Vec<Vec<f32>> = channels
+        .iter()
+        .map(|c| c.values_as_f32().collect())
+        .collect();
+    if values.iter().any(|v| v.len() != width * height) {
+        bail!("Subsampled channels are not supported");
+    }
+
+    let (bounds, display_window) = exr_bounds(
+        origin,
+        &exr_image.attributes.display_window,
+        layer.attributes.layer_position,
+        layer.size,
+    );
+    let samples = Samples {
+        width,
+        height,
+        channels: values.len(),
+        data: (0..width * height)
+            .flat_map(|i| values.iter().map(move |v| v[i]))
+            .collect(),
+    };
+    let mut image =
+        samples_to_image(name, samples, false, rowbytes, Some((bounds.x1, bounds.y1)));
+    image.display_window = Some(display_window);
+    Ok(image)
+}
+
+/// Convert an image to an EXR layer with the given name
+fn exr_layer(layer_name: &str, image: &Image) -> FlatLayer {
+    let samples = image_to_samples(image);
+    let names: &[&str] = if samples.channels == 1 {
+        &["A"]
+    } else {
+        &["R", "G", "B", "A"]
+    };
+    let channels = names
+        .iter()
+        .enumerate()
+        .map(|(c, name)| {
+            AnyChannel::new(
+                *name,
+                FlatSamples::F32(
+                    samples
+                        .data
+                        .iter()
+                        .skip(c)
+                        .step_by(samples.channels)
+                        .copied()
+                        .collect(),
+                ),
+            )
+        })
+        .collect();
+
+    let display_window = image.display_window.unwrap_or(image.bounds);
+    Layer::new(
+        (samples.width, samples.height),
+        LayerAttributes {
+            layer_position: exr_layer_position(&image.bounds, &display_window),
+            ..LayerAttributes::named(layer_name)
+        },
+        Encoding::default(),
+        AnyChannels::sort(channels),
+    )
+}
+
+/// Write an image as a named part of a multi-part EXR. If the file
+/// exists, the other parts are kept and any part with the same name
+/// is replaced. All parts share one display window, so the image's
+/// must match the file's.
+pub fn write_exr_layer(path: &str, layer_name: &str, image: Image) -> GenericResult {
+    let display_window = image.display_window.unwrap_or(image.bounds);
+    let display_bounds =
+        IntegerBounds::from_dimensions((display_window.width(), display_window.height()));
+    let mut layers: Vec<FlatLayer> = if Path::new(path).exists() {
+        let existing = read_all_flat_layers_from_file(path)
+            .with_context(|| format!("Read EXR \"{}\"", path))?;
+        // Part positions are relative to the display window, so
+        // existing parts would move if it changed
+        let existing_window = existing.attributes.display_window;
+        if existing_window != display_bounds {
+            bail!(
+                "\"{}\" has a {}x{} display window at ({}, {}), but layer {} has {}x{}",
+                path,
+                existing_window.size.0,
+                existing_window.size.1,
+                existing_window.position.0,
+                existing_window.position.1,
+                layer_name,
+                display_window.width(),
+                display_window.height()
+            );
+        }
+        existing.layer_data.into_iter().collect()
+    } else {
+        Vec::new()
+    };
+    if layers.iter().any(|l| l.attributes.layer_name.is_none()) {
+        bail!("\"{}\" already contains an unnamed layer", path);
+    }
+    layers.retain(|l| {
+        !l.attributes
+            .layer_name
+            .as_ref()
+            .is_some_and(|n| n.eq(layer_name))
+    });
+    layers.push(exr_layer(layer_name, &image));
+
+    let attributes = ImageAttributes::new(display_bounds);
+    exr::image::Image::from_layers(attributes, layers)
+        .write()
+        .to_file(path)
+        .with_context(|| format!("Write EXR \"{}\"", path))?;
+    Ok(())
+}
+
+/// Convert samples to an image. A single channel is read as grey if
+/// `grey` is set (for formats where it means luminance), and as Alpha
+/// otherwise.
+fn samples_to_image(
+    name: &str,
+    samples: Samples,
+    grey: bool,
+    rowbytes: Option<usize>,
+    origin: Option<(i32, i32)>,
+) -> Image {
+    let Samples {
+        width,
+        height,
+        channels,
+        data,
+    } = samples;
+    let (x1, y1) = origin.unwrap_or((0, 0));
+    let bounds = OfxRectI {
+        x1,
+        y1,
+        x2: x1 + width as i32,
+        y2: y1 + height as i32,
+    };
+    let mut image = Image::empty(name, &bounds, rowbytes, channels == 1 && !grey);
+    let stride = image.stride;
+
+    for (y, row) in data.chunks_exact(width * channels).enumerate() {
+        // Flip y
+        let start = (height - 1 - y) * stride;
+        match &mut image.pixels {
+            ImagePixels::Alpha(pixels) => {
+                pixels[start..start + width].copy_from_slice(row);
+            }
+            ImagePixels::Rgba(pixels) => {
+                for (pixel, s) in pixels[start..start + width]
+                    .iter_mut()
+                    .zip(row.chunks_exact(channels))
+                {
+                    *pixel = match *s {
+                        [v] => Pixel {
+                            r: v,
+                            g: v,
+                            b: v,
+                            a: 1.0,
+                        },
+                        [v, a] => Pixel {
+                            r: v,
+                            g: v,
+                            b: v,
+                            a,
+                        },
+                        [r, g, b] => Pixel { r, g, b, a: 1.0 },
+                        [r, g, b, a] => Pixel { r, g, b, a },
+                        _ => unreachable!(),
+                    };
+                }
+            }
+        }
+    }
+
+    image
+}
+
+fn image_to_samples(image: &Image) -> Samples {
+    let width = image.bounds.width();
+    let height = image.bounds.height();
+    let stride = image.stride;
+    // Flip y
+    let rows = (0..height).rev().map(|y| y * stride..y * stride + width);
+
+    let (channels, data) = match &image.pixels {
+        ImagePixels::Rgba(pixels) => (
+            4,
+            rows.flat_map(|row| pixels[row].iter().flat_map(|p| [p.r, p.g, p.b, p.a]))
+                .collect(),
+        ),
+        ImagePixels::Alpha(pixels) => (
+            1,
+            rows.flat_map(|row| pixels[row].iter().copied()).collect(),
+        ),
+    };
+
+    Samples {
+        width,
+        height,
+        channels,
+        data,
+    }
+}
+
+/// Read an image. If format is None, it's determined from the file
+/// extension. If origin is None, EXR images are positioned by their
+/// data window, and other formats at (0, 0). Channels can only be
+/// selected from EXR files; otherwise the first RGBA layer is read.
+pub fn read_image(
+    name: &str,
+    path: &str,
+    format: Option<ImageFormat>,
+    channels: Option<&ChannelSpec>,
+    rowbytes: Option<usize>,
+    origin: Option<(i32, i32)>,
+) -> Result<Image> {
+    let format = match format {
+        Some(f) => f,
+        None => ImageFormat::from_path(path)?,
+    };
+    if let Some(spec) = channels {
+        if format != ImageFormat::Exr {
+            bail!("Channels can only be selected from EXR files");
+        }
+        return read_exr_channels(name, path, spec, rowbytes, origin)
+            .with_context(|| format!("Read EXR \"{}\"", path));
+    }
+    let samples = match format {
+        ImageFormat::Exr => return read_exr(name, path, rowbytes, origin),
+        ImageFormat::Png => read_png(path),
+        ImageFormat::Tiff => read_tiff(path),
+        ImageFormat::Raw => read_raw(path),
+    }
+    .with_context(|| format!("Read {:?} \"{}\"", format, path))?;
+
+    // A single channel in a PNG or TIFF is greyscale, but in a raw file
+    // it's alpha
+    let grey = matches!(format, ImageFormat::Png | ImageFormat::Tiff);
+    Ok(samples_to_image(name, samples, grey, rowbytes, origin))
+}
+
+/// Write an image. If format is None, it's determined from the file
+/// extension. If depth is None, the format's default is used.
+pub fn write_image(
+    path: &str,
+    format: Option<ImageFormat>,
+    depth: Option<SampleDepth>,
+    image: Image,
+) -> GenericResult {
+    let format = match format {
+        Some(f) => f,
+        None => ImageFormat::from_path(path)?,
+    };
+    let depth = depth.unwrap_or(format.default_depth());
+    match format {
+        ImageFormat::Exr => {
+            if depth != SampleDepth::F32 {
+                bail!("EXR output only supports f32 samples");
+            }
+            write_exr(path, image)
+        }
+        ImageFormat::Png => write_png(path, &image_to_samples(&image), depth),
+        ImageFormat::Tiff => write_tiff(path, &image_to_samples(&image), depth),
+        ImageFormat::Raw => write_raw(path, &image_to_samples(&image), depth),
+    }
+    .with_context(|| format!("Write {:?} \"{}\"", format, path))
+}
@@ -0,0 +1,143 @@
+//! JUnit XML test reports. Each command (or Group of commands) is a
+//! test case, and each command file is a test suite.
+use crate::{CommandResult, CommandStatus};
+use anyhow::{Context, Result};
+use std::fmt::Write;
+use std::fs;
+
+pub struct TestSuite<'a> {
+    pub name: String,
+    pub results: &'a [CommandResult],
+}
+
+impl TestSuite<'_> {
+    fn failures(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|r| r.status == CommandStatus::Failed)
+            .count()
+    }
+
+    fn errors(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|r| matches!(r.status, CommandStatus::Error | CommandStatus::Timeout))
+            .count()
+    }
+
+    fn seconds(&self) -> f64 {
+        self.results.iter().map(|r| r.duration_ms).sum::<f64>() / 1000.0
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // Other control characters aren't allowed in XML
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn write_test_case(
+    xml: &mut String,
+    suite: &str,
+    result: &CommandResult,
+) -> std::fmt::Result {
+    writeln!(
+        xml,
+        "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\">",
+        escape(&result.test_name()),
+        escape(suite),
+        result.duration_ms / 1000.0
+    )?;
+    let element = match result.status {
+        CommandStatus::Ok => None,
+        CommandStatus::Failed => Some("failure"),
+        CommandStatus::Error | CommandStatus::Timeout => Some("error"),
+    };
+    if let Some(element) = element {
+        // The message is the whole error chain on one line, and the
+        // body has one line per error
+        writeln!(
+            xml,
+            "      <{} message=\"{}\">{}</{}>",
+            element,
+            escape(&result.error.join(": ")),
+            escape(&result.error.join("\n")),
+            element
+        )?;
+    }
+    if !result.output.is_empty() {
+        let output: Vec<String> = result
+            .output
+            .iter()
+            .map(|value| match value {
+                serde_json::Value::String(s) => s.clone(),
+                value => value.to_string(),
+            })
+            .collect();
+        writeln!(
+            xml,
+            "      <system-out>{}</system-out>",
+            escape(&output.join("\n"))
+        )?;
+    }
+    writeln!(xml, "    </testcase>")
+}
+
+fn write_test_suites(xml: &mut String, suites: &[TestSuite]) -> std::fmt::Result {
+    writeln!(xml, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(
+        xml,
+        "<testsuites name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">",
+        env!("CARGO_PKG_NAME"),
+        suites.iter().map(|s| s.results.len()).sum::<usize>(),
+        suites
+            .iter()
+            .map(TestSuite::failures)
+            .sum::<usize>(),
+        suites
+            .iter()
+            .map(TestSuite::errors)
+            .sum::<usize>(),
+        suites.iter().map(TestSuite::seconds).sum::<f64>()
+    )?;
+    for suite in suites {
+        writeln!(
+            xml,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">",
+            escape(&suite.name),
+            suite.results.len(),
+            suite.failures(),
+            suite.errors(),
+            suite.seconds()
+        )?;
+        for result in suite.results {
+            write_test_case(xml, &suite.name, result)?;
+        }
+        writeln!(xml, "  </testsuite>")?;
+    }
+    writeln!(xml, "</testsuites>")
+}
+
+/// Generate a report for a list of test suites
+pub fn report(suites: &[TestSuite]) -> String {
+    let mut xml = String::new();
+    // Writing to a String can't fail
+    write_test_suites(&mut xml, suites).unwrap();
+    xml
+}
+
+pub fn write_report(filename: &str, suites: &[TestSuite]) -> Result<()> {
+    fs::write(filename, report(suites)).with_context(|| format!("Writing {}", filename))
+}
@@ -1,8 +1,11 @@
 use anyhow::{anyhow, bail, Context, Result};
 use clap::{Parser, Subcommand};
+use exr::image::{Encoding, Layer, SpecificChannels};
+use exr::meta::attribute::IntegerBounds;
+use exr::meta::header::LayerAttributes;
 use exr::prelude::{
-    read, read_first_rgba_layer_from_file, write_rgba_file, ReadChannels, ReadLayers,
-    ReadSpecificChannel, WritableImage,
+    read, read_first_rgba_layer_from_file, ReadChannels, ReadLayers, ReadSpecificChannel,
+    WritableImage,
 };
 use openfx_rs::constants;
 use openfx_rs::constants::ofxstatus;
@@ -10,25 +13,44 @@ use openfx_rs::strings::OfxStr;
 use openfx_rs::types::*;
 use serde::ser::SerializeMap;
 use serde::{Deserialize, Serialize, Serializer};
+use std::cell::Cell;
 use std::cmp::{max, min};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::env;
 use std::error::Error;
 use std::ffi::{c_char, c_int, c_void, CString};
 use std::fs;
 use std::fs::File;
-use std::io::Write;
+use std::io;
+use std::io::{BufRead, Write};
+use std::panic;
 use std::path::PathBuf;
 use std::string::String;
 use std::sync::{Arc, LazyLock, Mutex, OnceLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
 mod commands;
 use commands::*;
+mod compare;
+mod crash;
+use compare::ComparisonFailed;
+mod generators;
+mod image_io;
+mod junit;
+mod output_checks;
+mod property_audit;
+mod read_only;
 #[macro_use]
 mod handles;
 use handles::*;
 mod suite_impls;
+mod template;
+mod test_runner;
+mod timeout;
+mod trace;
+mod unsupported;
+use template::TemplateValues;
 
 /// An integer frame time
 #[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq, Eq, Hash)]
@@ -42,6 +64,9 @@ impl_handle!(ParamHandle, OfxParamHandle, Param);
 
 type GenericResult = Result<()>;
 
+// Exit status when a CompareImages command finds a difference
+const EXIT_COMPARISON_FAILED: i32 = 2;
+
 static VERBOSE: OnceLock<bool> = OnceLock::new();
 fn verbose() -> bool {
     *VERBOSE.get_or_init(|| false)
@@ -65,7 +90,15 @@ macro_rules! log_error {
 }
 
 static OUTPUT_FILE: OnceLock<Arc<File>> = OnceLock::new();
+// When set, output is collected here instead of being written, so it
+// can be returned in a command response. This is shared between
+// threads, since renders can produce output from worker threads.
+static OUTPUT_CAPTURE: Mutex<Option<Vec<String>>> = Mutex::new(None);
 fn write_output(message: &str) {
+    if let Some(lines) = OUTPUT_CAPTURE.lock().unwrap().as_mut() {
+        lines.push(message.to_string());
+        return;
+    }
     if let Some(file) = OUTPUT_FILE.get() {
         // We can't get a mutable reference to the file, but we can
         // write to an Arc<File> as long as the Arc itself is mutable.
@@ -184,6 +217,17 @@ fn crop(a: OfxRectI, b: OfxRectI) -> OfxRectI {
     }
 }
 
+// Coordinates of a pixel in a guarded buffer, by index from the start
+// of the first guard band. Pixels in the guard bands are given
+// coordinates in the rows before and after the image.
+fn pixel_coords(bounds: &OfxRectI, stride: usize, index: usize) -> (i32, i32) {
+    let offset = index as isize - GUARD_BAND_PIXELS as isize;
+    (
+        bounds.x1 + offset.rem_euclid(stride as isize) as i32,
+        bounds.y1 + offset.div_euclid(stride as isize) as i32,
+    )
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(tag = "type", content = "v")]
 pub enum ParamValue {
@@ -208,6 +252,35 @@ pub enum ParamValue {
 }
 
 impl ParamValue {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Self::Boolean(_) => "Boolean",
+            Self::Choice(_) => "Choice",
+            Self::Custom(_) => "Custom",
+            Self::Double(_) => "Double",
+            Self::Double2D(..) => "Double2D",
+            Self::Double3D(..) => "Double3D",
+            Self::Group => "Group",
+            Self::Integer(_) => "Integer",
+            Self::Integer2D(..) => "Integer2D",
+            Self::Integer3D(..) => "Integer3D",
+            Self::Page => "Page",
+            Self::Parametric => "Parametric",
+            Self::PushButton => "PushButton",
+            Self::Rgb(..) => "RGB",
+            Self::Rgba(..) => "RGBA",
+            Self::String(_) => "String",
+        }
+    }
+
+    /// Whether the param holds a value which can be saved and restored
+    fn has_value(&self) -> bool {
+        !matches!(
+            self,
+            Self::Group | Self::Page | Self::Parametric | Self::PushButton
+        )
+    }
+
     fn from_descriptor(props: &PropertySet) -> Self {
         #[allow(non_upper_case_globals)]
         match OfxStr::from_cstring(
@@ -331,8 +404,25 @@ impl Param {
         }
     }
 }
+
+impl Clone for Param {
+    fn clone(&self) -> Self {
+        // Deep copy the properties
+        Self {
+            value: self.value.clone(),
+            properties: self.properties.lock().clone().into_object(),
+        }
+    }
+}
+
 impl IntoObject for Param {}
 
+impl trace::DebugName for Param {
+    fn debug_name(&self) -> String {
+        format!("Param {}", self.properties.lock().name)
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct ParamSet {
     properties: Object<PropertySet>,
@@ -367,6 +457,12 @@ impl Default for ParamSet {
 
 impl IntoObject for ParamSet {}
 
+impl trace::DebugName for ParamSet {
+    fn debug_name(&self) -> String {
+        format!("ParamSet {}", self.properties.lock().name)
+    }
+}
+
 #[derive(Clone, Debug)]
 #[repr(C)]
 pub struct Pixel {
@@ -414,14 +510,65 @@ impl ImagePixels {
             ImagePixels::Alpha(_) => constants::ImageComponentAlpha,
         }
     }
+
+    fn components(&self) -> usize {
+        match self {
+            ImagePixels::Rgba(_) => 4,
+            ImagePixels::Alpha(_) => 1,
+        }
+    }
+
+    // All samples of all pixels. Pixel is repr(C), so RGBA pixels are
+    // four consecutive f32 samples.
+    fn samples(&self) -> &[f32] {
+        match self {
+            ImagePixels::Rgba(v) => unsafe {
+                std::slice::from_raw_parts(v.as_ptr() as *const f32, v.len() * 4)
+            },
+            ImagePixels::Alpha(v) => v,
+        }
+    }
+
+    fn samples_mut(&mut self) -> &mut [f32] {
+        match self {
+            ImagePixels::Rgba(v) => unsafe {
+                std::slice::from_raw_parts_mut(v.as_mut_ptr() as *mut f32, v.len() * 4)
+            },
+            ImagePixels::Alpha(v) => v,
+        }
+    }
+
+    fn resize(&mut self, pixels: usize) {
+        match self {
+            ImagePixels::Rgba(v) => v.resize(pixels, Pixel::zero()),
+            ImagePixels::Alpha(v) => v.resize(pixels, 0.0),
+        }
+    }
 }
 
+// Number of pixels in the guard bands before and after a guarded
+// output buffer
+const GUARD_BAND_PIXELS: usize = 64;
+
+// Written to guard bands, and to areas of an output image the plugin
+// shouldn't touch. A NaN with a payload, which a plugin is unlikely to
+// write by accident.
+const CANARY: u32 = 0x7fc0_dead;
+
 #[derive(Clone, Debug)]
 pub struct Image {
     bounds: OfxRectI,
     pixels: ImagePixels,
     stride: usize,
     properties: Object<PropertySet>,
+    // Full frame of the image, if known. Corresponds to the EXR
+    // display window.
+    display_window: Option<OfxRectI>,
+    // Read-only copy of the pixels, which the plugin sees instead
+    read_only: Option<Arc<read_only::ReadOnlyBuffer>>,
+    // If set, the pixels start and end with guard bands, which must be
+    // removed by check_guard_bands before the image is used
+    guard_bands: bool,
 }
 
 impl Image {
@@ -465,7 +612,149 @@ impl Image {
             pixels,
             stride,
             properties,
+            display_window: None,
+            read_only: None,
+            guard_bands: false,
+        }
+    }
+
+    /// Create an empty output image surrounded by guard bands. The
+    /// guard bands and row padding are filled with a canary value, so
+    /// writes to them can be detected by check_guard_bands.
+    fn guarded(
+        name: &str,
+        bounds: &OfxRectI,
+        rowbytes: Option<usize>,
+        alpha: bool,
+    ) -> Self {
+        let mut image = Self::empty(name, bounds, rowbytes, alpha);
+        let size = image.stride * bounds.height();
+        let components = image.pixels.components();
+        let canary = f32::from_bits(CANARY);
+
+        image.pixels.resize(size + 2 * GUARD_BAND_PIXELS);
+        let samples = image.pixels.samples_mut();
+        // Shift the pixels up to make room for the first guard band
+        samples.copy_within(0..size * components, GUARD_BAND_PIXELS * components);
+        for (i, pixel) in samples.chunks_exact_mut(components).enumerate() {
+            let (x, _) = pixel_coords(bounds, image.stride, i);
+            if i < GUARD_BAND_PIXELS || i >= GUARD_BAND_PIXELS + size || x >= bounds.x2 {
+                pixel.fill(canary);
+            }
+        }
+
+        // Point the plugin at the pixels after the first guard band
+        let data = unsafe {
+            image
+                .pixels
+                .as_mut_ptr()
+                .byte_add(GUARD_BAND_PIXELS * image.pixels.bytes_per_pixel())
+        };
+        image.properties.lock().set(
+            constants::ImagePropData.as_str(),
+            0,
+            PropertyValue::Pointer(Addr(data)),
+        );
+        image.guard_bands = true;
+        image
+    }
+
+    /// Check that the plugin hasn't written to the guard bands or row
+    /// padding of a guarded image, then remove the guard bands. Does
+    /// nothing for other images.
+    fn check_guard_bands(&mut self) -> GenericResult {
+        if !std::mem::take(&mut self.guard_bands) {
+            return Ok(());
         }
+        let size = self.stride * self.bounds.height();
+        let components = self.pixels.components();
+
+        // Coordinates of the written pixels in each area
+        let mut written: BTreeMap<&str, Vec<(i32, i32)>> = BTreeMap::new();
+        for (i, pixel) in self.pixels.samples().chunks_exact(components).enumerate() {
+            if pixel.iter().all(|s| s.to_bits() == CANARY) {
+                continue;
+            }
+            let (x, y) = pixel_coords(&self.bounds, self.stride, i);
+            let area = if i < GUARD_BAND_PIXELS {
+                "the guard band before the buffer"
+            } else if i >= GUARD_BAND_PIXELS + size {
+                "the guard band after the buffer"
+            } else if x >= self.bounds.x2 {
+                "the row padding"
+            } else {
+                continue;
+            };
+            written.entry(area).or_default().push((x, y));
+        }
+
+        // Remove the guard bands
+        let samples = self.pixels.samples_mut();
+        samples.copy_within(
+            GUARD_BAND_PIXELS * components..(GUARD_BAND_PIXELS + size) * components,
+            0,
+        );
+        self.pixels.resize(size);
+        let data = self.pixels.as_mut_ptr();
+        self.properties.lock().set(
+            constants::ImagePropData.as_str(),
+            0,
+            PropertyValue::Pointer(Addr(data)),
+        );
+
+        if !written.is_empty() {
+            let areas: Vec<String> = written
+                .iter()
+                .map(|(area, pixels)| {
+                    let (x, y) = pixels[0];
+                    format!(
+                        "{} pixels written in {}, first at ({}, {})",
+                        pixels.len(),
+                        area,
+                        x,
+                        y
+                    )
+                })
+                .collect();
+            bail!(
+                "Plugin wrote outside the output image: {}",
+                areas.join("; ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Give the plugin a read-only copy of the pixels, so writes to
+    /// them fault. The copy is named after the clip for crash reports.
+    fn protect(&mut self, clip: &str) -> GenericResult {
+        let (base, len) = match &self.pixels {
+            ImagePixels::Rgba(v) => {
+                (v.as_ptr() as *const u8, std::mem::size_of_val(&v[..]))
+            }
+            ImagePixels::Alpha(v) => {
+                (v.as_ptr() as *const u8, std::mem::size_of_val(&v[..]))
+            }
+        };
+        let buffer = read_only::ReadOnlyBuffer::new(
+            unsafe { std::slice::from_raw_parts(base, len) },
+            clip,
+        )?;
+
+        // Keep the offset of the data pointer, in case the image has
+        // been cropped
+        let mut props = self.properties.lock();
+        let offset = match props.get(constants::ImagePropData, 0) {
+            Ok(PropertyValue::Pointer(Addr(ptr))) => *ptr as usize - base as usize,
+            _ => 0,
+        };
+        let data = unsafe { buffer.as_ptr().byte_add(offset) };
+        props.set(
+            constants::ImagePropData.as_str(),
+            0,
+            PropertyValue::Pointer(Addr(data)),
+        );
+        self.read_only = Some(Arc::new(buffer));
+        Ok(())
     }
 
     fn empty(
@@ -529,6 +818,9 @@ enum ClipImages {
         bounds: OfxRectI,
         rowbytes: Option<usize>,
         alpha: bool,
+        premultiplication: CString,
+        // Create guarded images
+        guard: bool,
     },
 }
 
@@ -551,11 +843,21 @@ impl ClipImages {
                 bounds,
                 rowbytes,
                 alpha,
-            } => Some(
-                images
-                    .entry(frame)
-                    .or_insert_with(|| Image::empty(name, bounds, *rowbytes, *alpha)),
-            ),
+                premultiplication,
+                guard,
+            } => Some(images.entry(frame).or_insert_with(|| {
+                let image = if *guard {
+                    Image::guarded(name, bounds, *rowbytes, *alpha)
+                } else {
+                    Image::empty(name, bounds, *rowbytes, *alpha)
+                };
+                image.properties.lock().set(
+                    constants::ImageEffectPropPreMultiplication.as_str(),
+                    0,
+                    premultiplication.clone().into(),
+                );
+                image
+            })),
             ClipImages::NoImage => None,
         }
     }
@@ -583,36 +885,39 @@ pub struct Clip {
 static CLIP_IMAGES: Mutex<Vec<Object<PropertySet>>> = Mutex::new(Vec::new());
 
 impl Clip {
-    fn set_image(&mut self, image: Image) {
-        self.properties.lock().set(
+    // Describe the format of the clip's images in its properties
+    fn set_image_format(&mut self, image: &Image) {
+        let mut props = self.properties.lock();
+        props.set(
             constants::ImageEffectPropComponents.as_str(),
             0,
             image.pixels.component_str().into(),
         );
-        self.region_of_definition = Some(OfxRectD {
-            x1: 0.0,
-            y1: 0.0,
-            x2: image.bounds.width() as f64,
-            y2: image.bounds.height() as f64,
-        });
+        if let Ok(premultiplication) = image
+            .properties
+            .lock()
+            .get(constants::ImageEffectPropPreMultiplication, 0)
+        {
+            props.set(
+                constants::ImageEffectPropPreMultiplication.as_str(),
+                0,
+                premultiplication.clone(),
+            );
+        }
+    }
+
+    fn set_image(&mut self, image: Image) {
+        self.set_image_format(&image);
+        self.region_of_definition = Some(rect_to_double(image.bounds));
         self.images = ClipImages::Static(image);
     }
 
-    // Note: no longer used, but could be useful if we implement image
-    // sequences for inputs.
-    #[allow(dead_code)]
-    fn set_images(
-        &mut self,
-        width: usize,
-        height: usize,
-        images: HashMap<FrameNumber, Image>,
-    ) {
-        self.region_of_definition = Some(OfxRectD {
-            x1: 0.0,
-            y1: 0.0,
-            x2: width as f64,
-            y2: height as f64,
-        });
+    // Used for clips connected to the output of another instance
+    fn set_images(&mut self, rod: OfxRectD, images: HashMap<FrameNumber, Image>) {
+        if let Some(image) = images.values().next() {
+            self.set_image_format(image);
+        }
+        self.region_of_definition = Some(rod);
         self.images = ClipImages::Sequence(images);
     }
 
@@ -632,15 +937,36 @@ impl Clip {
         })
     }
 
-    fn release_image_handle(handle: PropertySetHandle) {
+    fn release_image_handle(handle: PropertySetHandle) -> OfxStatus {
         // Find the image corresponding to this handle and remove it
         // from the active list. It's an error to call this with an
         // image handle which isn't in use.
         let mut images = CLIP_IMAGES.lock().unwrap();
         if let Some(i) = images.iter().position(|item| item.to_handle() == handle) {
             images.remove(i);
+            ofxstatus::OK
         } else {
-            panic!("Image handle {:?} is not in use", handle);
+            drop(images);
+            bad_handle(
+                PropertySetHandle::type_name(),
+                format!("Image handle {:?} is not in use", handle),
+            )
+        }
+    }
+
+    /// Forget images which weren't released, e.g. after a render
+    /// failed part way through, logging their names
+    fn discard_unreleased_images() {
+        let mut images = CLIP_IMAGES.lock().unwrap();
+        if !images.is_empty() {
+            log_error!(
+                "Discarding unreleased images: {:?}",
+                images
+                    .iter()
+                    .map(|img| img.lock().name.clone())
+                    .collect::<Vec<_>>()
+            );
+            images.clear();
         }
     }
 
@@ -684,6 +1010,62 @@ impl Serialize for Clip {
 
 impl IntoObject for Clip {}
 
+impl trace::DebugName for Clip {
+    fn debug_name(&self) -> String {
+        format!("Clip {}", self.name)
+    }
+}
+
+thread_local! {
+    // The frame being rendered on the current thread, if any
+    static RENDER_FRAME: Cell<Option<FrameNumber>> = const { Cell::new(None) };
+}
+
+/// Simulated abort state for an instance during a render. Shared with
+/// any per-thread copies of the instance, so a trigger applies to the
+/// whole render.
+#[derive(Clone, Debug)]
+struct AbortState {
+    trigger: AbortTrigger,
+    start: Instant,
+    calls: u32,
+    // Set when abort() first returns true. Once triggered, abort()
+    // keeps returning true for the rest of the render.
+    triggered_at: Option<Instant>,
+}
+
+impl IntoObject for AbortState {}
+
+impl AbortState {
+    fn new(trigger: AbortTrigger) -> Self {
+        Self {
+            trigger,
+            start: Instant::now(),
+            calls: 0,
+            triggered_at: None,
+        }
+    }
+
+    /// Called from the abort() suite function. Returns true if the
+    /// plugin should abort.
+    fn check(&mut self) -> bool {
+        if self.triggered_at.is_none() {
+            self.calls += 1;
+            let triggered = match self.trigger {
+                AbortTrigger::AfterCalls(n) => self.calls > n,
+                AbortTrigger::AfterMillis(ms) => {
+                    self.start.elapsed() >= Duration::from_millis(ms)
+                }
+                AbortTrigger::OnFrame(frame) => RENDER_FRAME.get() == Some(frame),
+            };
+            if triggered {
+                self.triggered_at = Some(Instant::now());
+            }
+        }
+        self.triggered_at.is_some()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct ImageEffect {
     properties: Object<PropertySet>,
@@ -691,6 +1073,7 @@ pub struct ImageEffect {
     clips: HashMap<String, Object<Clip>>,
     // Stored in reverse order (next response at end of list)
     message_suite_responses: Vec<OfxStatus>,
+    abort: Option<Object<AbortState>>,
 }
 
 impl Serialize for ImageEffect {
@@ -735,6 +1118,10 @@ impl ImageEffect {
                             constants::ImageEffectPropComponents,
                             constants::ImageComponentRGBA.into(),
                         ),
+                        (
+                            constants::ImageEffectPropPreMultiplication,
+                            constants::ImagePreMultiplied.into(),
+                        ),
                         (constants::ImageEffectPropFrameRate, (24.0).into()),
                         (constants::ImagePropPixelAspectRatio, (1.0).into()),
                         (constants::ImageEffectPropFrameRange, [0.0, 1.0].into()),
@@ -750,6 +1137,35 @@ impl ImageEffect {
         self.clips.get(name.as_str()).unwrap().clone()
     }
 
+    /// Copy an instance, including params and clips. Nothing is
+    /// shared with the original except input image data.
+    fn deep_copy(&self) -> Self {
+        let param_set = self.param_set.lock();
+        let mut properties = self.properties.lock().clone();
+        // The copy will get its own instance data from CreateInstance
+        properties
+            .values
+            .remove(constants::PropInstanceData.as_str());
+        Self {
+            properties: properties.into_object(),
+            param_set: ParamSet {
+                properties: param_set.properties.lock().clone().into_object(),
+                descriptors: param_set.descriptors.clone(),
+                params: copy_map(&param_set.params),
+            }
+            .into_object(),
+            clips: copy_map(&self.clips),
+            message_suite_responses: self.message_suite_responses.clone(),
+            abort: self.abort.clone(),
+        }
+    }
+
+    /// If a simulated abort has been triggered, return the time it
+    /// happened.
+    fn aborted_at(&self) -> Option<Instant> {
+        self.abort.as_ref().and_then(|a| a.lock().triggered_at)
+    }
+
     fn get_param(&self, name: &str) -> Option<Object<Param>> {
         self.param_set.lock().params.get(name).cloned()
     }
@@ -785,12 +1201,19 @@ impl Default for ImageEffect {
             param_set: Default::default(),
             clips: Default::default(),
             message_suite_responses: vec![ofxstatus::ReplyYes, ofxstatus::ReplyNo], // Default::default(),
+            abort: None,
         }
     }
 }
 
 impl IntoObject for ImageEffect {}
 
+impl trace::DebugName for ImageEffect {
+    fn debug_name(&self) -> String {
+        format!("ImageEffect {}", self.properties.lock().name)
+    }
+}
+
 #[derive(Debug)]
 #[allow(dead_code)]
 struct Plugin {
@@ -817,14 +1240,38 @@ impl Plugin {
         out_args: PropertySetHandle,
     ) -> OfxStatus {
         let handle_ptr: *mut c_void = handle.into();
-        unsafe {
+        crash::in_action(action.as_ptr(), || unsafe {
             (self.main_entry)(
                 action.as_ptr(),
                 handle_ptr,
                 in_args.into(),
                 out_args.into(),
             )
-        }
+        })
+    }
+
+    /// Call an action with its configured timeout, returning the
+    /// status for the caller to check.
+    fn call_action_with_timeout(
+        &self,
+        action: OfxStr,
+        handle: ImageEffectHandle,
+        in_args: PropertySetHandle,
+        out_args: PropertySetHandle,
+    ) -> OfxStatus {
+        timeout::call_with_timeout(
+            action.as_str(),
+            || {
+                let handle_ptr: *mut c_void = handle.into();
+                let target = if handle_ptr.is_null() {
+                    "no instance".to_string()
+                } else {
+                    handle.with_object(|effect| effect.properties.lock().name.clone())
+                };
+                format!("{} on {} ({})", action, target, self.plugin_identifier)
+            },
+            || self.call_action(action, handle, in_args, out_args),
+        )
     }
 
     fn try_call_action(
@@ -834,7 +1281,7 @@ impl Plugin {
         in_args: PropertySetHandle,
         out_args: PropertySetHandle,
     ) -> GenericResult {
-        let stat = self.call_action(action, handle, in_args, out_args);
+        let stat = self.call_action_with_timeout(action, handle, in_args, out_args);
         if stat.succeeded() {
             Ok(())
         } else {
@@ -1178,6 +1625,12 @@ impl PropertySet {
 
 impl IntoObject for PropertySet {}
 
+impl trace::DebugName for PropertySet {
+    fn debug_name(&self) -> String {
+        format!("PropertySet {}", self.name)
+    }
+}
+
 fn plist_path(bundle_path: &std::path::Path) -> std::path::PathBuf {
     bundle_path.join("Contents/Info.plist")
 }
@@ -1365,8 +1818,7 @@ fn create_images(
     effect: &mut ImageEffect,
     inputs: HashMap<String, Image>,
     project_dims: Property,
-    output_rect: &OfxRectI,
-    output_rowbytes: Option<usize>,
+    protect_inputs: bool,
 ) -> GenericResult {
     effect.properties.lock().values.insert(
         constants::ImageEffectPropProjectSize.to_string(),
@@ -1377,23 +1829,114 @@ fn create_images(
         project_dims,
     );
 
-    // TODO: call getClipPreferences action to determine output format
-    let output_alpha = inputs
-        .get("Source")
-        .map(|img| matches!(img.pixels, ImagePixels::Alpha(_)))
-        .unwrap_or(false);
-
-    for (name, image) in inputs {
+    for (name, mut image) in inputs {
+        if protect_inputs {
+            image.protect(&name)?;
+        }
         effect.get_clip(&name)?.lock().set_image(image);
     }
-    let mut output = effect.get_clip("Output")?.lock();
+    Ok(())
+}
+
+/// Format of the output clip, chosen by the plugin in the
+/// GetClipPreferences action
+struct OutputFormat {
+    alpha: bool,
+    premultiplication: CString,
+}
+
+const OUTPUT_COMPONENTS_PROP: &str = "OfxImageClipPropComponents_Output\0";
+
+/// Call the GetClipPreferences action to find the output format. The
+/// defaults follow the Source clip, if there is one.
+fn get_output_format(
+    effect: &Object<ImageEffect>,
+    plugin: &LoadedPlugin,
+) -> Result<OutputFormat> {
+    let source = effect
+        .lock()
+        .clips
+        .get("Source")
+        .map(|clip| clip.lock().properties.clone());
+    let default = |key: OfxStr, fallback: OfxStr| -> Property {
+        source
+            .as_ref()
+            .and_then(|props| props.lock().get(key, 0).ok().cloned())
+            .unwrap_or_else(|| fallback.into())
+            .into()
+    };
+    let outargs = PropertySet::new(
+        "getClipPreferences_outargs",
+        &[
+            (
+                OfxStr::from_str(OUTPUT_COMPONENTS_PROP),
+                default(
+                    constants::ImageEffectPropComponents,
+                    constants::ImageComponentRGBA,
+                ),
+            ),
+            (
+                constants::ImageEffectPropPreMultiplication,
+                default(
+                    constants::ImageEffectPropPreMultiplication,
+                    constants::ImagePreMultiplied,
+                ),
+            ),
+        ],
+    )
+    .into_object();
+
+    #[allow(clippy::redundant_clone)]
+    plugin.plugin.try_call_action(
+        constants::ImageEffectActionGetClipPreferences,
+        effect.clone().into(),
+        PropertySetHandle::from(std::ptr::null_mut()),
+        PropertySetHandle::from(outargs.clone()),
+    )?;
 
+    let out = outargs.lock();
+    let alpha = matches!(
+        out.get(OfxStr::from_str(OUTPUT_COMPONENTS_PROP), 0),
+        Ok(PropertyValue::String(s))
+            if s.to_bytes() == constants::ImageComponentAlpha.as_str().as_bytes()
+    );
+    let premultiplication = match out.get(constants::ImageEffectPropPreMultiplication, 0)
+    {
+        Ok(PropertyValue::String(s)) => s.clone(),
+        _ => bail!(
+            "GetClipPreferences set a bad {}",
+            constants::ImageEffectPropPreMultiplication
+        ),
+    };
+    Ok(OutputFormat {
+        alpha,
+        premultiplication,
+    })
+}
+
+/// Set up the output clip to create images in the given format as the
+/// plugin asks for them
+fn create_output_images(
+    effect: &ImageEffect,
+    format: OutputFormat,
+    output_rect: &OfxRectI,
+    output_rowbytes: Option<usize>,
+    guard_output: bool,
+) -> GenericResult {
+    let mut output = effect.get_clip("Output")?.lock();
+    output.properties.lock().set(
+        constants::ImageEffectPropPreMultiplication.as_str(),
+        0,
+        format.premultiplication.clone().into(),
+    );
     output.images = ClipImages::LazySequence {
         images: HashMap::new(),
         name: "Output",
         bounds: *output_rect,
         rowbytes: output_rowbytes,
-        alpha: output_alpha,
+        alpha: format.alpha,
+        premultiplication: format.premultiplication,
+        guard: guard_output,
     };
     Ok(())
 }
@@ -1407,11 +1950,67 @@ fn get_image_stride(width: usize, rowbytes: Option<usize>, pixel_size: usize) ->
         .unwrap_or(width)
 }
 
+/// Convert an EXR data window to OFX bounds, relative to the bottom
+/// left of the display window. EXR y coordinates increase downwards.
+fn exr_data_window_bounds(
+    display_window: &IntegerBounds,
+    position: exr::math::Vec2<i32>,
+    size: exr::math::Vec2<usize>,
+) -> OfxRectI {
+    let x1 = position.0 - display_window.position.0;
+    let y1 = display_window.position.1 + display_window.size.1 as i32
+        - (position.1 + size.1 as i32);
+    OfxRectI {
+        x1,
+        y1,
+        x2: x1 + size.0 as i32,
+        y2: y1 + size.1 as i32,
+    }
+}
+
+/// Inverse of exr_data_window_bounds: get the data window position of
+/// an image with the given bounds, with the display window at the
+/// origin
+fn exr_layer_position(
+    bounds: &OfxRectI,
+    display_window: &OfxRectI,
+) -> exr::math::Vec2<i32> {
+    exr::math::Vec2(bounds.x1 - display_window.x1, display_window.y2 - bounds.y2)
+}
+
+/// Get the bounds and display window of an image read from EXR. If
+/// origin is given, the image is placed there, otherwise at the data
+/// window offset. The display window becomes a rectangle of the same
+/// size at the origin.
+fn exr_bounds(
+    origin: Option<(i32, i32)>,
+    display_window: &IntegerBounds,
+    layer_position: exr::math::Vec2<i32>,
+    size: exr::math::Vec2<usize>,
+) -> (OfxRectI, OfxRectI) {
+    let bounds = exr_data_window_bounds(display_window, layer_position, size);
+    let (x1, y1) = origin.unwrap_or((bounds.x1, bounds.y1));
+    (
+        OfxRectI {
+            x1,
+            y1,
+            x2: x1 + size.0 as i32,
+            y2: y1 + size.1 as i32,
+        },
+        OfxRectI {
+            x1: 0,
+            y1: 0,
+            x2: display_window.size.0 as i32,
+            y2: display_window.size.1 as i32,
+        },
+    )
+}
+
 fn read_exr(
     name: &str,
     path: &str,
     rowbytes: Option<usize>,
-    origin: (i32, i32),
+    origin: Option<(i32, i32)>,
 ) -> Result<Image> {
     read_exr_rgba(name, path, rowbytes, origin).or_else(|_| {
         read_exr_alpha(name, path, rowbytes, origin)
@@ -1423,7 +2022,7 @@ fn read_exr_rgba(
     name: &str,
     path: &str,
     rowbytes: Option<usize>,
-    origin: (i32, i32),
+    origin: Option<(i32, i32)>,
 ) -> Result<Image> {
     // Rowbytes calculation is a bit weird:
     // read_first_rgba_layer_from_file can't return a separate
@@ -1431,7 +2030,7 @@ fn read_exr_rgba(
     // recalculate stride several times.
     let pixel_size = std::mem::size_of::<Pixel>();
 
-    let (width, height, pixels) = read_first_rgba_layer_from_file(
+    let exr_image = read_first_rgba_layer_from_file(
         path,
         // Construct pixel storage. We use a tuple which includes
         // width and height, so we can correctly interpret the flat
@@ -1457,37 +2056,38 @@ fn read_exr_rgba(
             pixels[index] = Pixel { r, g, b, a };
         },
     )
-    .with_context(|| format!("Read EXR \"{}\"", path))?
-    .layer_data
-    .channel_data
-    .pixels; // Get the pixel storage we constructed
-
-    let (x1, y1) = origin;
-    let bounds = OfxRectI {
-        x1,
-        y1,
-        x2: x1 + width as i32,
-        y2: y1 + height as i32,
-    };
+    .with_context(|| format!("Read EXR \"{}\"", path))?;
+    let layer = exr_image.layer_data;
+    // Get the pixel storage we constructed
+    let (width, _, pixels) = layer.channel_data.pixels;
+
+    let (bounds, display_window) = exr_bounds(
+        origin,
+        &exr_image.attributes.display_window,
+        layer.attributes.layer_position,
+        layer.size,
+    );
 
     // Discard the exr image struct and build our own
-    Ok(Image::new(
+    let mut image = Image::new(
         name,
         &bounds,
         ImagePixels::Rgba(pixels),
         get_image_stride(width, rowbytes, pixel_size),
-    ))
+    );
+    image.display_window = Some(display_window);
+    Ok(image)
 }
 
 fn read_exr_alpha(
     name: &str,
     path: &str,
     rowbytes: Option<usize>,
-    origin: (i32, i32),
+    origin: Option<(i32, i32)>,
 ) -> Result<Image> {
     let pixel_size = std::mem::size_of::<f32>();
 
-    let (width, height, pixels) = read()
+    let exr_image = read()
         .no_deep_data()
         .largest_resolution_level()
         .specific_channels()
@@ -1514,54 +2114,66 @@ fn read_exr_alpha(
         .first_valid_layer()
         .all_attributes()
         .from_file(path)
-        .with_context(|| format!("Read EXR \"{}\"", path))?
-        .layer_data
-        .channel_data
-        .pixels;
-
-    let (x1, y1) = origin;
-    let bounds = OfxRectI {
-        x1,
-        y1,
-        x2: x1 + width as i32,
-        y2: y1 + height as i32,
-    };
+        .with_context(|| format!("Read EXR \"{}\"", path))?;
+    let layer = exr_image.layer_data;
+    let (width, _, pixels) = layer.channel_data.pixels;
+
+    let (bounds, display_window) = exr_bounds(
+        origin,
+        &exr_image.attributes.display_window,
+        layer.attributes.layer_position,
+        layer.size,
+    );
 
-    Ok(Image::new(
+    let mut image = Image::new(
         name,
         &bounds,
         ImagePixels::Alpha(pixels),
         get_image_stride(width, rowbytes, pixel_size),
-    ))
+    );
+    image.display_window = Some(display_window);
+    Ok(image)
 }
 
+/// Write an image to EXR. The image bounds become the data window,
+/// and its display window (or the bounds, if it has none) becomes the
+/// display window.
 fn write_exr(filename: &str, image: Image) -> GenericResult {
+    let width = image.bounds.width();
+    let height = image.bounds.height();
+    let stride = image.stride;
+    // Flip y and convert to flat index
+    let index = |x: usize, y: usize| (height - 1 - y) * stride + x;
+
+    let display_window = image.display_window.unwrap_or(image.bounds);
+    let attributes = LayerAttributes {
+        layer_position: exr_layer_position(&image.bounds, &display_window),
+        ..LayerAttributes::default()
+    };
+    let display_window =
+        IntegerBounds::from_dimensions((display_window.width(), display_window.height()));
+
     match &image.pixels {
         ImagePixels::Rgba(pixels) => {
-            write_rgba_file(
-                filename,
-                image.bounds.width(),
-                image.bounds.height(),
-                |x, y| {
-                    // Flip y and convert to flat index
-                    let pixel =
-                        &pixels[(image.bounds.height() - 1 - y) * image.stride + x];
-                    (pixel.r, pixel.g, pixel.b, pixel.a)
-                },
-            )?;
+            let channels = SpecificChannels::rgba(|exr::math::Vec2(x, y)| {
+                let pixel = &pixels[index(x, y)];
+                (pixel.r, pixel.g, pixel.b, pixel.a)
+            });
+            let layer =
+                Layer::new((width, height), attributes, Encoding::default(), channels);
+            let mut exr_image = exr::image::Image::from_layer(layer);
+            exr_image.attributes.display_window = display_window;
+            exr_image.write().to_file(filename)?;
         }
         ImagePixels::Alpha(pixels) => {
-            let width = image.bounds.width();
-            let height = image.bounds.height();
-            let stride = image.stride;
-            let channels = exr::image::SpecificChannels::build()
+            let channels = SpecificChannels::build()
                 .with_channel::<f32>("A")
-                .with_pixel_fn(|exr::math::Vec2(x, y)| {
-                    (pixels[(height - 1 - y) * stride + x],)
-                });
-            exr::image::Image::from_channels((width, height), channels)
-                .write()
-                .to_file(filename)?;
+                .with_pixel_fn(|exr::math::Vec2(x, y)| (pixels[index(x, y)],));
+            let layer =
+                Layer::new((width, height), attributes, Encoding::default(), channels);
+            let mut exr_image = exr::image::Image::from_layer(layer);
+            exr_image.attributes.display_window = display_window;
+            exr_image.write().to_file(filename)?;
         }
     }
 
@@ -1579,7 +2191,11 @@ struct LoadedPlugin {
 
 struct Instance {
     plugin_name: String,
+    context: ImageEffectContext,
     effect: Object<ImageEffect>,
+    // Input clips connected to the output of another instance. Maps
+    // clip name to instance name.
+    connections: HashMap<String, String>,
 }
 
 // Mutable state for running commands
@@ -1837,79 +2453,153 @@ fn create(
     context: ImageEffectContext,
     state: &mut CommandState,
 ) -> GenericResult {
-    let effect = {
-        let plugin = state.get_plugin(plugin_name)?;
-        let descriptor = plugin.descriptor.lock();
-        let values = &descriptor.properties.lock().values;
-        let context_str = image_effect_context_str(context);
-
-        if !values
-            .get(constants::ImageEffectPropSupportedContexts.as_str())
-            .map(|p| p.0.contains(&context_str.into()))
-            .unwrap_or(false)
-        {
-            bail!("Filter context not supported");
-        }
-        if !values
-            .get(constants::ImageEffectPropSupportedPixelDepths.as_str())
-            .map(|p| p.0.contains(&constants::BitDepthFloat.into()))
-            .unwrap_or(false)
-        {
-            bail!("OfxBitDepthFloat not supported");
-        }
-
-        // Descriptor for the plugin in Filter context
-        let filter = ImageEffect {
-            properties: PropertySet::new(
-                "filter",
-                &[(
-                    constants::PluginPropFilePath,
-                    plugin.bundle.path.to_str().unwrap().into(),
-                )],
-            )
-            .into_object(),
-            ..Default::default()
-        }
-        .into_object();
-
-        let filter_inargs = PropertySet::new(
-            "filter_inargs",
-            &[(constants::ImageEffectPropContext, context_str.into())],
-        )
-        .into_object();
-        #[allow(clippy::redundant_clone)]
-        plugin.plugin.try_call_action(
-            constants::ImageEffectActionDescribeInContext,
-            filter.clone().into(),
-            PropertySetHandle::from(filter_inargs.clone()),
-            PropertySetHandle::from(std::ptr::null_mut()),
-        )?;
-
-        // Instance of the filter. Both instances and descriptors are
-        // ImageEffect objects.
-        let filter_instance: Object<ImageEffect> =
-            create_instance(&filter.lock(), context_str.as_str()).into_object();
+    create_with_params(plugin_name, instance_name, context, BTreeMap::new(), state)?;
+    Ok(())
+}
 
-        plugin.plugin.try_call_action(
-            constants::ActionCreateInstance,
-            filter_instance.clone().into(),
-            PropertySetHandle::from(std::ptr::null_mut()),
-            PropertySetHandle::from(std::ptr::null_mut()),
-        )?;
-        filter_instance
-    };
+/// Create an instance, setting param values before the CreateInstance
+/// action as a host does when loading a project. Returns a
+/// description of each param which couldn't be set.
+fn create_with_params(
+    plugin_name: &str,
+    instance_name: &str,
+    context: ImageEffectContext,
+    params: BTreeMap<String, ParamValue>,
+    state: &mut CommandState,
+) -> Result<Vec<String>> {
+    let (effect, errors) =
+        create_effect_with_params(plugin_name, instance_name, context, params, state)?;
     state.instances.insert(
         instance_name.to_string(),
         Instance {
             plugin_name: plugin_name.to_string(),
+            context,
             effect,
+            connections: HashMap::new(),
         },
     );
+    Ok(errors)
+}
+
+/// Create an instance of a plugin with the given param values,
+/// without adding it to the command state. Returns the instance and
+/// any param errors.
+fn create_effect_with_params(
+    plugin_name: &str,
+    instance_name: &str,
+    context: ImageEffectContext,
+    params: BTreeMap<String, ParamValue>,
+    state: &CommandState,
+) -> Result<(Object<ImageEffect>, Vec<String>)> {
+    let plugin = state.get_plugin(plugin_name)?;
+    let descriptor = plugin.descriptor.lock();
+    let values = &descriptor.properties.lock().values;
+    let context_str = image_effect_context_str(context);
+
+    if !values
+        .get(constants::ImageEffectPropSupportedContexts.as_str())
+        .map(|p| p.0.contains(&context_str.into()))
+        .unwrap_or(false)
+    {
+        bail!("Filter context not supported");
+    }
+    if !values
+        .get(constants::ImageEffectPropSupportedPixelDepths.as_str())
+        .map(|p| p.0.contains(&constants::BitDepthFloat.into()))
+        .unwrap_or(false)
+    {
+        bail!("OfxBitDepthFloat not supported");
+    }
+
+    // Descriptor for the plugin in Filter context
+    let filter = ImageEffect {
+        properties: PropertySet::new(
+            "filter",
+            &[(
+                constants::PluginPropFilePath,
+                plugin.bundle.path.to_str().unwrap().into(),
+            )],
+        )
+        .into_object(),
+        ..Default::default()
+    }
+    .into_object();
+
+    let filter_inargs = PropertySet::new(
+        "filter_inargs",
+        &[(constants::ImageEffectPropContext, context_str.into())],
+    )
+    .into_object();
+    #[allow(clippy::redundant_clone)]
+    plugin.plugin.try_call_action(
+        constants::ImageEffectActionDescribeInContext,
+        filter.clone().into(),
+        PropertySetHandle::from(filter_inargs.clone()),
+        PropertySetHandle::from(std::ptr::null_mut()),
+    )?;
+
+    // Instance of the filter. Both instances and descriptors are
+    // ImageEffect objects.
+    let filter_instance: Object<ImageEffect> =
+        create_instance(&filter.lock(), context_str.as_str()).into_object();
+    // Identifies the instance in error messages
+    filter_instance.lock().properties.lock().name = format!("instance {}", instance_name);
+
+    let (values, errors) = check_param_values(&filter_instance, params);
+    for (name, value) in values {
+        let param = filter_instance.lock().get_param(&name).unwrap();
+        param.lock().value = value;
+    }
+
+    plugin.plugin.try_call_action(
+        constants::ActionCreateInstance,
+        filter_instance.clone().into(),
+        PropertySetHandle::from(std::ptr::null_mut()),
+        PropertySetHandle::from(std::ptr::null_mut()),
+    )?;
+    Ok((filter_instance, errors))
+}
+
+/// Create a new instance which is a copy of an existing one, with
+/// the same params and clip images. Calls the CreateInstance action
+/// on the copy.
+fn clone_instance(
+    effect: &Object<ImageEffect>,
+    plugin: &LoadedPlugin,
+) -> Result<Object<ImageEffect>> {
+    let copy = effect.lock().deep_copy().into_object();
+    plugin.plugin.try_call_action(
+        constants::ActionCreateInstance,
+        copy.clone().into(),
+        PropertySetHandle::from(std::ptr::null_mut()),
+        PropertySetHandle::from(std::ptr::null_mut()),
+    )?;
+    Ok(copy)
+}
+
+/// After a plugin has been told to abort, check that it returned
+/// promptly with an acceptable status.
+fn check_abort_response(
+    stat: OfxStatus,
+    aborted_at: Instant,
+    abort_limit: Duration,
+) -> GenericResult {
+    let elapsed = aborted_at.elapsed();
+    if elapsed > abort_limit {
+        bail!(
+            "Plugin took {:?} to return after abort (limit {:?})",
+            elapsed,
+            abort_limit
+        );
+    }
+    if stat != ofxstatus::OK && stat != ofxstatus::Failed {
+        bail!("Unexpected status after abort: {:?}", stat);
+    }
     Ok(())
 }
 
 fn get_output_rect(
-    inputs: &HashMap<String, Image>,
+    rods: &HashMap<String, OfxRectD>,
     layout: Option<&RenderLayout>,
     project_rect: OfxRectD,
     instance: &Instance,
@@ -1919,17 +2609,12 @@ fn get_output_rect(
         if let Some(w) = l.render_window {
             w
         } else {
-            let rods: HashMap<String, OfxRectD> = inputs
-                .iter()
-                .map(|(name, input)| (name.clone(), rect_to_double(input.bounds)))
-                .collect();
-
             // If layout is given but doesn't specify the render
             // window, compute it with the plugin's RoD action
             crop(
                 rect_to_int(get_rod_for_instance(
                     (project_rect.x2, project_rect.y2),
-                    &rods,
+                    rods,
                     instance,
                     plugin,
                 )?),
@@ -1942,36 +2627,281 @@ fn get_output_rect(
 }
 
 fn get_input_image(name: &str, input: &Input) -> Result<Image> {
-    read_exr(name, &input.filename, input.rowbytes, input.origin)
+    match &input.source {
+        InputSource::File {
+            filename,
+            format,
+            channels,
+        } => image_io::read_image(
+            name,
+            filename,
+            *format,
+            channels.as_ref(),
+            input.rowbytes,
+            input.origin,
+        ),
+        InputSource::Generated { generator } => generators::generate_image(
+            name,
+            generator,
+            input.rowbytes,
+            input.origin.unwrap_or((0, 0)),
+        ),
+    }
+}
+
+fn connect_clip(
+    source_instance: &str,
+    instance_name: &str,
+    clip_name: &str,
+    state: &mut CommandState,
+) -> GenericResult {
+    if clip_name == "Output" {
+        bail!("Can't connect to the Output clip");
+    }
+    state
+        .get_instance(instance_name)?
+        .effect
+        .lock()
+        .get_clip(clip_name)?;
+
+    // Walk upstream from the source to make sure this connection
+    // doesn't create a cycle
+    let mut pending = vec![source_instance.to_string()];
+    while let Some(name) = pending.pop() {
+        if name == instance_name {
+            bail!(
+                "Connecting {} to {} would create a cycle",
+                source_instance,
+                instance_name
+            );
+        }
+        pending.extend(state.get_instance(&name)?.connections.values().cloned());
+    }
+
+    state
+        .instances
+        .get_mut(instance_name)
+        .unwrap()
+        .connections
+        .insert(clip_name.to_string(), source_instance.to_string());
+    Ok(())
+}
+
+/// Upstream results shared by all the renders of one graph, so that
+/// node inputs are read, and RoDs computed, once per render.
+#[derive(Default)]
+struct GraphCache {
+    // RoDs of upstream instances, by instance name
+    rods: HashMap<String, OfxRectD>,
+    // Input images read to compute RoDs, by instance and clip name,
+    // held until the instance renders
+    images: HashMap<(String, String), Image>,
+}
+
+impl GraphCache {
+    /// Get an instance's input image, reading it if it isn't cached.
+    fn input_image(
+        &mut self,
+        instance_name: &str,
+        name: &str,
+        input: &Input,
+    ) -> Result<&Image> {
+        let key = (instance_name.to_string(), name.to_string());
+        if !self.images.contains_key(&key) {
+            self.images
+                .insert(key.clone(), get_input_image(name, input)?);
+        }
+        Ok(&self.images[&key])
+    }
+
+    /// Take an instance's input image for rendering, reading it if it
+    /// isn't cached. Each image is passed to a single render, so a
+    /// later render of the same instance reads it again.
+    fn take_input_image(
+        &mut self,
+        instance_name: &str,
+        name: &str,
+        input: &Input,
+    ) -> Result<Image> {
+        match self
+            .images
+            .remove(&(instance_name.to_string(), name.to_string()))
+        {
+            Some(image) => Ok(image),
+            None => get_input_image(name, input),
+        }
+    }
+}
+
+/// Get the RoD of an instance's output, following connections to
+/// upstream instances.
+fn get_graph_rod(
+    instance_name: &str,
+    inputs: &HashMap<String, Input>,
+    project_extent: (f64, f64),
+    options: &RenderOptions,
+    state: &CommandState,
+    cache: &mut GraphCache,
+) -> Result<OfxRectD> {
+    if let Some(rod) = cache.rods.get(instance_name) {
+        return Ok(*rod);
+    }
+
+    let instance = state.get_instance(instance_name)?;
+    let plugin = state.get_plugin(&instance.plugin_name)?;
+
+    let mut rods = HashMap::new();
+    for (name, input) in inputs {
+        let image = cache.input_image(instance_name, name, input)?;
+        rods.insert(name.clone(), rect_to_double(image.bounds));
+    }
+    for (clip, source) in &instance.connections {
+        let rod = get_graph_rod(
+            source,
+            options.node_inputs.get(source).unwrap_or(&HashMap::new()),
+            project_extent,
+            options,
+            state,
+            cache,
+        )?;
+        rods.insert(clip.clone(), rod);
+    }
+
+    let rod = get_rod_for_instance(project_extent, &rods, instance, plugin)?;
+    cache.rods.insert(instance_name.to_string(), rod);
+    Ok(rod)
 }
 
 trait ImageWriter {
     fn write_image(&self, frame: u32, image: Image) -> GenericResult;
 }
 
-struct ExrWriter {
-    output_directory: Option<String>,
-    format_width: usize,
+/// Writes image files named by a template
+struct FileWriter {
+    // Path template. If None, images are discarded.
+    template: Option<String>,
+    instance: String,
+    plugin: String,
+    frame_padding: usize,
+    format: ImageFormat,
+    depth: Option<SampleDepth>,
+    // If set, write EXR parts with this name instead of whole files
+    layer: Option<String>,
 }
 
-impl ExrWriter {
-    fn new(output_directory: Option<&String>, frame_limit: u32) -> Self {
-        ExrWriter {
-            output_directory: output_directory.cloned(),
-            format_width: (frame_limit.ilog10() + 1) as usize,
+impl FileWriter {
+    fn new(
+        options: &OutputOptions,
+        instance_name: &str,
+        plugin_name: &str,
+        frame_limit: u32,
+    ) -> Result<Self> {
+        // Escape the directory so braces in it aren't taken as tokens
+        let directory = options
+            .output_directory
+            .as_ref()
+            .map(|d| d.replace('{', "{{").replace('}', "}}"));
+        let template = match (directory, &options.template) {
+            (Some(directory), Some(template)) => Some(format!("{directory}/{template}")),
+            (Some(directory), None) => Some(format!("{directory}/{{frame}}.{{ext}}")),
+            (None, template) => template.clone(),
+        };
+
+        let format = options
+            .output_format
+            .or_else(|| {
+                options
+                    .template
+                    .as_ref()
+                    .and_then(|t| ImageFormat::from_path(t).ok())
+            })
+            .unwrap_or(ImageFormat::Exr);
+        let layer = options.output_layer.clone();
+        let depth = options.output_depth;
+        if layer.is_some()
+            && (format != ImageFormat::Exr
+                || depth.is_some_and(|d| d != SampleDepth::F32))
+        {
+            bail!("Output layers can only be written to f32 EXR files");
         }
+
+        let writer = FileWriter {
+            template,
+            instance: instance_name.to_string(),
+            plugin: plugin_name.to_string(),
+            frame_padding: options
+                .frame_padding
+                .unwrap_or((frame_limit.ilog10() + 1) as usize),
+            format,
+            depth,
+            layer,
+        };
+        // Check the template before rendering anything
+        let empty = OfxRectI {
+            x1: 0,
+            y1: 0,
+            x2: 0,
+            y2: 0,
+        };
+        writer.path(0, &empty, None)?;
+        Ok(writer)
+    }
+
+    fn path(
+        &self,
+        frame: u32,
+        bounds: &OfxRectI,
+        project: Option<OfxRectI>,
+    ) -> Result<Option<String>> {
+        self.template
+            .as_ref()
+            .map(|template| {
+                template::expand(
+                    template,
+                    &TemplateValues {
+                        instance: &self.instance,
+                        plugin: &self.plugin,
+                        ext: self.format.extension(),
+                        frame,
+                        frame_padding: self.frame_padding,
+                        bounds: *bounds,
+                        project: project.unwrap_or(*bounds),
+                    },
+                )
+            })
+            .transpose()
     }
 }
 
-impl ImageWriter for ExrWriter {
+/// Keeps rendered images in memory, for passing the output of one
+/// instance to the input of another
+#[derive(Default)]
+struct MemoryWriter {
+    images: Mutex<HashMap<FrameNumber, Image>>,
+}
+
+impl ImageWriter for MemoryWriter {
     fn write_image(&self, frame: u32, image: Image) -> GenericResult {
-        if let Some(output_directory) = &self.output_directory {
-            let format_width = self.format_width;
+        self.images
+            .lock()
+            .unwrap()
+            .insert(FrameNumber(frame), image);
+        Ok(())
+    }
+}
 
-            write_exr(
-                &format!("{output_directory}/{frame:0format_width$}.exr"),
-                image,
-            )?;
+impl ImageWriter for FileWriter {
+    fn write_image(&self, frame: u32, image: Image) -> GenericResult {
+        if let Some(path) = self.path(frame, &image.bounds, image.display_window)? {
+            if let Some(parent) = std::path::Path::new(&path).parent() {
+                fs::create_dir_all(parent)?;
+            }
+            match &self.layer {
+                Some(layer) => image_io::write_exr_layer(&path, layer, image)?,
+                None => {
+                    image_io::write_image(&path, Some(self.format), self.depth, image)?
+                }
+            }
         }
         Ok(())
     }
@@ -1982,14 +2912,52 @@ fn render<W: ImageWriter + Sync>(
     inputs: &HashMap<String, Input>,
     writer: &W,
     layout: Option<&RenderLayout>,
-    frame_range: (FrameNumber, FrameNumber),
-    thread_count: u32,
-    state: &mut CommandState,
+    options: &RenderOptions,
+    state: &CommandState,
+) -> GenericResult {
+    if let Some(check) = &options.deterministic_check {
+        return render_deterministic(
+            instance_name,
+            inputs,
+            writer,
+            layout,
+            options,
+            check,
+            state,
+        );
+    }
+
+    render_node(
+        instance_name,
+        inputs,
+        writer,
+        layout,
+        options,
+        state,
+        &mut GraphCache::default(),
+    )
+}
+
+/// Render an instance and, recursively, the instances connected to
+/// it, sharing upstream inputs and RoDs through the cache.
+fn render_node<W: ImageWriter + Sync>(
+    instance_name: &str,
+    inputs: &HashMap<String, Input>,
+    writer: &W,
+    layout: Option<&RenderLayout>,
+    options: &RenderOptions,
+    state: &CommandState,
+    cache: &mut GraphCache,
 ) -> GenericResult {
-    let (FrameNumber(frame_min), FrameNumber(frame_limit)) = frame_range;
+    let (FrameNumber(frame_min), FrameNumber(frame_limit)) = options.frame_range;
     if frame_limit <= frame_min {
         bail!(format!("Invalid frame range {frame_min}..{frame_limit}"));
     }
+    // Split the frames into equal chunks, one per thread. Threads
+    // beyond the number of chunks would have nothing to render.
+    let frame_count = frame_limit - frame_min;
+    let chunk_size = frame_count.div_ceil(options.thread_count.clamp(1, frame_count));
+    let thread_count = frame_count.div_ceil(chunk_size);
 
     let instance = state.get_instance(instance_name)?;
     let plugin = state.get_plugin(&instance.plugin_name)?;
@@ -1997,7 +2965,9 @@ fn render<W: ImageWriter + Sync>(
     let input_images = inputs
         .iter()
         .map(|(name, input)| {
-            get_input_image(name, input).map(|image| (name.clone(), image))
+            cache
+                .take_input_image(instance_name, name, input)
+                .map(|image| (name.clone(), image))
         })
         .collect::<Result<HashMap<_, _>>>()
         .with_context(|| "Reading input images")?;
@@ -2007,15 +2977,85 @@ fn render<W: ImageWriter + Sync>(
     let project_dims = if let Some(l) = layout {
         [l.project_dims.0, l.project_dims.1]
     } else if let Some(image) = input_images.get("Source") {
-        [image.bounds.width() as f64, image.bounds.height() as f64]
+        let extent = image.display_window.unwrap_or(image.bounds);
+        [extent.width() as f64, extent.height() as f64]
     } else {
         bail!("No Source input, please specify render layout.");
     };
 
     let project_rect = rect_from_dims(project_dims[0], project_dims[1]);
+    let project_extent = (project_dims[0], project_dims[1]);
+
+    let mut rods: HashMap<String, OfxRectD> = input_images
+        .iter()
+        .map(|(name, image)| (name.clone(), rect_to_double(image.bounds)))
+        .collect();
+    for (clip, source) in &instance.connections {
+        if inputs.contains_key(clip) {
+            bail!("Clip {} has both an input and a connection", clip);
+        }
+        let rod = get_graph_rod(
+            source,
+            options.node_inputs.get(source).unwrap_or(&HashMap::new()),
+            project_extent,
+            options,
+            state,
+            cache,
+        )
+        .with_context(|| format!("Getting RoD of {}", source))?;
+        rods.insert(clip.clone(), rod);
+    }
+
+    let output_rect = get_output_rect(&rods, layout, project_rect, instance, plugin)?;
+
+    // Render upstream instances, restricted to the RoI of the clip
+    // they're connected to
+    let mut upstream_images = HashMap::new();
+    if !instance.connections.is_empty() {
+        let rois = get_rois_for_instance(
+            project_extent,
+            &rect_to_double(output_rect),
+            instance,
+            plugin,
+        )?;
+        for (clip, source) in &instance.connections {
+            let roi = rois
+                .get(clip)
+                .context(format!("Missing RoI for clip {}", clip))?;
+            let rod = rods[clip];
+            let window = crop(rect_to_int(*roi), rect_to_int(rod));
+            if window.x2 <= window.x1 || window.y2 <= window.y1 {
+                bail!("Empty RoI for clip {}", clip);
+            }
 
-    let output_rect =
-        get_output_rect(&input_images, layout, project_rect, instance, plugin)?;
+            let upstream_layout = RenderLayout {
+                project_dims: project_extent,
+                render_window: Some(window),
+                rowbytes: layout.and_then(|l| l.rowbytes),
+                crop_inputs_to_roi: layout.map(|l| l.crop_inputs_to_roi).unwrap_or(false),
+            };
+            let upstream_options = RenderOptions {
+                abort: None,
+                deterministic_check: None,
+                ..options.clone()
+            };
+            let upstream_writer = MemoryWriter::default();
+            render_node(
+                source,
+                options.node_inputs.get(source).unwrap_or(&HashMap::new()),
+                &upstream_writer,
+                Some(&upstream_layout),
+                &upstream_options,
+                state,
+                cache,
+            )
+            .with_context(|| format!("Rendering {}", source))?;
+            upstream_images.insert(
+                clip.clone(),
+                (rod, upstream_writer.images.into_inner().unwrap()),
+            );
+        }
+    }
 
     // Crop images to corresponding input RoIs
     if layout.map(|l| l.crop_inputs_to_roi).unwrap_or(false) {
@@ -2037,52 +3077,102 @@ fn render<W: ImageWriter + Sync>(
         &mut instance.effect.lock(),
         input_images,
         project_dims.into(),
+        options.protect_inputs,
+    )?;
+    for (clip, (rod, mut images)) in upstream_images {
+        if options.protect_inputs {
+            for image in images.values_mut() {
+                image.protect(&clip)?;
+            }
+        }
+        instance
+            .effect
+            .lock()
+            .get_clip(&clip)?
+            .lock()
+            .set_images(rod, images);
+    }
+
+    let output_format = get_output_format(&instance.effect, plugin)?;
+    create_output_images(
+        &instance.effect.lock(),
+        output_format,
         &output_rect,
         layout.and_then(|l| l.rowbytes),
+        options.guard_output,
     )?;
-
     instance.effect.lock().check_required_inputs()?;
 
-    let render_range = move |start, limit| -> GenericResult {
-        for frame in start..limit {
-            let render_inargs = PropertySet::new(
-                "render_inargs",
-                &[
-                    (constants::PropTime, (frame as f64).into()),
-                    (
-                        constants::ImageEffectPropFieldToRender,
-                        constants::ImageFieldNone.into(),
-                    ),
-                    (
-                        constants::ImageEffectPropRenderWindow,
-                        (&output_rect).into(),
-                    ),
-                    (constants::ImageEffectPropRenderScale, [1.0, 1.0].into()),
-                    (
-                        constants::ImageEffectPropSequentialRenderStatus,
-                        false.into(),
-                    ),
-                    (
-                        constants::ImageEffectPropInteractiveRenderStatus,
-                        false.into(),
-                    ),
-                    (constants::ImageEffectPropRenderQualityDraft, false.into()),
-                ],
-            )
-            .into_object();
+    instance.effect.lock().abort = options
+        .abort
+        .map(|trigger| AbortState::new(trigger).into_object());
+    let abort_limit = Duration::from_millis(options.abort_limit_ms);
+
+    let render_range =
+        move |effect: &Object<ImageEffect>, start, limit| -> GenericResult {
+            for frame in start..limit {
+                // Stop early if another thread has already aborted
+                if effect.lock().aborted_at().is_some() {
+                    break;
+                }
 
-            #[allow(clippy::redundant_clone)]
-            plugin.plugin.try_call_action(
-                constants::ImageEffectActionRender,
-                instance.effect.clone().into(),
-                PropertySetHandle::from(render_inargs.clone()),
-                PropertySetHandle::from(std::ptr::null_mut()),
-            )?;
+                let render_inargs = PropertySet::new(
+                    "render_inargs",
+                    &[
+                        (constants::PropTime, (frame as f64).into()),
+                        (
+                            constants::ImageEffectPropFieldToRender,
+                            constants::ImageFieldNone.into(),
+                        ),
+                        (
+                            constants::ImageEffectPropRenderWindow,
+                            (&output_rect).into(),
+                        ),
+                        (constants::ImageEffectPropRenderScale, [1.0, 1.0].into()),
+                        (
+                            constants::ImageEffectPropSequentialRenderStatus,
+                            false.into(),
+                        ),
+                        (
+                            constants::ImageEffectPropInteractiveRenderStatus,
+                            false.into(),
+                        ),
+                        (constants::ImageEffectPropRenderQualityDraft, false.into()),
+                    ],
+                )
+                .into_object();
+
+                RENDER_FRAME.set(Some(FrameNumber(frame)));
+                #[allow(clippy::redundant_clone)]
+                let stat = plugin.plugin.call_action_with_timeout(
+                    constants::ImageEffectActionRender,
+                    effect.clone().into(),
+                    PropertySetHandle::from(render_inargs.clone()),
+                    PropertySetHandle::from(std::ptr::null_mut()),
+                );
+                RENDER_FRAME.set(None);
+
+                let aborted_at = effect.lock().aborted_at();
+                if let Some(aborted_at) = aborted_at {
+                    check_abort_response(stat, aborted_at, abort_limit)
+                        .with_context(|| format!("Aborted render of frame {}", frame))?;
+                    output!("Render aborted at frame {}", frame);
+                    // Discard the partial output
+                    effect
+                        .lock()
+                        .clips
+                        .get("Output")
+                        .unwrap()
+                        .lock()
+                        .images
+                        .take_image_at_frame(FrameNumber(frame));
+                    break;
+                }
+                if stat.failed() {
+                    bail!("{} failed: {:?}", constants::ImageEffectActionRender, stat);
+                }
 
-            writer.write_image(
-                frame,
-                instance
-                    .effect
+                let mut image = effect
                     .lock()
                     .clips
                     .get("Output")
@@ -2090,48 +3180,176 @@ fn render<W: ImageWriter + Sync>(
                     .lock()
                     .images
                     .take_image_at_frame(FrameNumber(frame))
-                    .unwrap(),
-            )?;
+                    .unwrap();
+                image
+                    .check_guard_bands()
+                    .with_context(|| format!("Checking output of frame {}", frame))?;
+                output_checks::check_output(&image, &options.output_checks, frame)?;
+                image.display_window = Some(rect_to_int(project_rect));
+                writer.write_image(frame, image)?;
+            }
+            Ok(())
+        };
+
+    let run = || -> GenericResult {
+        if thread_count <= 1 {
+            render_range(&instance.effect, frame_min, frame_limit)?;
+        } else {
+            // Either share the configured instance between all threads,
+            // or give each thread its own copy
+            let effects = if options.instance_per_thread {
+                let mut effects = Vec::new();
+                for _ in 0..thread_count {
+                    match clone_instance(&instance.effect, plugin) {
+                        Ok(effect) => effects.push(effect),
+                        Err(e) => {
+                            let _ = destroy_effects(&effects, plugin);
+                            return Err(e.context("Creating per-thread instances"));
+                        }
+                    }
+                }
+                effects
+            } else {
+                vec![instance.effect.clone(); thread_count as usize]
+            };
+
+            let result = thread::scope(|s| -> GenericResult {
+                let threads = effects
+                    .iter()
+                    .enumerate()
+                    .map(|(i, effect)| {
+                        let min = frame_min + i as u32 * chunk_size;
+                        let limit = (min + chunk_size).min(frame_limit);
+                        s.spawn(move || render_range(effect, min, limit))
+                    })
+                    .collect::<Vec<_>>();
+
+                for t in threads {
+                    // Unwrapping the join result gives us the Result returned by
+                    // the closure. Propagate any error it contains.
+                    t.join().unwrap()?;
+                }
+                Ok(())
+            });
+
+            // Destroy the copies even if the render failed. A render
+            // error is returned in preference to a destroy error.
+            let destroyed = if options.instance_per_thread {
+                destroy_effects(&effects, plugin)
+                    .context("Destroying per-thread instances")
+            } else {
+                Ok(())
+            };
+            result.and(destroyed)?;
         }
         Ok(())
     };
+    let result = run();
 
-    if thread_count <= 1 {
-        render_range(frame_min, frame_limit)?;
+    // Clean up whether or not the render succeeded, so a failed or
+    // aborted render doesn't affect later commands
+    instance.effect.lock().abort = None;
+    if result.is_ok() {
+        Clip::check_for_unreleased_images();
     } else {
-        let chunk_size =
-            ((frame_limit - frame_min) as f32 / thread_count as f32).ceil() as u32;
-
-        thread::scope(|s| -> GenericResult {
-            let threads = (0..thread_count)
-                .map(|i| {
-                    let min = i * chunk_size;
-                    let limit = (min + chunk_size).min(frame_limit);
-                    s.spawn(move || render_range(min, limit))
-                })
-                .collect::<Vec<_>>();
-
-            for t in threads {
-                // Unwrapping the join result gives us the Result returned by
-                // the closure. Propagate any error it contains.
-                t.join().unwrap()?;
-            }
-            Ok(())
-        })?;
+        Clip::discard_unreleased_images();
     }
-
-    // Check after all renders are finished
-    Clip::check_for_unreleased_images();
-
-    Ok(())
+    result
 }
 
-// Call GetRegionsOfInterest action, return the RoI for the Source clip
-fn get_rois(
+/// Render an instance several times, optionally with different
+/// thread counts, and fail if any output differs from the first
+/// render. If they all match, the output of the first render is
+/// written.
+fn render_deterministic<W: ImageWriter + Sync>(
     instance_name: &str,
-    project_extent: (f64, f64),
-    region_of_interest: &OfxRectD,
-    state: &mut CommandState,
+    inputs: &HashMap<String, Input>,
+    writer: &W,
+    layout: Option<&RenderLayout>,
+    options: &RenderOptions,
+    check: &DeterministicCheck,
+    state: &CommandState,
+) -> GenericResult {
+    if options.abort.is_some() {
+        bail!("A deterministic check can't be combined with abort");
+    }
+    let thread_counts = if check.thread_counts.is_empty() {
+        vec![options.thread_count; check.renders as usize]
+    } else {
+        check.thread_counts.clone()
+    };
+    if thread_counts.len() < 2 {
+        bail!("A deterministic check needs at least two renders");
+    }
+
+    let mut renders = Vec::new();
+    for (i, &thread_count) in thread_counts.iter().enumerate() {
+        let render_options = RenderOptions {
+            thread_count,
+            deterministic_check: None,
+            ..options.clone()
+        };
+        let images = MemoryWriter::default();
+        render(
+            instance_name,
+            inputs,
+            &images,
+            layout,
+            &render_options,
+            state,
+        )
+        .with_context(|| format!("Render {} (thread_count {})", i + 1, thread_count))?;
+        renders.push(images.images.into_inner().unwrap());
+    }
+
+    let mut first = renders.remove(0);
+    let mut frames: Vec<_> = first.keys().copied().collect();
+    frames.sort_by_key(|f| f.0);
+    let mut differences = Vec::new();
+    for (i, images) in renders.iter().enumerate() {
+        // Frames from either render, so extra frames are caught too
+        let all_frames: BTreeSet<u32> =
+            first.keys().chain(images.keys()).map(|f| f.0).collect();
+        for frame in all_frames.into_iter().map(FrameNumber) {
+            let summary = match (images.get(&frame), first.get(&frame)) {
+                (Some(image), Some(reference)) => {
+                    compare::describe_differences(image, reference, check.tolerance)
+                }
+                (None, _) => Some("missing".to_string()),
+                (Some(_), None) => Some("not in the first render".to_string()),
+            };
+            if let Some(summary) = summary {
+                differences.push(format!(
+                    "Frame {} of render {} (thread_count {}): {}",
+                    frame.0,
+                    i + 2,
+                    thread_counts[i + 1],
+                    summary
+                ));
+            }
+        }
+    }
+    if !differences.is_empty() {
+        return Err(ComparisonFailed(format!(
+            "Renders differ from the first render: {}",
+            differences.join("; ")
+        ))
+        .into());
+    }
+    output!("{} renders of {} match", thread_counts.len(), instance_name);
+
+    for frame in frames {
+        writer.write_image(frame.0, first.remove(&frame).unwrap())?;
+    }
+    Ok(())
+}
+
+// Call GetRegionsOfInterest action, return the RoI for the Source clip
+fn get_rois(
+    instance_name: &str,
+    project_extent: (f64, f64),
+    region_of_interest: &OfxRectD,
+    state: &mut CommandState,
 ) -> Result<HashMap<String, OfxRectD>> {
     let instance = state.get_instance(instance_name)?;
     let plugin = state.get_plugin(&instance.plugin_name)?;
@@ -2373,6 +3591,256 @@ fn set_params(
     Ok(())
 }
 
+/// Param values saved by SaveParams, keyed by param name. Params
+/// without a value (groups, pages, etc.) are omitted. Params aren't
+/// animated by this host, so each has a single value.
+#[derive(Deserialize, Serialize)]
+struct ParamPreset {
+    plugin: String,
+    params: BTreeMap<String, ParamValue>,
+}
+
+/// Values of all params which can be saved
+fn saved_param_values(effect: &ImageEffect) -> BTreeMap<String, ParamValue> {
+    effect
+        .param_set
+        .lock()
+        .params
+        .iter()
+        .map(|(name, param)| (name.clone(), param.lock().value.clone()))
+        .filter(|(_, value)| value.has_value())
+        .collect()
+}
+
+/// Check saved param values against the params of an effect. Returns
+/// the values which can be applied, and a description of each one
+/// which can't.
+fn check_param_values(
+    effect: &Object<ImageEffect>,
+    params: BTreeMap<String, ParamValue>,
+) -> (Vec<(String, ParamValue)>, Vec<String>) {
+    let mut values = Vec::new();
+    let mut errors = Vec::new();
+    for (name, value) in params {
+        let param = effect.lock().get_param(&name);
+        match param {
+            None => errors.push(format!("{}: no such param", name)),
+            Some(param) => {
+                let expected = param.lock().value.type_name();
+                if value.type_name() == expected {
+                    values.push((name, value));
+                } else {
+                    errors.push(format!(
+                        "{}: saved value is {}, param is {}",
+                        name,
+                        value.type_name(),
+                        expected
+                    ));
+                }
+            }
+        }
+    }
+    (values, errors)
+}
+
+/// Report every param which couldn't be loaded, and fail if there
+/// were any
+fn report_param_errors(
+    command: &str,
+    errors: &[String],
+    applied: usize,
+    filename: &str,
+) -> GenericResult {
+    for error in errors {
+        log_error!("{}: {}", command, error);
+    }
+    if !errors.is_empty() {
+        bail!(
+            "{} of {} params in {} could not be loaded",
+            errors.len(),
+            errors.len() + applied,
+            filename
+        );
+    }
+    Ok(())
+}
+
+fn save_params(
+    instance_name: &str,
+    filename: &str,
+    state: &CommandState,
+) -> GenericResult {
+    let instance = state.get_instance(instance_name)?;
+    let preset = ParamPreset {
+        plugin: instance.plugin_name.clone(),
+        params: saved_param_values(&instance.effect.lock()),
+    };
+    fs::write(filename, serde_json::to_string_pretty(&preset)?)
+        .with_context(|| format!("Writing {}", filename))?;
+    Ok(())
+}
+
+fn load_params(
+    instance_name: &str,
+    filename: &str,
+    call_instance_changed: bool,
+    state: &mut CommandState,
+) -> GenericResult {
+    let preset: ParamPreset = serde_json::from_str(
+        &fs::read_to_string(filename).with_context(|| format!("Reading {}", filename))?,
+    )
+    .with_context(|| format!("Parsing {}", filename))?;
+
+    let instance = state.get_instance(instance_name)?;
+    if preset.plugin != instance.plugin_name {
+        log_error!(
+            "Warning: preset {} was saved from {}, loading into {}",
+            filename,
+            preset.plugin,
+            instance.plugin_name
+        );
+    }
+
+    // Check every param before applying any, and report all the
+    // problems rather than stopping at the first one
+    let (values, errors) = check_param_values(&instance.effect, preset.params);
+    set_params(instance_name, &values, call_instance_changed, state)?;
+    report_param_errors("LoadParams", &errors, values.len(), filename)
+}
+
+/// Instance state saved by SaveProject
+#[derive(Deserialize, Serialize)]
+struct Project {
+    instance_name: String,
+    plugin: String,
+    context: ImageEffectContext,
+    params: BTreeMap<String, ParamValue>,
+}
+
+/// Save an instance as a host does when saving a project: give the
+/// plugin a chance to flush private data into its params, then save
+/// all param values.
+fn save_project(
+    instance_name: &str,
+    filename: &str,
+    state: &CommandState,
+) -> GenericResult {
+    let instance = state.get_instance(instance_name)?;
+    let plugin = state.get_plugin(&instance.plugin_name)?;
+    plugin.plugin.try_call_action(
+        constants::ActionSyncPrivateData,
+        instance.effect.clone().into(),
+        PropertySetHandle::from(std::ptr::null_mut()),
+        PropertySetHandle::from(std::ptr::null_mut()),
+    )?;
+
+    let project = Project {
+        instance_name: instance_name.to_string(),
+        plugin: instance.plugin_name.clone(),
+        context: instance.context,
+        params: saved_param_values(&instance.effect.lock()),
+    };
+    fs::write(filename, serde_json::to_string_pretty(&project)?)
+        .with_context(|| format!("Writing {}", filename))?;
+    Ok(())
+}
+
+/// Load an instance saved by SaveProject. Any existing instance with
+/// the same name is destroyed and replaced by a fresh one, keeping
+/// its clip connections. The new instance is created first, so if
+/// that fails the existing one is left in place.
+fn load_project(
+    filename: &str,
+    instance_name: Option<&str>,
+    state: &mut CommandState,
+) -> GenericResult {
+    let project: Project = serde_json::from_str(
+        &fs::read_to_string(filename).with_context(|| format!("Reading {}", filename))?,
+    )
+    .with_context(|| format!("Parsing {}", filename))?;
+    let instance_name = instance_name.unwrap_or(&project.instance_name);
+
+    let count = project.params.len();
+    let (effect, errors) = create_effect_with_params(
+        &project.plugin,
+        instance_name,
+        project.context,
+        project.params,
+        state,
+    )?;
+
+    let connections = if state.instances.contains_key(instance_name) {
+        let connections = state.get_instance(instance_name)?.connections.clone();
+        if let Err(e) = destroy_instance(instance_name, state) {
+            // Keep the existing instance, and discard the new one
+            let _ = destroy_effect(&effect, state.get_plugin(&project.plugin)?);
+            return Err(e);
+        }
+        connections
+    } else {
+        HashMap::new()
+    };
+    state.instances.insert(
+        instance_name.to_string(),
+        Instance {
+            plugin_name: project.plugin,
+            context: project.context,
+            effect,
+            connections,
+        },
+    );
+    report_param_errors("LoadProject", &errors, count - errors.len(), filename)
+}
+
+/// Render an instance, save it as a project, reload it and render
+/// again. Fails if any output differs.
+fn check_project_round_trip(
+    instance_name: &str,
+    filename: &str,
+    inputs: &HashMap<String, Input>,
+    layout: Option<&RenderLayout>,
+    options: &RenderOptions,
+    state: &mut CommandState,
+) -> GenericResult {
+    let before = MemoryWriter::default();
+    render(instance_name, inputs, &before, layout, options, state)
+        .context("Rendering before save")?;
+    save_project(instance_name, filename, state)?;
+    load_project(filename, Some(instance_name), state)?;
+    let after = MemoryWriter::default();
+    render(instance_name, inputs, &after, layout, options, state)
+        .context("Rendering after reload")?;
+
+    let before = before.images.into_inner().unwrap();
+    let mut after = after.images.into_inner().unwrap();
+    let mut frames: Vec<_> = before.keys().copied().collect();
+    frames.sort_by_key(|f| f.0);
+    for frame in frames {
+        let image = after
+            .remove(&frame)
+            .with_context(|| format!("Frame {} missing after reload", frame.0))?;
+        match compare::max_difference(&before[&frame], &image) {
+            Some(0.0) => {}
+            Some(difference) => {
+                return Err(ComparisonFailed(format!(
+                    "Frame {} differs after reload (max difference {})",
+                    frame.0, difference
+                ))
+                .into());
+            }
+            None => {
+                return Err(ComparisonFailed(format!(
+                    "Frame {} has different size or components after reload",
+                    frame.0
+                ))
+                .into());
+            }
+        }
+    }
+    output!("Project round trip of {} matches", instance_name);
+    Ok(())
+}
+
 fn describe(
     bundle_name: &str,
     plugin_name: &str,
@@ -2489,16 +3957,34 @@ fn print_params(instance_name: &str, state: &mut CommandState) -> GenericResult
 fn destroy_instance(instance_name: &str, state: &mut CommandState) -> GenericResult {
     let instance = state.get_instance(instance_name)?;
     let plugin = state.get_plugin(&instance.plugin_name)?;
+    destroy_effect(&instance.effect, plugin)?;
+    state.instances.remove(instance_name);
+    Ok(())
+}
+
+/// Call the DestroyInstance action on an instance
+fn destroy_effect(effect: &Object<ImageEffect>, plugin: &LoadedPlugin) -> GenericResult {
     plugin.plugin.try_call_action(
         constants::ActionDestroyInstance,
-        instance.effect.clone().into(),
+        effect.clone().into(),
         PropertySetHandle::from(std::ptr::null_mut()),
         PropertySetHandle::from(std::ptr::null_mut()),
     )?;
-    state.instances.remove(instance_name);
     Ok(())
 }
 
+/// Destroy several instances. Tries them all, and returns the first
+/// error.
+fn destroy_effects(
+    effects: &[Object<ImageEffect>],
+    plugin: &LoadedPlugin,
+) -> GenericResult {
+    effects
+        .iter()
+        .map(|effect| destroy_effect(effect, plugin))
+        .fold(Ok(()), |first, result| first.and(result))
+}
+
 fn unload_plugin(plugin_name: &str, state: &mut CommandState) -> GenericResult {
     let plugin = state.get_plugin(plugin_name)?;
     plugin.plugin.try_call_action(
@@ -2527,23 +4013,20 @@ fn process_command(command: &Command, state: &mut CommandState) -> GenericResult
         Render {
             instance_name,
             inputs,
-            output_directory,
+            output,
             layout,
-            frame_range,
-            thread_count,
+            options,
         } => {
-            if let Some(dir) = output_directory {
-                std::fs::create_dir_all(dir)?;
-            }
-            let (_, FrameNumber(frame_limit)) = *frame_range;
-            let writer = ExrWriter::new(output_directory.as_ref(), frame_limit);
+            let (_, FrameNumber(frame_limit)) = options.frame_range;
+            let plugin_name = &state.get_instance(instance_name)?.plugin_name;
+            let writer =
+                FileWriter::new(output, instance_name, plugin_name, frame_limit)?;
             render(
                 instance_name,
                 inputs,
                 &writer,
                 layout.as_ref(),
-                *frame_range,
-                *thread_count,
+                options,
                 state,
             )
             .context("Render")
@@ -2611,6 +4094,82 @@ fn process_command(command: &Command, state: &mut CommandState) -> GenericResult
             set_host_properties(props, state);
             Ok(())
         }
+        ConnectClip {
+            source_instance,
+            instance_name,
+            clip_name,
+        } => connect_clip(source_instance, instance_name, clip_name, state)
+            .context("ConnectClip"),
+        CompareImages {
+            image,
+            reference,
+            metric,
+            tolerance,
+            threshold,
+            region,
+            diff_file,
+        } => compare::compare_images(
+            image,
+            reference,
+            *metric,
+            *tolerance,
+            *threshold,
+            *region,
+            diff_file.as_deref(),
+        )
+        .context("CompareImages"),
+        SaveParams {
+            instance_name,
+            filename,
+        } => save_params(instance_name, filename, state).context("SaveParams"),
+        LoadParams {
+            instance_name,
+            filename,
+            call_instance_changed,
+        } => load_params(instance_name, filename, *call_instance_changed, state)
+            .context("LoadParams"),
+        SaveProject {
+            instance_name,
+            filename,
+        } => save_project(instance_name, filename, state).context("SaveProject"),
+        LoadProject {
+            filename,
+            instance_name,
+        } => {
+            load_project(filename, instance_name.as_deref(), state).context("LoadProject")
+        }
+        CheckProjectRoundTrip {
+            instance_name,
+            filename,
+            inputs,
+            layout,
+            options,
+        } => check_project_round_trip(
+            instance_name,
+            filename,
+            inputs,
+            layout.as_ref(),
+            options,
+            state,
+        )
+        .context("CheckProjectRoundTrip"),
+        SetActionTimeout {
+            timeout_ms,
+            actions,
+        } => {
+            timeout::set_timeouts(
+                timeout_ms.map(Duration::from_millis),
+                actions
+                    .iter()
+                    .map(|(action, ms)| (action.clone(), Duration::from_millis(*ms)))
+                    .collect(),
+            );
+            Ok(())
+        }
+        Group { name, commands } => commands
+            .iter()
+            .try_for_each(|command| process_command(command, state))
+            .with_context(|| format!("Group {}", name)),
     }
 }
 
@@ -2620,6 +4179,199 @@ fn read_commands(path: &str) -> Result<Vec<Command>> {
         .and_then(|s| serde_json::from_str(&s).with_context(|| "Error parsing JSON"))
 }
 
+/// Run a function, returning any output it produces rather than
+/// writing it
+fn capture_output<T>(f: impl FnOnce() -> T) -> (T, Vec<String>) {
+    *OUTPUT_CAPTURE.lock().unwrap() = Some(Vec::new());
+    let result = f();
+    let lines = OUTPUT_CAPTURE.lock().unwrap().take().unwrap_or_default();
+    (result, lines)
+}
+
+// Ordered from best to worst, so the overall status of a run is the
+// maximum of its commands
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+enum CommandStatus {
+    Ok,
+    // A comparison ran but found a difference
+    Failed,
+    Error,
+    // An action timed out, and the run was aborted
+    Timeout,
+}
+
+impl CommandStatus {
+    fn of(result: &GenericResult) -> Self {
+        match result {
+            Ok(()) => CommandStatus::Ok,
+            Err(e) if e.downcast_ref::<ComparisonFailed>().is_some() => {
+                CommandStatus::Failed
+            }
+            Err(_) => CommandStatus::Error,
+        }
+    }
+}
+
+/// Result of a single command, as written to the results stream and
+/// by Serve
+#[derive(Deserialize, Serialize, Debug)]
+struct CommandResult {
+    // Command file, when running a directory of tests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    index: usize,
+    // None if the command couldn't be parsed
+    #[serde(rename = "type")]
+    command_type: Option<String>,
+    // Name of a Group command
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    status: CommandStatus,
+    duration_ms: f64,
+    // Output lines, parsed as JSON where possible
+    output: Vec<serde_json::Value>,
+    // Error messages, outermost context first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    error: Vec<String>,
+}
+
+impl CommandResult {
+    fn new(
+        index: usize,
+        command: Option<&Command>,
+        result: &GenericResult,
+        duration: Duration,
+        output: Vec<String>,
+    ) -> Self {
+        Self {
+            file: None,
+            index,
+            command_type: command.map(|c| c.type_name().to_string()),
+            name: command.and_then(Command::name).map(str::to_string),
+            status: CommandStatus::of(result),
+            duration_ms: duration.as_secs_f64() * 1000.0,
+            output: output
+                .into_iter()
+                .map(|line| {
+                    serde_json::from_str(&line).unwrap_or(serde_json::Value::String(line))
+                })
+                .collect(),
+            error: match result {
+                Ok(()) => Vec::new(),
+                Err(e) => e.chain().map(|e| e.to_string()).collect(),
+            },
+        }
+    }
+
+    /// Name used for the command in test reports
+    fn test_name(&self) -> String {
+        match (&self.name, &self.command_type) {
+            (Some(name), _) => name.clone(),
+            (None, Some(command_type)) => format!("{}: {}", self.index, command_type),
+            (None, None) => self.index.to_string(),
+        }
+    }
+}
+
+/// Worst status of a list of commands
+fn run_status(results: &[CommandResult]) -> CommandStatus {
+    results
+        .iter()
+        .map(|r| r.status)
+        .max()
+        .unwrap_or(CommandStatus::Ok)
+}
+
+/// Run commands in order, returning a result record for each command
+/// which was run. Unless continue_on_error is set, stops at the first
+/// command which fails. If capture is set, command output is put in
+/// the records instead of being written. Each record is also written
+/// to the results stream, if given, as soon as the command finishes.
+fn run_commands(
+    commands: &[Command],
+    capture: bool,
+    continue_on_error: bool,
+    mut results: Option<&mut dyn Write>,
+    state: &mut CommandState,
+) -> Result<Vec<CommandResult>> {
+    let mut records = Vec::new();
+    for (index, command) in commands.iter().enumerate() {
+        let start = Instant::now();
+        let (result, output) = if capture {
+            capture_output(|| process_command(command, state))
+        } else {
+            (process_command(command, state), Vec::new())
+        };
+        let duration = start.elapsed();
+
+        if let Err(e) = &result {
+            log_error!("Error running command: {:?}", e);
+        }
+        let record = CommandResult::new(index, Some(command), &result, duration, output);
+        if let Some(results) = results.as_mut() {
+            writeln!(results, "{}", serde_json::to_string(&record)?)?;
+            results.flush()?;
+        }
+
+        let status = record.status;
+        records.push(record);
+        if status != CommandStatus::Ok && !continue_on_error {
+            break;
+        }
+    }
+    Ok(records)
+}
+
+/// Run a function, turning a panic into an error so that a long-lived
+/// process can report it and carry on. Panics in suite functions
+/// can't unwind through the plugin, so still abort.
+fn catch_panic(f: impl FnOnce() -> GenericResult) -> GenericResult {
+    panic::catch_unwind(panic::AssertUnwindSafe(f)).unwrap_or_else(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown cause".to_string());
+        bail!("Panicked: {}", message)
+    })
+}
+
+/// Read commands one per line, running each against the same state
+/// and writing a JSON result line for each. Blank lines are ignored.
+/// Returns when the input is closed.
+fn serve(
+    input: impl BufRead,
+    mut responses: impl Write,
+    state: &mut CommandState,
+) -> GenericResult {
+    let lines = input
+        .lines()
+        .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()));
+    for (index, line) in lines.enumerate() {
+        let line = line.context("Reading command")?;
+        let command = serde_json::from_str::<Command>(&line);
+
+        let start = Instant::now();
+        let (result, output) = capture_output(|| {
+            catch_panic(|| match &command {
+                Ok(command) => process_command(command, state),
+                Err(e) => Err(anyhow!("{}", e)).context("Error parsing JSON"),
+            })
+        });
+        let response = CommandResult::new(
+            index,
+            command.as_ref().ok(),
+            &result,
+            start.elapsed(),
+            output,
+        );
+        writeln!(responses, "{}", serde_json::to_string(&response)?)?;
+        responses.flush()?;
+    }
+    Ok(())
+}
+
 #[derive(Parser)]
 struct Cli {
     #[command(subcommand)]
@@ -2633,6 +4385,36 @@ struct Cli {
     #[arg(short, long)]
     /// Enable verbose output
     verbose: bool,
+    #[arg(long, value_name = "FILE")]
+    /// Write a JSON result record for each command here ("-" for
+    /// stdout). Command output is included in the records.
+    results: Option<String>,
+    #[arg(long)]
+    /// Keep running commands after one fails
+    continue_on_error: bool,
+    #[arg(long, value_name = "FILE")]
+    /// Write a JUnit XML report here
+    junit: Option<String>,
+    #[arg(long, value_name = "MS")]
+    /// Abort the run if any plugin action takes longer than this
+    action_timeout_ms: Option<u64>,
+    #[arg(long)]
+    /// Return kOfxStatErrUnsupported from suite functions which aren't
+    /// implemented, instead of aborting, and report the calls at the
+    /// end of the run
+    allow_unsupported: bool,
+    #[arg(long)]
+    /// Return kOfxStatErrBadHandle from suite functions which are
+    /// passed a bad handle, instead of aborting. The run fails at the
+    /// end with a list of the bad handles.
+    recover_bad_handles: bool,
+    #[arg(long, value_name = "FILE")]
+    /// Write a JSON line for every suite call made by plugins here
+    trace: Option<String>,
+    #[arg(long)]
+    /// Record property lookups which fail, and print a summary at the
+    /// end of the run
+    audit_properties: bool,
 }
 
 #[derive(Subcommand)]
@@ -2652,6 +4434,33 @@ enum CliCommands {
     },
     /// Run commands from a JSON file
     Run { command_file: String },
+    /// Run every command file in a directory (or a single file), each
+    /// in its own process, and summarise the results
+    Test {
+        directory: String,
+        #[arg(short, long)]
+        /// Number of files to run at once (default: number of CPUs)
+        jobs: Option<usize>,
+    },
+    /// Run commands read from stdin, one JSON object per line,
+    /// writing a JSON response line for each to stdout
+    Serve,
+    /// Compare an image with a reference
+    Compare {
+        image: String,
+        reference: String,
+        #[arg(long, value_enum, default_value_t)]
+        metric: CompareMetric,
+        #[arg(long, default_value_t = 0.0)]
+        /// Maximum error (minimum for PSNR)
+        tolerance: f64,
+        #[arg(long, default_value_t = 0.0)]
+        /// Per-pixel threshold for pixels-over-threshold
+        threshold: f32,
+        #[arg(long, value_name = "FILE")]
+        /// Write the difference image here on failure
+        diff: Option<String>,
+    },
 }
 
 fn main() {
@@ -2660,6 +4469,25 @@ fn main() {
     let args = Cli::parse();
 
     VERBOSE.get_or_init(|| args.verbose);
+    crash::install_handler();
+    unsupported::set_allowed(args.allow_unsupported);
+    handles::set_recover_bad_handles(args.recover_bad_handles);
+    property_audit::set_enabled(args.audit_properties);
+    if let Some(ref filename) = args.trace {
+        if let CliCommands::Test { .. } = args.command {
+            // Every test process would write to the same file
+            eprintln!("--trace can't be used with test. Trace a command file with run.");
+            std::process::exit(64);
+        }
+        trace::open(filename).unwrap_or_else(|e| {
+            eprintln!("{:?}", e);
+            std::process::exit(-1);
+        });
+    }
+    timeout::set_timeouts(
+        args.action_timeout_ms.map(Duration::from_millis),
+        HashMap::new(),
+    );
 
     // Maybe open a file for errors
     if let Some(ref filename) = args.errors {
@@ -2685,6 +4513,22 @@ fn main() {
         }
     }
 
+    // Maybe open a stream for results
+    let mut results: Option<Box<dyn Write>> = match args.results.as_deref() {
+        None => None,
+        Some("-") => Some(Box::new(io::stdout())),
+        Some(filename) => Some(Box::new(File::create(filename).unwrap_or_else(|err| {
+            eprintln!("Failed to open \"{}\" for writing: {}", filename, err);
+            std::process::exit(-1);
+        }))),
+    };
+
+    // Name of the test suite in reports
+    let suite_name = match &args.command {
+        CliCommands::Run { command_file } => command_file.clone(),
+        _ => env!("CARGO_PKG_NAME").to_string(),
+    };
+
     let commands = match args.command {
         // Run ListPlugins on the given bundle
         CliCommands::List { bundle_name } => vec![Command::ListPlugins {
@@ -2706,6 +4550,79 @@ fn main() {
             plugin_name: plugin_name.clone(),
             context,
         }],
+        CliCommands::Compare {
+            image,
+            reference,
+            metric,
+            tolerance,
+            threshold,
+            diff,
+        } => vec![Command::CompareImages {
+            image,
+            reference,
+            metric,
+            tolerance,
+            threshold,
+            region: None,
+            diff_file: diff,
+        }],
+        CliCommands::Test { directory, jobs } => {
+            let exe = env::current_exe().unwrap_or_else(|e| {
+                log_error!("Finding the runner executable: {:?}", e);
+                std::process::exit(-1);
+            });
+            let jobs = jobs.unwrap_or_else(|| {
+                thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1)
+            });
+            // Options passed on to each test process
+            let mut test_args = Vec::new();
+            if args.verbose {
+                test_args.push("--verbose".to_string());
+            }
+            if args.continue_on_error {
+                test_args.push("--continue-on-error".to_string());
+            }
+            if let Some(ms) = args.action_timeout_ms {
+                test_args.extend(["--action-timeout-ms".to_string(), ms.to_string()]);
+            }
+            if args.allow_unsupported {
+                test_args.push("--allow-unsupported".to_string());
+            }
+            if args.recover_bad_handles {
+                test_args.push("--recover-bad-handles".to_string());
+            }
+            if args.audit_properties {
+                test_args.push("--audit-properties".to_string());
+            }
+            let files = test_runner::run_tests(
+                &exe,
+                std::path::Path::new(&directory),
+                jobs,
+                &test_args,
+            )
+            .unwrap_or_else(|e| {
+                log_error!("{:?}", e);
+                std::process::exit(64);
+            });
+            if let Some(results) = results.as_mut() {
+                for record in files.iter().flat_map(|f| &f.results) {
+                    writeln!(results, "{}", serde_json::to_string(record).unwrap())
+                        .unwrap();
+                }
+            }
+            finish(&files, args.junit.as_deref());
+        }
+        CliCommands::Serve => {
+            if let Err(e) = serve(io::stdin().lock(), io::stdout().lock(), &mut state) {
+                log_error!("Error serving commands: {:?}", e);
+                std::process::exit(-1);
+            }
+            // Each command's result has already been returned, so only
+            // the reports and bad handles matter here
+            finish(&[], None);
+        }
         // Otherwise read commands from file
         CliCommands::Run { ref command_file } => read_commands(command_file)
             .unwrap_or_else(|e| {
@@ -2714,15 +4631,74 @@ fn main() {
             }),
     };
 
-    for ref c in commands {
-        if let Err(e) = process_command(c, &mut state) {
-            log_error!("Error running command: {:?}", e);
-            std::process::exit(-1);
+    let records = run_commands(
+        &commands,
+        results.is_some() || args.junit.is_some(),
+        args.continue_on_error,
+        results.as_mut().map(|r| r as &mut dyn Write),
+        &mut state,
+    )
+    .unwrap_or_else(|e| {
+        log_error!("Error writing results: {:?}", e);
+        std::process::exit(-1);
+    });
+
+    finish(
+        &[test_runner::TestFile {
+            name: suite_name,
+            results: records,
+        }],
+        args.junit.as_deref(),
+    );
+}
+
+/// Report any unsupported suite calls, failed property lookups and
+/// bad handles, and write a JUnit report, if requested, then exit with
+/// the status of the worst command. Bad handles fail the run even if
+/// every command succeeded.
+fn finish(files: &[test_runner::TestFile], junit: Option<&str>) -> ! {
+    unsupported::report();
+    property_audit::report();
+    let bad_handles = handles::bad_handles();
+    if !bad_handles.is_empty() {
+        log_error!("Bad handles passed to suite functions:");
+        for bad in &bad_handles {
+            log_error!("    {}", bad);
         }
     }
-}
+    if let Some(filename) = junit {
+        let suites: Vec<_> = files
+            .iter()
+            .map(|f| junit::TestSuite {
+                name: f.name.clone(),
+                results: &f.results,
+            })
+            .collect();
+        junit::write_report(filename, &suites).unwrap_or_else(|e| {
+            log_error!("Error writing JUnit report: {:?}", e);
+            std::process::exit(-1);
+        });
+    }
 
-#[cfg(test)]
+    let status = files
+        .iter()
+        .map(|f| run_status(&f.results))
+        .max()
+        .unwrap_or(CommandStatus::Ok);
+    let status = if bad_handles.is_empty() {
+        status
+    } else {
+        status.max(CommandStatus::Error)
+    };
+    std::process::exit(match status {
+        CommandStatus::Ok => 0,
+        CommandStatus::Failed => EXIT_COMPARISON_FAILED,
+        CommandStatus::Error => -1,
+        CommandStatus::Timeout => timeout::EXIT_TIMEOUT,
+    })
+}
+
+#[cfg(test)]
 mod test {
     // Integration tests which use the process_command interface crash
     // when run concurrently. This doesn't happen in the real
@@ -3070,61 +5046,693 @@ mod test {
     }
 
     #[test]
-    fn render_basic() {
+    fn serve_commands() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        let mut state = set_up_basic_plugin();
+        let input = concat!(
+            r#"{"type": "PrintParams", "instance_name": "instance1"}"#,
+            "\n\n",
+            "not json\n",
+            r#"{"type": "PrintParams", "instance_name": "missing"}"#,
+            "\n",
+        );
+        let mut responses: Vec<u8> = Vec::new();
+        serve(input.as_bytes(), &mut responses, &mut state).unwrap();
+
+        // One response per command, skipping the blank line
+        let responses: Vec<serde_json::Value> = String::from_utf8(responses)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(responses.len(), 3);
+
+        assert_eq!(responses[0]["status"], "ok");
+        assert_eq!(responses[0]["type"], "PrintParams");
+        assert!(responses[0]["error"].is_null());
+        assert!(responses[0]["output"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|v| v["params"]["scale"].is_object()));
+
+        assert_eq!(responses[1]["status"], "error");
+        assert!(responses[1]["type"].is_null());
+        assert_eq!(responses[1]["error"][0], "Error parsing JSON");
+
+        // The state persists after an error
+        assert_eq!(responses[2]["index"], 2);
+        assert_eq!(responses[2]["status"], "error");
+        assert_eq!(
+            responses[2]["error"],
+            serde_json::json!(["PrintParams", "No instance named missing"])
+        );
+        assert!(state.get_instance("instance1").is_ok());
+    }
+
+    #[test]
+    fn catch_panics() {
+        catch_panic(|| Ok(())).unwrap();
+        let err = catch_panic(|| panic!("Not implemented")).unwrap_err();
+        assert_eq!(err.to_string(), "Panicked: Not implemented");
+        let err =
+            catch_panic(|| panic!("{} is not implemented", "clipGetHandle")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Panicked: clipGetHandle is not implemented"
+        );
+    }
+
+    #[test]
+    fn command_results() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        let commands: Vec<Command> = serde_json::from_str(
+            r#"[{"type": "PrintParams", "instance_name": "missing"},
+                {"type": "CompareImages",
+                 "image": "test/colorbars.exr",
+                 "reference": "test/circle.exr"},
+                {"type": "PrintParams", "instance_name": "instance1"}]"#,
+        )
+        .unwrap();
+        let run = |continue_on_error| {
+            let mut state = set_up_basic_plugin();
+            let mut results: Vec<u8> = Vec::new();
+            let status = run_status(
+                &run_commands(
+                    &commands,
+                    true,
+                    continue_on_error,
+                    Some(&mut results as &mut dyn Write),
+                    &mut state,
+                )
+                .unwrap(),
+            );
+            let records: Vec<serde_json::Value> = String::from_utf8(results)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            (status, records)
+        };
+
+        // Stop at the first error
+        let (status, records) = run(false);
+        assert_eq!(status, CommandStatus::Error);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["index"], 0);
+        assert_eq!(records[0]["type"], "PrintParams");
+        assert_eq!(records[0]["status"], "error");
+        assert!(records[0]["duration_ms"].is_f64());
+
+        // Report every command
+        let (status, records) = run(true);
+        assert_eq!(status, CommandStatus::Error);
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[1]["type"], "CompareImages");
+        assert_eq!(records[1]["status"], "failed");
+        assert_eq!(records[2]["status"], "ok");
+        assert!(records[2]["error"].is_null());
+        assert!(records[2]["output"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|v| v["params"]["scale"].is_object()));
+    }
+
+    #[test]
+    fn junit_report() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        let commands: Vec<Command> = serde_json::from_str(
+            r#"[{"type": "Group", "name": "set <scale>", "commands": [
+                   {"type": "SetParams", "instance_name": "instance1",
+                    "values": [["scale", {"type": "Double", "v": 2.0}]],
+                    "call_instance_changed": false},
+                   {"type": "PrintParams", "instance_name": "instance1"}]},
+                {"type": "Group", "name": "missing", "commands": [
+                   {"type": "PrintParams", "instance_name": "instance1"},
+                   {"type": "PrintParams", "instance_name": "missing"}]},
+                {"type": "CompareImages",
+                 "image": "test/colorbars.exr",
+                 "reference": "test/circle.exr"}]"#,
+        )
+        .unwrap();
+        let mut state = set_up_basic_plugin();
+        let records = run_commands(&commands, true, true, None, &mut state).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].name.as_deref(), Some("set <scale>"));
+        assert_eq!(records[1].status, CommandStatus::Error);
+        assert_eq!(
+            records[1].error,
+            ["Group missing", "PrintParams", "No instance named missing"]
+        );
+
+        let xml = junit::report(&[junit::TestSuite {
+            name: "commands.json".to_string(),
+            results: &records,
+        }]);
+        assert!(xml.contains(
+            r#"<testsuite name="commands.json" tests="3" failures="1" errors="1""#
+        ));
+        assert!(xml
+            .contains(r#"<testcase name="set &lt;scale&gt;" classname="commands.json""#));
+        assert!(xml.contains(
+            r#"<error message="Group missing: PrintParams: No instance named missing">"#
+        ));
+        assert!(xml.contains(r#"<testcase name="2: CompareImages""#));
+        assert!(xml.contains("<failure message=\"CompareImages: "));
+        // Output of the group is captured
+        assert!(xml.contains("<system-out>{&quot;"));
+    }
+
+    #[test]
+    fn test_runner_files() {
+        let out_dir = path::PathBuf::from(env!("OUT_DIR"));
+        let dir = out_dir.join("test_runner");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        fs::write(dir.join("b.json"), "[]").unwrap();
+        fs::write(dir.join("nested/a.json"), "[]").unwrap();
+        // Not valid JSON, so included and reported when run
+        fs::write(dir.join("broken.json"), "[{").unwrap();
+        // Skipped: not an array, or not JSON
+        fs::write(dir.join("preset.json"), "{}").unwrap();
+        fs::write(dir.join("notes.txt"), "[]").unwrap();
+
+        let names: Vec<_> = test_runner::find_command_files(&dir)
+            .unwrap()
+            .iter()
+            .map(|p| p.strip_prefix(&dir).unwrap().to_str().unwrap().to_string())
+            .collect();
+        assert_eq!(names, ["b.json", "broken.json", "nested/a.json"]);
+    }
+
+    #[test]
+    fn test_runner_results() {
+        let stdout = concat!(
+            r#"{"index": 0, "type": "CreatePlugin", "status": "ok", "duration_ms": 1.0, "output": []}"#,
+            "\nplugin output\n",
+            r#"{"index": 1, "type": "Render", "status": "ok", "duration_ms": 2.0, "output": []}"#,
+            "\n"
+        );
+
+        let results = test_runner::collect_results("a.json", None, stdout, "", Ok(()));
+        assert_eq!(results.len(), 2);
+        assert_eq!(run_status(&results), CommandStatus::Ok);
+        assert_eq!(results[1].file.as_deref(), Some("a.json"));
+
+        // A process which fails without reporting an error gets an
+        // error record
+        let results = test_runner::collect_results(
+            "a.json",
+            None,
+            stdout,
+            "Segmentation fault\n",
+            Err("signal: 11".to_string()),
+        );
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[2].index, 2);
+        assert_eq!(results[2].status, CommandStatus::Error);
+        assert_eq!(
+            results[2].error,
+            ["Test process failed (signal: 11)", "Segmentation fault"]
+        );
+
+        // A crash report identifies the command, action and suite
+        // calls
+        let commands: Vec<Command> = serde_json::from_str(
+            r#"[{"type": "CreatePlugin", "bundle_name": "b", "plugin_name": "p"},
+                {"type": "ListPlugins", "bundle_name": "b"},
+                {"type": "Group", "name": "render", "commands": []}]"#,
+        )
+        .unwrap();
+        // Type names match the "type" field
+        for command in &commands {
+            let value = serde_json::to_value(command).unwrap();
+            assert_eq!(value["type"], command.type_name());
+        }
+        let results = test_runner::collect_results(
+            "a.json",
+            Some(&commands),
+            stdout,
+            concat!(
+                "Error: log output\n",
+                "Crashed with SIGSEGV during OfxImageEffectActionRender\n",
+                "Last suite calls: propGetInt, clipGetImage\n"
+            ),
+            Err("signal: 11 (SIGSEGV)".to_string()),
+        );
+        assert_eq!(results[2].command_type.as_deref(), Some("Group"));
+        assert_eq!(results[2].test_name(), "render");
+        assert_eq!(results[2].status, CommandStatus::Error);
+        assert_eq!(
+            results[2].error,
+            [
+                "Test process failed (signal: 11 (SIGSEGV))",
+                "Crashed with SIGSEGV during OfxImageEffectActionRender",
+                "Last suite calls: propGetInt, clipGetImage"
+            ]
+        );
+
+        let results = test_runner::collect_results(
+            "a.json",
+            Some(&commands),
+            stdout,
+            concat!(
+                "Timed out after 100 ms in OfxActionInstanceChanged on instance instance1 (p)\n",
+                "Last suite calls: paramGetValue\n"
+            ),
+            Err("exit status: 3".to_string()),
+        );
+        assert_eq!(results[2].status, CommandStatus::Timeout);
+        assert_eq!(results[2].error.len(), 3);
+    }
+
+    #[test]
+    fn action_timeout() {
         let _lock = COMMAND_MUTEX.lock().unwrap();
 
-        let input_path = "test/colorbars.exr";
         let mut state = set_up_basic_plugin();
+        process_command(
+            &SetActionTimeout {
+                timeout_ms: Some(60_000),
+                actions: HashMap::from([(
+                    constants::ActionInstanceChanged.to_string(),
+                    30_000,
+                )]),
+            },
+            &mut state,
+        )
+        .unwrap();
 
+        // Actions which finish in time are unaffected
         process_command(
             &SetParams {
                 instance_name: "instance1".to_string(),
                 values: vec![("scale".to_string(), ParamValue::Double(2.0))],
-                call_instance_changed: false,
+                call_instance_changed: true,
             },
             &mut state,
         )
         .unwrap();
+        timeout::set_timeouts(None, HashMap::new());
+    }
 
-        let writer = CaptureWriter {
-            images: Default::default(),
-        };
+    #[test]
+    fn render_timeout() {
+        // A timeout exits the process, so render in a child process
+        // running only this test
+        if env::var_os("OFX_TEST_RENDER_TIMEOUT").is_none() {
+            let output = std::process::Command::new(env::current_exe().unwrap())
+                .args(["--exact", "test::render_timeout", "--nocapture"])
+                .env("OFX_TEST_RENDER_TIMEOUT", "1")
+                .output()
+                .unwrap();
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            assert_eq!(
+                output.status.code(),
+                Some(timeout::EXIT_TIMEOUT),
+                "{stderr}"
+            );
+            assert!(stderr.contains(&format!(
+                "{}1 ms in {}",
+                timeout::TIMEOUT_MARKER,
+                constants::ImageEffectActionRender
+            )));
+            return;
+        }
+
+        let mut state = set_up_basic_plugin();
+        process_command(
+            &SetActionTimeout {
+                timeout_ms: None,
+                actions: HashMap::from([(
+                    constants::ImageEffectActionRender.to_string(),
+                    1,
+                )]),
+            },
+            &mut state,
+        )
+        .unwrap();
 
+        // Frames large enough that some render takes longer than 1 ms
         let inputs = HashMap::from([(
             "Source".to_string(),
             Input {
-                filename: input_path.to_string(),
+                source: InputSource::Generated {
+                    generator: Generator {
+                        pattern: Pattern::Constant {
+                            colour: [0.25, 0.5, 0.125, 0.5],
+                        },
+                        size: (1024, 1024),
+                        components: ImageComponents::Rgba,
+                    },
+                },
                 rowbytes: None,
-                origin: (0, 0),
+                origin: Some((0, 0)),
             },
         )]);
-
-        render(
+        struct Discard;
+        impl ImageWriter for Discard {
+            fn write_image(&self, _frame: u32, _image: Image) -> GenericResult {
+                Ok(())
+            }
+        }
+        let _ = render(
             "instance1",
             &inputs,
-            &writer,
+            &Discard,
             None,
-            (FrameNumber(0), FrameNumber(1)),
-            1,
+            &RenderOptions {
+                frame_range: (FrameNumber(0), FrameNumber(100)),
+                ..Default::default()
+            },
             &mut state,
-        )
-        .unwrap();
+        );
+        panic!("Render finished without timing out");
+    }
 
-        let images = writer.images.lock().unwrap();
-        assert_eq!(images.len(), 1);
-        let (frame, image) = &images[0];
-        assert_eq!(*frame, 0);
-        assert_eq!(image.bounds.width(), 25);
-        assert_eq!(image.bounds.height(), 14);
-        insta::assert_debug_snapshot!(image.pixels);
+    #[test]
+    fn instance_error_messages() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        // Instances are named in error messages
+        let state = set_up_basic_plugin();
+        let instance = state.get_instance("instance1").unwrap();
+        let err = instance
+            .effect
+            .lock()
+            .properties
+            .lock()
+            .get(constants::ImageEffectPropFrameRate, 1)
+            .unwrap_err();
+        assert!(err.message.contains("on instance instance1"));
     }
 
     #[test]
-    fn render_layout() {
+    fn timeout_deadlines() {
+        use timeout::{Deadline, Deadlines};
+
+        let start = Instant::now();
+        let deadline = |ms, description: &str| Deadline {
+            at: start + Duration::from_millis(ms),
+            timeout: Duration::from_millis(ms),
+            description: description.to_string(),
+        };
+        let mut deadlines = Deadlines::default();
+        assert!(deadlines.earliest().is_none());
+
+        let long = deadlines.add(deadline(1000, "long"));
+        let short = deadlines.add(deadline(10, "short"));
+        assert_eq!(deadlines.earliest().unwrap().description, "short");
+        assert!(deadlines.expired(start).is_none());
+        let later = start + Duration::from_millis(50);
+        assert_eq!(deadlines.expired(later).unwrap().description, "short");
+
+        // Once the short action returns, only the long one is left
+        deadlines.remove(short);
+        assert!(deadlines.expired(later).is_none());
+        assert_eq!(deadlines.earliest().unwrap().description, "long");
+        deadlines.remove(long);
+        assert!(deadlines.earliest().is_none());
+    }
+
+    #[test]
+    fn unsupported_calls() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        unsupported::set_allowed(true);
+        let status =
+            crash::in_action(constants::ActionInstanceChanged.as_ptr(), || unsafe {
+                suite_impls::MEMORY_SUITE.memoryFree.unwrap()(std::ptr::null_mut())
+            });
+        unsupported::set_allowed(false);
+        assert_eq!(status, ofxstatus::ErrUnsupported);
+
+        // Reports from test processes are merged
+        unsupported::add_reports(concat!(
+            "not a report\n",
+            r#"{"unsupported_calls":[{"function":"memoryFree","action":"OfxActionInstanceChanged","count":2}]}"#
+        ));
+        let call = unsupported::calls()
+            .into_iter()
+            .find(|c| c.function == "memoryFree")
+            .unwrap();
+        assert_eq!(call.action, constants::ActionInstanceChanged.as_str());
+        assert_eq!(call.count, 3);
+    }
+
+    #[test]
+    fn current_suite_function() {
+        assert_eq!(crash::current_function(), None);
+        {
+            let _outer = crash::enter_function("paramGetValue");
+            {
+                let _inner = crash::enter_function("paramGetHandle");
+                // Other threads have their own current function
+                thread::spawn(|| assert_eq!(crash::current_function(), None))
+                    .join()
+                    .unwrap();
+                assert_eq!(crash::current_function(), Some("paramGetHandle"));
+            }
+            assert_eq!(crash::current_function(), Some("paramGetValue"));
+        }
+        assert_eq!(crash::current_function(), None);
+    }
+
+    #[test]
+    fn current_action_per_thread() {
+        let render = constants::ImageEffectActionRender;
+        crash::in_action(render.as_ptr(), || {
+            // An action on another thread doesn't replace this one's
+            thread::spawn(|| {
+                assert_eq!(crash::current_action(), None);
+                crash::in_action(constants::ActionInstanceChanged.as_ptr(), || {
+                    assert_eq!(
+                        crash::current_action(),
+                        Some(constants::ActionInstanceChanged.as_str())
+                    );
+                });
+            })
+            .join()
+            .unwrap();
+            assert_eq!(crash::current_action(), Some(render.as_str()));
+        });
+        assert_eq!(crash::current_action(), None);
+    }
+
+    #[test]
+    fn recover_bad_handles() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        let bad: PropertySetHandle = std::ptr::null_mut::<std::ffi::c_void>().into();
+        let mut count = 0;
+        handles::set_recover_bad_handles(true);
+        let statuses = unsafe {
+            [
+                suite_impls::PROPERTY_SUITE.propGetDimension.unwrap()(
+                    bad.into(),
+                    c"OfxPropLabel".as_ptr(),
+                    &mut count,
+                ),
+                suite_impls::IMAGE_EFFECT_SUITE.clipReleaseImage.unwrap()(bad.into()),
+            ]
+        };
+        handles::set_recover_bad_handles(false);
+        assert_eq!(statuses, [ofxstatus::ErrBadHandle, ofxstatus::ErrBadHandle]);
+
+        let bad_handles = handles::bad_handles();
+        let functions: Vec<_> = bad_handles.iter().map(|b| b.function).collect();
+        assert!(functions.ends_with(&["propGetDimension", "clipReleaseImage"]));
+        let last = bad_handles.last().unwrap();
+        assert_eq!(last.handle_type, "PropertySetHandle");
+        assert!(last.error.contains("is not in use"));
+    }
+
+    #[test]
+    fn trace_suite_calls() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        let filename = path::PathBuf::from(env!("OUT_DIR")).join("trace.jsonl");
+        trace::open(filename.to_str().unwrap()).unwrap();
+        let state = set_up_basic_plugin();
+        let instance = state.get_instance("instance1").unwrap();
+        let effect: openfx_rs::types::OfxImageEffectHandle =
+            instance.effect.to_handle().into();
+        let param_suite = suite_impls::PARAMETER_SUITE;
+        let mut param_set = openfx_rs::types::OfxParamSetHandle(std::ptr::null_mut());
+        let mut param = openfx_rs::types::OfxParamHandle(std::ptr::null_mut());
+        let mut value = 0.0;
+        unsafe {
+            let get_param_set = suite_impls::IMAGE_EFFECT_SUITE.getParamSet.unwrap();
+            assert_eq!(get_param_set(effect, &mut param_set), ofxstatus::OK);
+            let get_handle = param_suite.paramGetHandle.unwrap();
+            let status = get_handle(
+                param_set,
+                c"scale".as_ptr(),
+                &mut param,
+                std::ptr::null_mut(),
+            );
+            assert_eq!(status, ofxstatus::OK);
+            let status = param_suite.paramSetValue.unwrap()(param, 2.5f64);
+            assert_eq!(status, ofxstatus::OK);
+            let status =
+                param_suite.paramGetValue.unwrap()(param, &mut value as *mut f64);
+            assert_eq!(status, ofxstatus::OK);
+        }
+        assert_eq!(value, 2.5);
+        trace::close();
+
+        let entries: Vec<serde_json::Value> = fs::read_to_string(&filename)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let param_define = entries
+            .iter()
+            .find(|e| e["function"] == "paramDefine")
+            .unwrap();
+        assert_eq!(param_define["suite"], "parameter");
+        assert_eq!(
+            param_define["action"],
+            constants::ImageEffectActionDescribeInContext.as_str()
+        );
+        assert!(param_define["handle"]
+            .as_str()
+            .unwrap()
+            .starts_with("ParamSet "));
+        assert!(param_define["thread"].is_string());
+
+        // Property calls include the property, values and status
+        let prop_set = entries
+            .iter()
+            .find(|e| e["function"] == "propSetString")
+            .unwrap();
+        assert_eq!(prop_set["suite"], "property");
+        assert!(prop_set["handle"]
+            .as_str()
+            .unwrap()
+            .starts_with("PropertySet "));
+        assert!(prop_set["property"].is_string());
+        assert_eq!(prop_set["index"], 0);
+        assert!(prop_set["values_in"][0].is_string());
+        assert!(prop_set["status"].is_string());
+
+        // Other suites record status, returned handles and param
+        // values
+        let ok = format!("{:?}", ofxstatus::OK);
+        let find = |function: &str| {
+            entries
+                .iter()
+                .rev()
+                .find(|e| e["function"] == function)
+                .unwrap()
+        };
+        let get_param_set = find("getParamSet");
+        assert_eq!(get_param_set["status"], ok.as_str());
+        assert!(get_param_set["values_out"][0]
+            .as_str()
+            .unwrap()
+            .starts_with("ParamSet "));
+        let get_handle = find("paramGetHandle");
+        assert_eq!(get_handle["status"], ok.as_str());
+        assert!(get_handle["values_out"][0]
+            .as_str()
+            .unwrap()
+            .contains("scale"));
+        let set_value = find("paramSetValue");
+        assert_eq!(set_value["status"], ok.as_str());
+        assert_eq!(set_value["values_in"][0]["v"], 2.5);
+        let get_value = find("paramGetValue");
+        assert_eq!(get_value["status"], ok.as_str());
+        assert_eq!(get_value["values_out"][0]["v"], 2.5);
+    }
+
+    #[test]
+    fn set_param_without_type() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        let state = set_up_basic_plugin();
+        let instance = state.get_instance("instance1").unwrap();
+        let param = instance.effect.lock().get_param("scale").unwrap();
+        param
+            .lock()
+            .properties
+            .lock()
+            .values
+            .remove(constants::ParamPropType.as_str());
+        let handle: openfx_rs::types::OfxParamHandle = param.to_handle().into();
+
+        // The value can't be read without the type, so setting it fails
+        // instead of aborting, and leaves the param unchanged
+        let status = unsafe {
+            suite_impls::PARAMETER_SUITE.paramSetValue.unwrap()(handle, 2.5f64)
+        };
+        assert_eq!(status, ofxstatus::Failed);
+        assert!(matches!(param.lock().value, ParamValue::Double(v) if v != 2.5));
+    }
+
+    #[test]
+    fn property_audit() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        let state = set_up_basic_plugin();
+        let instance = state.get_instance("instance1").unwrap();
+        let props: PropertySetHandle = instance.effect.lock().properties.to_handle();
+        let get_double = suite_impls::PROPERTY_SUITE.propGetDouble.unwrap();
+        let mut value = 0.0;
+        property_audit::set_enabled(true);
+        for (property, index, expected) in [
+            (c"NoSuchProperty", 0, ofxstatus::ErrUnknown),
+            (c"NoSuchProperty", 0, ofxstatus::ErrUnknown),
+            (c"OfxPropType", 3, ofxstatus::ErrBadIndex),
+            (c"OfxPropType", 0, ofxstatus::ErrUnknown),
+        ] {
+            let status =
+                unsafe { get_double(props.into(), property.as_ptr(), index, &mut value) };
+            assert_eq!(status, expected);
+        }
+        property_audit::set_enabled(false);
+
+        // Duplicate lookups are counted once
+        let missing: Vec<_> = property_audit::missing_properties()
+            .into_iter()
+            .filter(|m| m.property_set == "instance instance1")
+            .map(|m| (m.property, m.index, m.problem, m.count))
+            .collect();
+        assert_eq!(
+            missing,
+            [
+                (
+                    "NoSuchProperty".to_string(),
+                    Some(0),
+                    property_audit::Problem::Missing,
+                    2
+                ),
+                (
+                    "OfxPropType".to_string(),
+                    Some(0),
+                    property_audit::Problem::WrongType,
+                    1
+                ),
+                (
+                    "OfxPropType".to_string(),
+                    Some(3),
+                    property_audit::Problem::BadIndex,
+                    1
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn render_basic() {
         let _lock = COMMAND_MUTEX.lock().unwrap();
 
-        let mut state = set_up_basic_plugin();
         let input_path = "test/colorbars.exr";
+        let mut state = set_up_basic_plugin();
 
         process_command(
             &SetParams {
@@ -3143,9 +5751,13 @@ mod test {
         let inputs = HashMap::from([(
             "Source".to_string(),
             Input {
-                filename: input_path.to_string(),
+                source: InputSource::File {
+                    filename: input_path.to_string(),
+                    format: None,
+                    channels: None,
+                },
                 rowbytes: None,
-                origin: (-2, 3),
+                origin: Some((0, 0)),
             },
         )]);
 
@@ -3153,19 +5765,8 @@ mod test {
             "instance1",
             &inputs,
             &writer,
-            Some(&RenderLayout {
-                project_dims: (25.0, 25.0),
-                render_window: Some(OfxRectI {
-                    x1: 10,
-                    y1: 2,
-                    x2: 26,
-                    y2: 12,
-                }),
-                rowbytes: None,
-                crop_inputs_to_roi: false,
-            }),
-            (FrameNumber(0), FrameNumber(1)),
-            1,
+            None,
+            &RenderOptions::default(),
             &mut state,
         )
         .unwrap();
@@ -3174,23 +5775,303 @@ mod test {
         assert_eq!(images.len(), 1);
         let (frame, image) = &images[0];
         assert_eq!(*frame, 0);
-        assert_eq!(image.bounds.width(), 16);
-        assert_eq!(image.bounds.height(), 10);
+        assert_eq!(image.bounds.width(), 25);
+        assert_eq!(image.bounds.height(), 14);
         insta::assert_debug_snapshot!(image.pixels);
     }
 
     #[test]
-    fn render_mask() {
+    fn render_protected_inputs() {
         let _lock = COMMAND_MUTEX.lock().unwrap();
 
-        let mut state = set_up_basic_plugin();
-        let source_path = "test/colorbars.exr";
-        let mask_path = "test/circle.exr";
+        // The plugin sees a read-only copy of the pixels, named for
+        // crash reports
+        let bounds = OfxRectI {
+            x1: 0,
+            y1: 0,
+            x2: 4,
+            y2: 2,
+        };
+        let mut image = Image::empty("Source", &bounds, None, false);
+        image.protect("Source").unwrap();
+        let data = match image.properties.lock().get(constants::ImagePropData, 0) {
+            Ok(PropertyValue::Pointer(Addr(ptr))) => *ptr as usize,
+            p => panic!("Expected pointer, got {:?}", p),
+        };
+        assert_eq!(
+            crash::read_only_clip(data + 16).map(|c| c.to_str().unwrap()),
+            Some("Source")
+        );
+        drop(image);
+        assert_eq!(crash::read_only_clip(data), None);
 
-        process_command(
-            &SetParams {
-                instance_name: "instance1".to_string(),
-                values: vec![("scale".to_string(), ParamValue::Double(2.0))],
+        // Rendering from read-only inputs gives the same result
+        let mut state = set_up_basic_plugin();
+        let inputs = HashMap::from([(
+            "Source".to_string(),
+            Input {
+                source: InputSource::File {
+                    filename: "test/colorbars.exr".to_string(),
+                    format: None,
+                    channels: None,
+                },
+                rowbytes: None,
+                origin: Some((0, 0)),
+            },
+        )]);
+        let pixels: Vec<_> = [false, true]
+            .into_iter()
+            .map(|protect_inputs| {
+                let writer = CaptureWriter {
+                    images: Default::default(),
+                };
+                render(
+                    "instance1",
+                    &inputs,
+                    &writer,
+                    None,
+                    &RenderOptions {
+                        protect_inputs,
+                        ..Default::default()
+                    },
+                    &mut state,
+                )
+                .unwrap();
+                let images = writer.images.into_inner().unwrap();
+                format!("{:?}", images[0].1.pixels)
+            })
+            .collect();
+        assert_eq!(pixels[0], pixels[1]);
+    }
+
+    #[test]
+    fn guard_bands() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        let bounds = OfxRectI {
+            x1: 10,
+            y1: 20,
+            x2: 14,
+            y2: 22,
+        };
+        let pixel_size = std::mem::size_of::<Pixel>();
+        // Write a pixel through the data pointer, as a plugin would
+        let write = |image: &Image, x: isize, y: isize| {
+            let data = match image.properties.lock().get(constants::ImagePropData, 0) {
+                Ok(PropertyValue::Pointer(Addr(ptr))) => *ptr as *mut Pixel,
+                p => panic!("Expected pointer, got {:?}", p),
+            };
+            unsafe { *data.offset(y * image.stride as isize + x) = Pixel::zero() };
+        };
+
+        // Writes inside the image are fine
+        let mut image = Image::guarded("Output", &bounds, Some(6 * pixel_size), false);
+        assert_eq!(image.stride, 6);
+        write(&image, 0, 0);
+        write(&image, 3, 1);
+        image.check_guard_bands().unwrap();
+        let ImagePixels::Rgba(ref pixels) = image.pixels else {
+            panic!("Expected RGBA image");
+        };
+        assert_eq!(pixels.len(), 12);
+        assert_eq!(pixels[0].r, 0.0);
+        assert!(pixels[4].r.is_nan());
+
+        // Writes to the guard bands and row padding are reported with
+        // their coordinates
+        let mut image = Image::guarded("Output", &bounds, Some(6 * pixel_size), false);
+        write(&image, -1, 0);
+        write(&image, 4, 0);
+        write(&image, 5, 1);
+        write(&image, 0, 2);
+        let err = image.check_guard_bands().unwrap_err().to_string();
+        assert!(err.contains(
+            "1 pixels written in the guard band before the buffer, first at (15, 19)"
+        ));
+        assert!(err.contains(
+            "1 pixels written in the guard band after the buffer, first at (10, 22)"
+        ));
+        assert!(err.contains("2 pixels written in the row padding, first at (14, 20)"));
+
+        // A plugin which only writes its render window passes, and
+        // gives the same result as without guard bands
+        let mut state = set_up_basic_plugin();
+        process_command(
+            &SetParams {
+                instance_name: "instance1".to_string(),
+                values: vec![("scale".to_string(), ParamValue::Double(2.0))],
+                call_instance_changed: false,
+            },
+            &mut state,
+        )
+        .unwrap();
+        let inputs = HashMap::from([(
+            "Source".to_string(),
+            Input {
+                source: InputSource::File {
+                    filename: "test/colorbars.exr".to_string(),
+                    format: None,
+                    channels: None,
+                },
+                rowbytes: None,
+                origin: Some((0, 0)),
+            },
+        )]);
+        let writer = CaptureWriter {
+            images: Default::default(),
+        };
+        render(
+            "instance1",
+            &inputs,
+            &writer,
+            None,
+            &RenderOptions {
+                guard_output: true,
+                ..Default::default()
+            },
+            &mut state,
+        )
+        .unwrap();
+        let images = writer.images.into_inner().unwrap();
+        insta::assert_debug_snapshot!("render_basic", images[0].1.pixels);
+    }
+
+    #[test]
+    fn output_checks() {
+        let bounds = OfxRectI {
+            x1: 10,
+            y1: 20,
+            x2: 14,
+            y2: 22,
+        };
+        let pixel_size = std::mem::size_of::<Pixel>();
+        let mut image = Image::empty("Output", &bounds, Some(6 * pixel_size), false);
+        let ImagePixels::Rgba(ref mut pixels) = image.pixels else {
+            panic!("Expected RGBA image");
+        };
+        pixels[1].r = f32::NAN;
+        pixels[2].a = -1.0;
+        // Row padding isn't checked
+        pixels[4].g = f32::INFINITY;
+        pixels[7].r = 2.0;
+        pixels[7].a = 1.0;
+        // Within rounding error of alpha
+        pixels[3].r = 0.500_000_06;
+        pixels[3].a = 0.5;
+
+        // Nothing is checked by default
+        output_checks::check_output(&image, &OutputChecks::default(), 3).unwrap();
+
+        // Warnings don't fail the render
+        let checks = OutputChecks {
+            nan: Some(CheckLevel::Warning),
+            negative_alpha: Some(CheckLevel::Warning),
+            ..Default::default()
+        };
+        output_checks::check_output(&image, &checks, 3).unwrap();
+
+        let checks = OutputChecks {
+            nan: Some(CheckLevel::Error),
+            infinity: Some(CheckLevel::Error),
+            negative_alpha: Some(CheckLevel::Warning),
+            premultiplication: Some(CheckLevel::Error),
+        };
+        let err = output_checks::check_output(&image, &checks, 3).unwrap_err();
+        assert!(err.downcast_ref::<ComparisonFailed>().is_some());
+        assert_eq!(
+            err.to_string(),
+            "Frame 3: 1 pixels with NaN, first at (11, 20); \
+             Frame 3: 2 pixels with colour greater than alpha, first at (12, 20)"
+        );
+
+        // Colour can exceed alpha in an unpremultiplied image
+        image.properties.lock().set(
+            constants::ImageEffectPropPreMultiplication.as_str(),
+            0,
+            constants::ImageUnPreMultiplied.into(),
+        );
+        let checks = OutputChecks {
+            premultiplication: Some(CheckLevel::Error),
+            ..Default::default()
+        };
+        output_checks::check_output(&image, &checks, 3).unwrap();
+    }
+
+    #[test]
+    fn render_layout() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        let mut state = set_up_basic_plugin();
+        let input_path = "test/colorbars.exr";
+
+        process_command(
+            &SetParams {
+                instance_name: "instance1".to_string(),
+                values: vec![("scale".to_string(), ParamValue::Double(2.0))],
+                call_instance_changed: false,
+            },
+            &mut state,
+        )
+        .unwrap();
+
+        let writer = CaptureWriter {
+            images: Default::default(),
+        };
+
+        let inputs = HashMap::from([(
+            "Source".to_string(),
+            Input {
+                source: InputSource::File {
+                    filename: input_path.to_string(),
+                    format: None,
+                    channels: None,
+                },
+                rowbytes: None,
+                origin: Some((-2, 3)),
+            },
+        )]);
+
+        render(
+            "instance1",
+            &inputs,
+            &writer,
+            Some(&RenderLayout {
+                project_dims: (25.0, 25.0),
+                render_window: Some(OfxRectI {
+                    x1: 10,
+                    y1: 2,
+                    x2: 26,
+                    y2: 12,
+                }),
+                rowbytes: None,
+                crop_inputs_to_roi: false,
+            }),
+            &RenderOptions::default(),
+            &mut state,
+        )
+        .unwrap();
+
+        let images = writer.images.lock().unwrap();
+        assert_eq!(images.len(), 1);
+        let (frame, image) = &images[0];
+        assert_eq!(*frame, 0);
+        assert_eq!(image.bounds.width(), 16);
+        assert_eq!(image.bounds.height(), 10);
+        insta::assert_debug_snapshot!(image.pixels);
+    }
+
+    #[test]
+    fn render_mask() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        let mut state = set_up_basic_plugin();
+        let source_path = "test/colorbars.exr";
+        let mask_path = "test/circle.exr";
+
+        process_command(
+            &SetParams {
+                instance_name: "instance1".to_string(),
+                values: vec![("scale".to_string(), ParamValue::Double(2.0))],
                 call_instance_changed: false,
             },
             &mut state,
@@ -3205,17 +6086,25 @@ mod test {
             (
                 "Source".to_string(),
                 Input {
-                    filename: source_path.to_string(),
+                    source: InputSource::File {
+                        filename: source_path.to_string(),
+                        format: None,
+                        channels: None,
+                    },
                     rowbytes: None,
-                    origin: (0, 0),
+                    origin: Some((0, 0)),
                 },
             ),
             (
                 "Mask".to_string(),
                 Input {
-                    filename: mask_path.to_string(),
+                    source: InputSource::File {
+                        filename: mask_path.to_string(),
+                        format: None,
+                        channels: None,
+                    },
                     rowbytes: Some(std::mem::size_of::<f32>() * (16)), // Pad to 16 pixels
-                    origin: (2, 3),
+                    origin: Some((2, 3)),
                 },
             ),
         ]);
@@ -3225,8 +6114,7 @@ mod test {
             &inputs,
             &writer,
             None,
-            (FrameNumber(0), FrameNumber(1)),
-            1,
+            &RenderOptions::default(),
             &mut state,
         )
         .unwrap();
@@ -3264,9 +6152,13 @@ mod test {
         let inputs = HashMap::from([(
             "Source".to_string(),
             Input {
-                filename: input_path.to_string(),
+                source: InputSource::File {
+                    filename: input_path.to_string(),
+                    format: None,
+                    channels: None,
+                },
                 rowbytes: None,
-                origin: (5, 5),
+                origin: Some((5, 5)),
             },
         )]);
 
@@ -3280,8 +6172,7 @@ mod test {
                 rowbytes: None,
                 crop_inputs_to_roi: false,
             }),
-            (FrameNumber(0), FrameNumber(1)),
-            1,
+            &RenderOptions::default(),
             &mut state,
         )
         .unwrap();
@@ -3319,9 +6210,13 @@ mod test {
         let inputs = HashMap::from([(
             "Source".to_string(),
             Input {
-                filename: input_path.to_string(),
+                source: InputSource::File {
+                    filename: input_path.to_string(),
+                    format: None,
+                    channels: None,
+                },
                 rowbytes: None,
-                origin: (0, 0),
+                origin: Some((0, 0)),
             },
         )]);
 
@@ -3340,8 +6235,7 @@ mod test {
                 rowbytes: None,
                 crop_inputs_to_roi: true,
             }),
-            (FrameNumber(0), FrameNumber(1)),
-            1,
+            &RenderOptions::default(),
             &mut state,
         )
         .unwrap();
@@ -3379,9 +6273,13 @@ mod test {
         let inputs = HashMap::from([(
             "Source".to_string(),
             Input {
-                filename: input_path.to_string(),
+                source: InputSource::File {
+                    filename: input_path.to_string(),
+                    format: None,
+                    channels: None,
+                },
                 rowbytes: Some(std::mem::size_of::<Pixel>() * (32)), // Pad to 32 pixels
-                origin: (0, 0),
+                origin: Some((0, 0)),
             },
         )]);
 
@@ -3400,8 +6298,7 @@ mod test {
                 rowbytes: Some(std::mem::size_of::<Pixel>() * (32)), // Pad to 32 pixels
                 crop_inputs_to_roi: false,
             }),
-            (FrameNumber(0), FrameNumber(1)),
-            1,
+            &RenderOptions::default(),
             &mut state,
         )
         .unwrap();
@@ -3439,9 +6336,13 @@ mod test {
         let inputs = HashMap::from([(
             "Source".to_string(),
             Input {
-                filename: input_path.to_string(),
+                source: InputSource::File {
+                    filename: input_path.to_string(),
+                    format: None,
+                    channels: None,
+                },
                 rowbytes: None,
-                origin: (0, 0),
+                origin: Some((0, 0)),
             },
         )]);
 
@@ -3450,8 +6351,7 @@ mod test {
             &inputs,
             &writer,
             None,
-            (FrameNumber(0), FrameNumber(1)),
-            1,
+            &RenderOptions::default(),
             &mut state,
         )
         .unwrap();
@@ -3465,6 +6365,1234 @@ mod test {
         insta::assert_debug_snapshot!(image.pixels);
     }
 
+    fn render_frames_with_threads(
+        frame_range: (u32, u32),
+        thread_count: u32,
+        instance_per_thread: bool,
+    ) -> Vec<(u32, Image)> {
+        let mut state = set_up_basic_plugin();
+
+        let writer = CaptureWriter {
+            images: Default::default(),
+        };
+
+        let inputs = HashMap::from([(
+            "Source".to_string(),
+            Input {
+                source: InputSource::File {
+                    filename: "test/colorbars.exr".to_string(),
+                    format: None,
+                    channels: None,
+                },
+                rowbytes: None,
+                origin: Some((0, 0)),
+            },
+        )]);
+
+        render(
+            "instance1",
+            &inputs,
+            &writer,
+            None,
+            &RenderOptions {
+                frame_range: (FrameNumber(frame_range.0), FrameNumber(frame_range.1)),
+                thread_count,
+                instance_per_thread,
+                ..Default::default()
+            },
+            &mut state,
+        )
+        .unwrap();
+
+        let mut images = writer.images.into_inner().unwrap();
+        images.sort_by_key(|(frame, _)| *frame);
+        images
+    }
+
+    #[test]
+    fn render_frame_range_threads() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        let images = render_frames_with_threads((10, 20), 3, false);
+        assert_eq!(
+            images.iter().map(|(frame, _)| *frame).collect::<Vec<_>>(),
+            (10..20).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn render_instance_per_thread() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        let images = render_frames_with_threads((10, 20), 3, true);
+        assert_eq!(
+            images.iter().map(|(frame, _)| *frame).collect::<Vec<_>>(),
+            (10..20).collect::<Vec<_>>()
+        );
+        let expected = format!("{:?}", images[0].1.pixels);
+        for (_, image) in &images {
+            assert_eq!(format!("{:?}", image.pixels), expected);
+        }
+
+        // More threads than frames
+        let images = render_frames_with_threads((10, 12), 8, true);
+        assert_eq!(
+            images.iter().map(|(frame, _)| *frame).collect::<Vec<_>>(),
+            vec![10, 11]
+        );
+    }
+
+    #[test]
+    fn render_deterministic_check() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        let mut state = set_up_basic_plugin();
+        let writer = CaptureWriter {
+            images: Default::default(),
+        };
+        let inputs = HashMap::from([(
+            "Source".to_string(),
+            Input {
+                source: InputSource::File {
+                    filename: "test/colorbars.exr".to_string(),
+                    format: None,
+                    channels: None,
+                },
+                rowbytes: None,
+                origin: Some((0, 0)),
+            },
+        )]);
+
+        // The basic plugin is deterministic, so the renders match and
+        // the first one is written
+        let options = RenderOptions {
+            frame_range: (FrameNumber(0), FrameNumber(4)),
+            deterministic_check: Some(DeterministicCheck {
+                renders: 2,
+                thread_counts: vec![1, 3],
+                tolerance: 0.0,
+            }),
+            ..Default::default()
+        };
+        render("instance1", &inputs, &writer, None, &options, &mut state).unwrap();
+        let mut images = writer.images.into_inner().unwrap();
+        images.sort_by_key(|(frame, _)| *frame);
+        assert_eq!(
+            images.iter().map(|(frame, _)| *frame).collect::<Vec<_>>(),
+            (0..4).collect::<Vec<_>>()
+        );
+
+        let options = RenderOptions {
+            deterministic_check: Some(DeterministicCheck {
+                renders: 1,
+                thread_counts: vec![],
+                tolerance: 0.0,
+            }),
+            ..Default::default()
+        };
+        let writer = CaptureWriter {
+            images: Default::default(),
+        };
+        let err = render("instance1", &inputs, &writer, None, &options, &mut state)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "A deterministic check needs at least two renders"
+        );
+
+        // Differences are summarised with the first differing sample.
+        // Without a tolerance, the sign of zero counts.
+        let bounds = OfxRectI {
+            x1: 10,
+            y1: 20,
+            x2: 14,
+            y2: 22,
+        };
+        let image = Image::empty("Output", &bounds, None, false);
+        let mut other = Image::empty("Output", &bounds, None, false);
+        let ImagePixels::Rgba(ref mut pixels) = other.pixels else {
+            panic!("Expected RGBA image");
+        };
+        pixels[1].g = -0.0;
+        pixels[6].a = 0.5;
+        assert_eq!(
+            compare::describe_differences(&other, &image, 0.0).unwrap(),
+            "2 samples differ, max difference 0.5, first at (11, 20) in G"
+        );
+        assert_eq!(
+            compare::describe_differences(&other, &image, 0.25).unwrap(),
+            "1 samples differ, max difference 0.5, first at (12, 21) in A"
+        );
+        assert_eq!(compare::describe_differences(&other, &image, 0.5), None);
+
+        // A NaN where the first render has a number differs at any
+        // tolerance
+        let ImagePixels::Rgba(ref mut pixels) = other.pixels else {
+            panic!("Expected RGBA image");
+        };
+        pixels[3].b = f32::NAN;
+        assert_eq!(
+            compare::describe_differences(&other, &image, 0.5).unwrap(),
+            "1 samples differ, max difference NaN, first at (13, 20) in B"
+        );
+        let alpha = Image::empty("Output", &bounds, None, true);
+        assert_eq!(
+            compare::describe_differences(&alpha, &image, 0.0).unwrap(),
+            "different size or components"
+        );
+    }
+
+    #[test]
+    fn render_abort_on_frame() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        let mut state = set_up_basic_plugin();
+        let writer = CaptureWriter {
+            images: Default::default(),
+        };
+
+        let inputs = HashMap::from([(
+            "Source".to_string(),
+            Input {
+                source: InputSource::File {
+                    filename: "test/colorbars.exr".to_string(),
+                    format: None,
+                    channels: None,
+                },
+                rowbytes: None,
+                origin: Some((0, 0)),
+            },
+        )]);
+
+        render(
+            "instance1",
+            &inputs,
+            &writer,
+            None,
+            &RenderOptions {
+                frame_range: (FrameNumber(0), FrameNumber(5)),
+                abort: Some(AbortTrigger::OnFrame(FrameNumber(2))),
+                ..Default::default()
+            },
+            &mut state,
+        )
+        .unwrap();
+
+        // Frames before the abort are written, the aborted frame and
+        // everything after it are not
+        let images = writer.images.lock().unwrap();
+        assert_eq!(
+            images.iter().map(|(frame, _)| *frame).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+    }
+
+    // Render frames 0..6 with an abort trigger, returning the result
+    // and the frames which were written
+    fn render_with_abort(
+        state: &mut CommandState,
+        abort: Option<AbortTrigger>,
+        abort_limit_ms: u64,
+    ) -> (GenericResult, Vec<u32>) {
+        let writer = CaptureWriter {
+            images: Default::default(),
+        };
+        let inputs = HashMap::from([(
+            "Source".to_string(),
+            Input {
+                source: InputSource::File {
+                    filename: "test/colorbars.exr".to_string(),
+                    format: None,
+                    channels: None,
+                },
+                rowbytes: None,
+                origin: Some((0, 0)),
+            },
+        )]);
+        let result = render(
+            "instance1",
+            &inputs,
+            &writer,
+            None,
+            &RenderOptions {
+                frame_range: (FrameNumber(0), FrameNumber(6)),
+                thread_count: 2,
+                instance_per_thread: true,
+                abort,
+                abort_limit_ms,
+                ..Default::default()
+            },
+            state,
+        );
+        let mut frames: Vec<_> = writer
+            .images
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .map(|(frame, _)| frame)
+            .collect();
+        frames.sort();
+        (result, frames)
+    }
+
+    #[test]
+    fn render_abort_threads() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        let mut state = set_up_basic_plugin();
+
+        // The basic plugin calls abort() once per row, and the input
+        // has 14 rows. Per-thread instances share the abort state, so
+        // the trigger counts calls from both threads and at most one
+        // frame can finish.
+        let (result, frames) =
+            render_with_abort(&mut state, Some(AbortTrigger::AfterCalls(20)), 1000);
+        result.unwrap();
+        assert!(frames.len() <= 1, "Frames rendered: {:?}", frames);
+
+        let (result, frames) =
+            render_with_abort(&mut state, Some(AbortTrigger::AfterMillis(0)), 1000);
+        result.unwrap();
+        assert_eq!(frames, Vec::<u32>::new());
+
+        // A failed response to an abort doesn't leave the abort state
+        // set for later renders
+        let (result, _) =
+            render_with_abort(&mut state, Some(AbortTrigger::AfterCalls(0)), 0);
+        let err = format!("{:#}", result.unwrap_err());
+        assert!(err.contains("to return after abort"), "{}", err);
+        let instance = state.get_instance("instance1").unwrap();
+        assert!(instance.effect.lock().abort.is_none());
+        let (result, frames) = render_with_abort(&mut state, None, 1000);
+        result.unwrap();
+        assert_eq!(frames, (0..6).collect::<Vec<_>>());
+    }
+
+    fn get_scale(state: &CommandState) -> ParamValue {
+        let instance = state.get_instance("instance1").unwrap();
+        let param = instance.effect.lock().get_param("scale").unwrap();
+        param.lock().value.clone()
+    }
+
+    #[test]
+    fn save_and_load_params() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        let out_dir = path::PathBuf::from(env!("OUT_DIR"));
+        let preset = out_dir.join("preset.json").to_str().unwrap().to_string();
+        let mut state = set_up_basic_plugin();
+        let set_scale = |scale, state: &mut CommandState| {
+            process_command(
+                &SetParams {
+                    instance_name: "instance1".to_string(),
+                    values: vec![("scale".to_string(), ParamValue::Double(scale))],
+                    call_instance_changed: false,
+                },
+                state,
+            )
+            .unwrap();
+        };
+
+        set_scale(2.0, &mut state);
+        process_command(
+            &SaveParams {
+                instance_name: "instance1".to_string(),
+                filename: preset.clone(),
+            },
+            &mut state,
+        )
+        .unwrap();
+        set_scale(1.0, &mut state);
+
+        let load = |filename: &str, state: &mut CommandState| {
+            process_command(
+                &LoadParams {
+                    instance_name: "instance1".to_string(),
+                    filename: filename.to_string(),
+                    call_instance_changed: true,
+                },
+                state,
+            )
+        };
+        load(&preset, &mut state).unwrap();
+        assert!(matches!(get_scale(&state), ParamValue::Double(2.0)));
+
+        // Unknown params are reported, but valid params are still
+        // applied
+        let bad_preset = out_dir
+            .join("bad_preset.json")
+            .to_str()
+            .unwrap()
+            .to_string();
+        fs::write(
+            &bad_preset,
+            r#"{"plugin": "uk.co.thefoundry.BasicGainPlugin",
+                "params": {"missing": {"type": "Double", "v": 1.0},
+                           "scale": {"type": "Double", "v": 3.0}}}"#,
+        )
+        .unwrap();
+        let err = load(&bad_preset, &mut state).unwrap_err();
+        assert!(format!("{:#}", err).contains("1 of 2 params"));
+        assert!(matches!(get_scale(&state), ParamValue::Double(3.0)));
+
+        // Type mismatches are not applied
+        fs::write(
+            &bad_preset,
+            r#"{"plugin": "uk.co.thefoundry.BasicGainPlugin",
+                "params": {"scale": {"type": "Integer", "v": 4}}}"#,
+        )
+        .unwrap();
+        assert!(load(&bad_preset, &mut state).is_err());
+        assert!(matches!(get_scale(&state), ParamValue::Double(3.0)));
+    }
+
+    #[test]
+    fn save_and_load_project() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        let out_dir = path::PathBuf::from(env!("OUT_DIR"));
+        let project = out_dir.join("project.json").to_str().unwrap().to_string();
+        let mut state = set_up_basic_plugin();
+        process_command(
+            &SetParams {
+                instance_name: "instance1".to_string(),
+                values: vec![("scale".to_string(), ParamValue::Double(2.0))],
+                call_instance_changed: false,
+            },
+            &mut state,
+        )
+        .unwrap();
+
+        process_command(
+            &CheckProjectRoundTrip {
+                instance_name: "instance1".to_string(),
+                filename: project.clone(),
+                inputs: HashMap::from([(
+                    "Source".to_string(),
+                    Input {
+                        source: InputSource::File {
+                            filename: "test/colorbars.exr".to_string(),
+                            format: None,
+                            channels: None,
+                        },
+                        rowbytes: None,
+                        origin: Some((0, 0)),
+                    },
+                )]),
+                layout: Some(RenderLayout {
+                    project_dims: (25.0, 14.0),
+                    render_window: None,
+                    rowbytes: None,
+                    crop_inputs_to_roi: false,
+                }),
+                options: RenderOptions::default(),
+            },
+            &mut state,
+        )
+        .unwrap();
+        // The reloaded instance replaces the original
+        assert!(matches!(get_scale(&state), ParamValue::Double(2.0)));
+
+        // Load a second copy under a different name
+        process_command(
+            &LoadProject {
+                filename: project,
+                instance_name: Some("instance2".to_string()),
+            },
+            &mut state,
+        )
+        .unwrap();
+        let instance = state.get_instance("instance2").unwrap();
+        assert_eq!(instance.plugin_name, "uk.co.thefoundry.BasicGainPlugin");
+        let param = instance.effect.lock().get_param("scale").unwrap();
+        assert!(matches!(param.lock().value, ParamValue::Double(2.0)));
+
+        // A project which fails to load leaves the existing instance
+        // and its connections in place
+        process_command(
+            &ConnectClip {
+                source_instance: "instance2".to_string(),
+                instance_name: "instance1".to_string(),
+                clip_name: "Source".to_string(),
+            },
+            &mut state,
+        )
+        .unwrap();
+        let bad_project = out_dir.join("bad_project.json");
+        fs::write(
+            &bad_project,
+            r#"{"instance_name": "instance1", "plugin": "missing",
+                "context": "General", "params": {}}"#,
+        )
+        .unwrap();
+        assert!(process_command(
+            &LoadProject {
+                filename: bad_project.to_str().unwrap().to_string(),
+                instance_name: None,
+            },
+            &mut state,
+        )
+        .is_err());
+        let instance = state.get_instance("instance1").unwrap();
+        assert_eq!(instance.connections["Source"], "instance2");
+        assert!(matches!(get_scale(&state), ParamValue::Double(2.0)));
+    }
+
+    #[test]
+    fn project_custom_param_bytes() {
+        // Custom and String params are CStrings which needn't be
+        // UTF-8. Their bytes survive saving and loading a project.
+        let bytes = vec![b'a', 0xff, 0x80, b'{', b'"'];
+        let project = Project {
+            instance_name: "instance1".to_string(),
+            plugin: "plugin".to_string(),
+            context: ImageEffectContext::General,
+            params: BTreeMap::from([(
+                "data".to_string(),
+                ParamValue::Custom(CString::new(bytes.clone()).unwrap()),
+            )]),
+        };
+        let json = serde_json::to_string_pretty(&project).unwrap();
+        let loaded: Project = serde_json::from_str(&json).unwrap();
+        match &loaded.params["data"] {
+            ParamValue::Custom(s) => assert_eq!(s.as_bytes(), bytes),
+            _ => panic!("Custom param not loaded"),
+        }
+    }
+
+    #[test]
+    fn render_graph() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        let mut state = set_up_basic_plugin();
+        for command in [
+            CreateInstance {
+                plugin_name: "uk.co.thefoundry.BasicGainPlugin".to_string(),
+                instance_name: "instance2".to_string(),
+                context: ImageEffectContext::General,
+            },
+            ConnectClip {
+                source_instance: "instance1".to_string(),
+                instance_name: "instance2".to_string(),
+                clip_name: "Source".to_string(),
+            },
+        ] {
+            process_command(&command, &mut state).unwrap();
+        }
+        for name in ["instance1", "instance2"] {
+            process_command(
+                &SetParams {
+                    instance_name: name.to_string(),
+                    values: vec![("scale".to_string(), ParamValue::Double(2.0))],
+                    call_instance_changed: false,
+                },
+                &mut state,
+            )
+            .unwrap();
+        }
+
+        // Connecting the other way round would create a cycle
+        assert!(process_command(
+            &ConnectClip {
+                source_instance: "instance2".to_string(),
+                instance_name: "instance1".to_string(),
+                clip_name: "Source".to_string(),
+            },
+            &mut state,
+        )
+        .is_err());
+
+        let input = Input {
+            source: InputSource::File {
+                filename: "test/colorbars.exr".to_string(),
+                format: None,
+                channels: None,
+            },
+            rowbytes: None,
+            origin: Some((0, 0)),
+        };
+        let writer = CaptureWriter {
+            images: Default::default(),
+        };
+        render(
+            "instance2",
+            &HashMap::new(),
+            &writer,
+            Some(&RenderLayout {
+                project_dims: (25.0, 14.0),
+                render_window: Some(OfxRectI {
+                    x1: 0,
+                    y1: 0,
+                    x2: 25,
+                    y2: 14,
+                }),
+                rowbytes: None,
+                crop_inputs_to_roi: false,
+            }),
+            &RenderOptions {
+                node_inputs: HashMap::from([(
+                    "instance1".to_string(),
+                    HashMap::from([("Source".to_string(), input.clone())]),
+                )]),
+                ..Default::default()
+            },
+            &mut state,
+        )
+        .unwrap();
+
+        let images = writer.images.lock().unwrap();
+        assert_eq!(images.len(), 1);
+        let (_, image) = &images[0];
+        assert_eq!(image.bounds.width(), 25);
+        assert_eq!(image.bounds.height(), 14);
+
+        // Inside the RoI of the second instance, the input has been
+        // scaled by both instances
+        let source = get_input_image("Source", &input).unwrap();
+        let (ImagePixels::Rgba(out), ImagePixels::Rgba(src)) =
+            (&image.pixels, &source.pixels)
+        else {
+            panic!("Expected RGBA images");
+        };
+        let index = 5 * 25 + 10;
+        assert_eq!(out[index].r, src[index].r * 4.0);
+        assert_eq!(out[index].a, src[index].a * 4.0);
+        drop(images);
+
+        // Upstream inputs are read once and RoDs computed once per
+        // render, so a cached RoD doesn't read its inputs again
+        let mut cache = GraphCache::default();
+        let options = RenderOptions {
+            node_inputs: HashMap::from([(
+                "instance1".to_string(),
+                HashMap::from([("Source".to_string(), input.clone())]),
+            )]),
+            ..Default::default()
+        };
+        let rod = get_graph_rod(
+            "instance1",
+            &options.node_inputs["instance1"],
+            (25.0, 14.0),
+            &options,
+            &state,
+            &mut cache,
+        )
+        .unwrap();
+        assert!(cache.rods.contains_key("instance1"));
+        assert!(cache
+            .images
+            .contains_key(&("instance1".to_string(), "Source".to_string())));
+        let missing = Input {
+            source: InputSource::File {
+                filename: "test/missing.exr".to_string(),
+                format: None,
+                channels: None,
+            },
+            ..input.clone()
+        };
+        let cached_rod = get_graph_rod(
+            "instance1",
+            &HashMap::from([("Source".to_string(), missing.clone())]),
+            (25.0, 14.0),
+            &options,
+            &state,
+            &mut cache,
+        )
+        .unwrap();
+        assert_eq!(
+            (cached_rod.x1, cached_rod.y1, cached_rod.x2, cached_rod.y2),
+            (rod.x1, rod.y1, rod.x2, rod.y2)
+        );
+
+        // Rendering takes the cached image, and reads it again next time
+        let image = cache
+            .take_input_image("instance1", "Source", &missing)
+            .unwrap();
+        assert_eq!(image.bounds.width(), 25);
+        assert!(cache
+            .take_input_image("instance1", "Source", &missing)
+            .is_err());
+    }
+
+    #[test]
+    fn generate_checkerboard() {
+        let image = get_input_image(
+            "Source",
+            &Input {
+                source: InputSource::Generated {
+                    generator: Generator {
+                        pattern: Pattern::Checkerboard {
+                            size: 2,
+                            colour1: [1.0, 1.0, 1.0, 1.0],
+                            colour2: [0.0, 0.0, 0.0, 0.0],
+                        },
+                        size: (6, 4),
+                        components: ImageComponents::Alpha,
+                    },
+                },
+                rowbytes: Some(std::mem::size_of::<f32>() * 8),
+                origin: Some((1, 0)),
+            },
+        )
+        .unwrap();
+
+        assert_eq!(image.bounds.x1, 1);
+        assert_eq!(image.bounds.width(), 6);
+        assert_eq!(image.stride, 8);
+        let ImagePixels::Alpha(pixels) = &image.pixels else {
+            panic!("Expected alpha image");
+        };
+        // First row starts at x=1, halfway through a square
+        assert_eq!(pixels[..6], [1.0, 0.0, 0.0, 1.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn render_generated_input() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        let mut state = set_up_basic_plugin();
+        process_command(
+            &SetParams {
+                instance_name: "instance1".to_string(),
+                values: vec![("scale".to_string(), ParamValue::Double(2.0))],
+                call_instance_changed: false,
+            },
+            &mut state,
+        )
+        .unwrap();
+
+        let writer = CaptureWriter {
+            images: Default::default(),
+        };
+        let inputs = HashMap::from([(
+            "Source".to_string(),
+            Input {
+                source: InputSource::Generated {
+                    generator: Generator {
+                        pattern: Pattern::Constant {
+                            colour: [0.25, 0.5, 0.125, 0.5],
+                        },
+                        size: (8, 4),
+                        components: ImageComponents::Rgba,
+                    },
+                },
+                rowbytes: None,
+                origin: Some((0, 0)),
+            },
+        )]);
+
+        render(
+            "instance1",
+            &inputs,
+            &writer,
+            None,
+            &RenderOptions::default(),
+            &mut state,
+        )
+        .unwrap();
+
+        let images = writer.images.lock().unwrap();
+        let ImagePixels::Rgba(pixels) = &images[0].1.pixels else {
+            panic!("Expected RGBA image");
+        };
+        assert_eq!(pixels.len(), 32);
+        for p in pixels {
+            assert_eq!([p.r, p.g, p.b, p.a], [0.5, 1.0, 0.25, 1.0]);
+        }
+    }
+
+    #[test]
+    fn compare_identical_images() {
+        compare::compare_images(
+            "test/colorbars.exr",
+            "test/colorbars.exr",
+            CompareMetric::MaxAbsError,
+            0.0,
+            0.0,
+            None,
+            None,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn compare_different_images() {
+        let out_dir = path::PathBuf::from(env!("OUT_DIR"));
+        let scaled_path = out_dir.join("compare_scaled.exr");
+        let diff_path = out_dir.join("compare_diff.exr");
+        let _ = std::fs::remove_file(&diff_path);
+
+        let mut image = read_exr("image", "test/colorbars.exr", None, None).unwrap();
+        if let ImagePixels::Rgba(pixels) = &mut image.pixels {
+            for p in pixels {
+                p.r *= 2.0;
+            }
+        }
+        write_exr(scaled_path.to_str().unwrap(), image).unwrap();
+
+        let err = compare::compare_images(
+            scaled_path.to_str().unwrap(),
+            "test/colorbars.exr",
+            CompareMetric::PixelsOverThreshold,
+            0.0,
+            0.01,
+            None,
+            Some(diff_path.to_str().unwrap()),
+        )
+        .unwrap_err();
+        assert!(err.downcast_ref::<ComparisonFailed>().is_some());
+        assert!(diff_path.exists());
+
+        // Passes if the tolerance allows every pixel to differ
+        compare::compare_images(
+            scaled_path.to_str().unwrap(),
+            "test/colorbars.exr",
+            CompareMetric::PixelsOverThreshold,
+            (25 * 14) as f64,
+            0.01,
+            None,
+            None,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn compare_empty_region() {
+        // Regions which are inverted or outside the image are errors,
+        // rather than comparing nothing and passing
+        for region in [
+            OfxRectI {
+                x1: 10,
+                y1: 0,
+                x2: 5,
+                y2: 14,
+            },
+            OfxRectI {
+                x1: 30,
+                y1: 0,
+                x2: 40,
+                y2: 14,
+            },
+        ] {
+            let err = compare::compare_images(
+                "test/colorbars.exr",
+                "test/colorbars.exr",
+                CompareMetric::MaxAbsError,
+                0.0,
+                0.0,
+                Some(region),
+                None,
+            )
+            .unwrap_err();
+            assert!(err
+                .to_string()
+                .contains("is empty or outside the 25x14 image"));
+        }
+    }
+
+    #[test]
+    fn image_formats_round_trip() {
+        let out_dir = path::PathBuf::from(env!("OUT_DIR"));
+        let rgba = "test/colorbars.exr";
+        let alpha = "test/alpha-bars.exr";
+        // Integer formats are only off by rounding to the nearest
+        // level
+        let u8_tolerance = 1.0 / 255.0;
+        let u16_tolerance = 1.0 / 65535.0;
+        for (source, filename, depth, tolerance) in [
+            (rgba, "round_trip.png", SampleDepth::U16, u16_tolerance),
+            (rgba, "round_trip.tiff", SampleDepth::F32, 0.0),
+            (rgba, "round_trip.raw", SampleDepth::F32, 0.0),
+            (alpha, "round_trip_alpha.png", SampleDepth::U8, u8_tolerance),
+            (
+                alpha,
+                "round_trip_alpha.tif",
+                SampleDepth::U16,
+                u16_tolerance,
+            ),
+        ] {
+            let path = out_dir.join(filename);
+            let path = path.to_str().unwrap();
+            let image = read_exr("image", source, None, None).unwrap();
+            // The test images go slightly outside [0, 1], which integer
+            // formats clamp. Compare those with a clamped copy.
+            let reference = if matches!(depth, SampleDepth::F32) {
+                source.to_string()
+            } else {
+                let mut clamped = image.clone();
+                for sample in clamped.pixels.samples_mut() {
+                    *sample = sample.clamp(0.0, 1.0);
+                }
+                // Alpha is written as grey, which is read back as RGB
+                if let ImagePixels::Alpha(pixels) = &clamped.pixels {
+                    clamped.pixels = ImagePixels::Rgba(
+                        pixels
+                            .iter()
+                            .map(|&v| Pixel {
+                                r: v,
+                                g: v,
+                                b: v,
+                                a: 1.0,
+                            })
+                            .collect(),
+                    );
+                }
+                let reference = out_dir.join(format!("{}_reference.exr", filename));
+                let reference = reference.to_str().unwrap().to_string();
+                write_exr(&reference, clamped).unwrap();
+                reference
+            };
+            image_io::write_image(path, None, Some(depth), image).unwrap();
+            compare::compare_images(
+                path,
+                &reference,
+                CompareMetric::MaxAbsError,
+                tolerance,
+                0.0,
+                None,
+                None,
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn read_grey_png() {
+        let path = path::PathBuf::from(env!("OUT_DIR")).join("grey.png");
+        let file = File::create(&path).unwrap();
+        let mut encoder = png::Encoder::new(io::BufWriter::new(file), 2, 1);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[0, 255]).unwrap();
+        writer.finish().unwrap();
+
+        // A greyscale PNG is RGB with opaque alpha, like an RGBA render
+        let image =
+            image_io::read_image("image", path.to_str().unwrap(), None, None, None, None)
+                .unwrap();
+        let ImagePixels::Rgba(pixels) = &image.pixels else {
+            panic!("Expected RGBA image");
+        };
+        let values: Vec<_> = pixels.iter().map(|p| [p.r, p.g, p.b, p.a]).collect();
+        assert_eq!(values, [[0.0, 0.0, 0.0, 1.0], [1.0, 1.0, 1.0, 1.0]]);
+    }
+
+    #[test]
+    fn exr_data_window_round_trip() {
+        let path = path::PathBuf::from(env!("OUT_DIR")).join("data_window.exr");
+        let path = path.to_str().unwrap();
+        let coords = |r: OfxRectI| (r.x1, r.y1, r.x2, r.y2);
+
+        // The test image is the bottom left corner of an HD frame
+        let mut image = read_exr("image", "test/colorbars.exr", None, None).unwrap();
+        assert_eq!(coords(image.bounds), (0, 0, 25, 14));
+        assert_eq!(image.display_window.map(coords), Some((0, 0, 1920, 1080)));
+
+        image.bounds = OfxRectI {
+            x1: -3,
+            y1: 2,
+            x2: 22,
+            y2: 16,
+        };
+        image.display_window = Some(OfxRectI {
+            x1: 0,
+            y1: 0,
+            x2: 20,
+            y2: 10,
+        });
+        write_exr(path, image).unwrap();
+
+        // Bounds come from the data window by default
+        let image = read_exr("image", path, None, None).unwrap();
+        assert_eq!(coords(image.bounds), (-3, 2, 22, 16));
+        assert_eq!(image.display_window.map(coords), Some((0, 0, 20, 10)));
+
+        // Explicit origin overrides the data window
+        let image = read_exr("image", path, None, Some((1, 1))).unwrap();
+        assert_eq!(coords(image.bounds), (1, 1, 26, 15));
+    }
+
+    #[test]
+    fn render_input_data_window_rod() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        let path = path::PathBuf::from(env!("OUT_DIR")).join("offset_source.exr");
+        let path = path.to_str().unwrap();
+        let mut image = read_exr("image", "test/colorbars.exr", None, None).unwrap();
+        image.bounds = OfxRectI {
+            x1: -3,
+            y1: 2,
+            x2: 22,
+            y2: 16,
+        };
+        write_exr(path, image).unwrap();
+
+        let mut state = set_up_basic_plugin();
+        let writer = CaptureWriter {
+            images: Default::default(),
+        };
+        let inputs = HashMap::from([(
+            "Source".to_string(),
+            Input {
+                source: InputSource::File {
+                    filename: path.to_string(),
+                    format: None,
+                    channels: None,
+                },
+                rowbytes: None,
+                origin: None,
+            },
+        )]);
+        render(
+            "instance1",
+            &inputs,
+            &writer,
+            None,
+            &RenderOptions::default(),
+            &mut state,
+        )
+        .unwrap();
+
+        // The clip RoD matches the data window of the input. The
+        // basic plugin's RoD is the Source RoD expanded by (1, 2, 3, 4).
+        let rod =
+            get_rod("instance1", (25.0, 14.0), &HashMap::new(), &mut state).unwrap();
+        assert_eq!((rod.x1, rod.y1, rod.x2, rod.y2), (-4.0, 0.0, 25.0, 20.0));
+    }
+
+    #[test]
+    fn default_project_from_display_window() {
+        let _lock = COMMAND_MUTEX.lock().unwrap();
+
+        let mut state = set_up_basic_plugin();
+        let writer = CaptureWriter {
+            images: Default::default(),
+        };
+        let inputs = HashMap::from([(
+            "Source".to_string(),
+            Input {
+                source: InputSource::File {
+                    filename: "test/colorbars.exr".to_string(),
+                    format: None,
+                    channels: None,
+                },
+                rowbytes: None,
+                origin: None,
+            },
+        )]);
+        render(
+            "instance1",
+            &inputs,
+            &writer,
+            None,
+            &RenderOptions::default(),
+            &mut state,
+        )
+        .unwrap();
+
+        // Without a layout, the project and output are the input's HD
+        // display window, not its 25x14 data window
+        let coords = |r: OfxRectI| (r.x1, r.y1, r.x2, r.y2);
+        let images = writer.images.lock().unwrap();
+        let (_, image) = &images[0];
+        assert_eq!(image.display_window.map(coords), Some((0, 0, 1920, 1080)));
+        assert_eq!(coords(image.bounds), (0, 0, 1920, 1080));
+    }
+
+    #[test]
+    fn exr_layers() {
+        let path = path::PathBuf::from(env!("OUT_DIR")).join("layers.exr");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        // Parts share a display window, which defaults to the bounds
+        let read_without_display_window = |name, filename| {
+            let mut image = read_exr(name, filename, None, None).unwrap();
+            image.display_window = None;
+            image
+        };
+        let rgba = || read_without_display_window("rgba", "test/colorbars.exr");
+        let alpha = || read_without_display_window("alpha", "test/alpha-bars.exr");
+
+        image_io::write_exr_layer(path, "diffuse", rgba()).unwrap();
+        image_io::write_exr_layer(path, "mask", alpha()).unwrap();
+        // Replaces the existing part
+        image_io::write_exr_layer(path, "diffuse", rgba()).unwrap();
+        let parts = exr::prelude::read_all_flat_layers_from_file(path).unwrap();
+        assert_eq!(parts.layer_data.len(), 2);
+
+        // A part with a different display window would move the
+        // existing parts
+        let mut other = alpha();
+        other.display_window = Some(OfxRectI {
+            x1: 0,
+            y1: 0,
+            x2: 100,
+            y2: 50,
+        });
+        let err = image_io::write_exr_layer(path, "other", other).unwrap_err();
+        assert!(err.to_string().contains("25x14 display window"));
+
+        let read = |spec: &str| {
+            let spec: ChannelSpec = serde_json::from_value(spec.into()).unwrap();
+            image_io::read_image("image", path, None, Some(&spec), None, None)
+        };
+        let pixels = |image: Image| -> Vec<[f32; 4]> {
+            match image.pixels {
+                ImagePixels::Rgba(p) => p.iter().map(|p| [p.r, p.g, p.b, p.a]).collect(),
+                ImagePixels::Alpha(p) => p.iter().map(|a| [0.0, 0.0, 0.0, *a]).collect(),
+            }
+        };
+
+        assert_eq!(pixels(read("diffuse.R,G,B,A").unwrap()), pixels(rgba()));
+        let mask = read("mask.A").unwrap();
+        assert!(matches!(mask.pixels, ImagePixels::Alpha(_)));
+        assert_eq!(pixels(mask), pixels(alpha()));
+        // Single channel from an RGBA layer is read as alpha
+        assert!(matches!(
+            read("diffuse.G").unwrap().pixels,
+            ImagePixels::Alpha(_)
+        ));
+        assert!(read("specular.R,G,B").is_err());
+    }
+
+    #[test]
+    fn parse_channel_spec() {
+        let parse = |s: &str| serde_json::from_value::<ChannelSpec>(s.into());
+        let spec = parse("diffuse.R,G,B,A").unwrap();
+        assert_eq!(spec.layer.as_deref(), Some("diffuse"));
+        assert_eq!(spec.channels, ["R", "G", "B", "A"]);
+        let spec = parse("beauty.diffuse.Z").unwrap();
+        assert_eq!(spec.layer.as_deref(), Some("beauty.diffuse"));
+        assert_eq!(spec.channels, ["Z"]);
+        let spec = parse("Y").unwrap();
+        assert!(spec.layer.is_none());
+        assert!(parse("R,G,B,A,Z").is_err());
+        assert!(parse("diffuse.R,,B").is_err());
+    }
+
+    #[test]
+    fn expand_output_template() {
+        let values = TemplateValues {
+            instance: "blur",
+            plugin: "org.example.blur",
+            ext: "exr",
+            frame: 7,
+            frame_padding: 3,
+            bounds: OfxRectI {
+                x1: -2,
+                y1: -2,
+                x2: 27,
+                y2: 16,
+            },
+            project: OfxRectI {
+                x1: 0,
+                y1: 0,
+                x2: 25,
+                y2: 14,
+            },
+        };
+        let expand = |t: &str| template::expand(t, &values);
+
+        assert_eq!(
+            expand("renders/{instance}_{frame:04}.{ext}").unwrap(),
+            "renders/blur_0007.exr"
+        );
+        assert_eq!(expand("{frame}").unwrap(), "007");
+        assert_eq!(
+            expand("{plugin}/{project_width}x{project_height}_{x1}_{width}").unwrap(),
+            "org.example.blur/25x14_-2_29"
+        );
+        assert_eq!(expand("{{literal}}").unwrap(), "{literal}");
+        assert!(expand("{unknown}").is_err());
+        assert!(expand("{instance:04}").is_err());
+        assert!(expand("{frame:x}").is_err());
+        assert!(expand("{frame").is_err());
+        assert!(expand("frame}").is_err());
+    }
+
+    #[test]
+    fn file_writer_paths() {
+        let bounds = OfxRectI {
+            x1: 0,
+            y1: 0,
+            x2: 10,
+            y2: 10,
+        };
+        let options = OutputOptions {
+            output_directory: Some("out".to_string()),
+            ..Default::default()
+        };
+        let writer = FileWriter::new(&options, "blur", "org.example.blur", 100).unwrap();
+        assert_eq!(
+            writer.path(3, &bounds, None).unwrap().as_deref(),
+            Some("out/003.exr")
+        );
+
+        // Fixed padding, format from the template's extension
+        let options = OutputOptions {
+            output_directory: Some("out".to_string()),
+            template: Some("{instance}_{frame}.png".to_string()),
+            frame_padding: Some(5),
+            ..Default::default()
+        };
+        let writer = FileWriter::new(&options, "blur", "org.example.blur", 100).unwrap();
+        assert_eq!(writer.format, ImageFormat::Png);
+        assert_eq!(
+            writer.path(3, &bounds, None).unwrap().as_deref(),
+            Some("out/blur_00003.png")
+        );
+
+        // Nothing is written without a directory or template
+        let writer =
+            FileWriter::new(&OutputOptions::default(), "blur", "plugin", 100).unwrap();
+        assert!(writer.path(3, &bounds, None).unwrap().is_none());
+
+        let options = OutputOptions {
+            template: Some("{bad}.exr".to_string()),
+            ..Default::default()
+        };
+        assert!(FileWriter::new(&options, "blur", "plugin", 100).is_err());
+    }
+
+    #[test]
+    fn image_format_errors() {
+        let image = read_exr("image", "test/colorbars.exr", None, None).unwrap();
+        let out_dir = path::PathBuf::from(env!("OUT_DIR"));
+        let path = out_dir.join("float.png");
+        assert!(image_io::write_image(
+            path.to_str().unwrap(),
+            None,
+            Some(SampleDepth::F32),
+            image
+        )
+        .is_err());
+        assert!(image_io::read_image(
+            "image",
+            "test/colorbars.jpg",
+            None,
+            None,
+            None,
+            None
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn compare_mismatched_components() {
+        let err = compare::compare_images(
+            "test/colorbars.exr",
+            "test/alpha-bars.exr",
+            CompareMetric::MaxAbsError,
+            0.0,
+            0.0,
+            None,
+            None,
+        )
+        .unwrap_err();
+        assert!(err.downcast_ref::<ComparisonFailed>().is_some());
+    }
+
     #[test]
     fn get_rod_basic() {
         let mut state = set_up_basic_plugin();
@@ -0,0 +1,106 @@
+//! Checks for values in rendered images which are almost always bugs:
+//! NaN, infinity, negative alpha, and colour greater than alpha in
+//! premultiplied images. These often go unnoticed until something
+//! downstream uses the image.
+use crate::commands::{CheckLevel, OutputChecks};
+use crate::compare::ComparisonFailed;
+use crate::{output, GenericResult, Image, ImagePixels, PropertyValue, Rect};
+use openfx_rs::constants;
+
+// Colour may exceed alpha by this much, relative to alpha, before the
+// premultiplication check fails. Allows for rounding in the plugin's
+// arithmetic.
+const PREMULTIPLICATION_TOLERANCE: f32 = 1e-5;
+
+// Number of pixels which failed a check, and the first one
+#[derive(Default)]
+struct Failures {
+    count: usize,
+    first: Option<(i32, i32)>,
+}
+
+impl Failures {
+    fn add(&mut self, failed: bool, x: i32, y: i32) {
+        if failed {
+            self.count += 1;
+            self.first.get_or_insert((x, y));
+        }
+    }
+}
+
+// The output image's premultiplication comes from the plugin's clip
+// preferences. Alpha-only images have no colour to check.
+fn is_premultiplied(image: &Image) -> bool {
+    !matches!(image.pixels, ImagePixels::Alpha(_))
+        && matches!(
+            image
+                .properties
+                .lock()
+                .get(constants::ImageEffectPropPreMultiplication, 0),
+            Ok(PropertyValue::String(s))
+                if s.to_bytes() == constants::ImagePreMultiplied.as_str().as_bytes()
+        )
+}
+
+/// Check a rendered frame. Failures of warning checks are written to
+/// the output, and failures of error checks return ComparisonFailed.
+pub fn check_output(image: &Image, checks: &OutputChecks, frame: u32) -> GenericResult {
+    let mut nan = Failures::default();
+    let mut infinity = Failures::default();
+    let mut negative_alpha = Failures::default();
+    let mut premultiplication = Failures::default();
+    let premultiplied = is_premultiplied(image);
+
+    let width = image.bounds.width();
+    for y in 0..image.bounds.height() {
+        for x in 0..width {
+            let index = y * image.stride + x;
+            let (colour, alpha) = match &image.pixels {
+                ImagePixels::Rgba(v) => {
+                    let p = &v[index];
+                    ([p.r, p.g, p.b], p.a)
+                }
+                ImagePixels::Alpha(v) => ([0.0; 3], v[index]),
+            };
+            let (x, y) = (image.bounds.x1 + x as i32, image.bounds.y1 + y as i32);
+            let samples = colour.iter().chain([&alpha]);
+            nan.add(samples.clone().any(|s| s.is_nan()), x, y);
+            infinity.add(samples.clone().any(|s| s.is_infinite()), x, y);
+            negative_alpha.add(alpha < 0.0, x, y);
+            let limit = alpha + PREMULTIPLICATION_TOLERANCE * alpha.abs().max(1.0);
+            premultiplication.add(
+                premultiplied && colour.iter().any(|&c| c > limit),
+                x,
+                y,
+            );
+        }
+    }
+
+    let mut errors = Vec::new();
+    for (level, failures, description) in [
+        (checks.nan, nan, "NaN"),
+        (checks.infinity, infinity, "infinity"),
+        (checks.negative_alpha, negative_alpha, "negative alpha"),
+        (
+            checks.premultiplication,
+            premultiplication,
+            "colour greater than alpha",
+        ),
+    ] {
+        let (Some(level), Some((x, y))) = (level, failures.first) else {
+            continue;
+        };
+        let message = format!(
+            "Frame {}: {} pixels with {}, first at ({}, {})",
+            frame, failures.count, description, x, y
+        );
+        match level {
+            CheckLevel::Warning => output!("Warning: {}", message),
+            CheckLevel::Error => errors.push(message),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(ComparisonFailed(errors.join("; ")).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,120 @@
+//! Audit of property lookups which fail. Plugins often ask for
+//! properties the runner doesn't provide, which is easy to miss since
+//! the lookup just returns an error status. In audit mode each failed
+//! lookup is recorded, and a summary is printed at the end of the run.
+use crate::{crash, log_error};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Number of failed lookups, keyed by everything else in a
+// MissingProperty
+type Lookup = (String, Option<usize>, String, String, Problem);
+static LOOKUPS: Mutex<BTreeMap<Lookup, u32>> = Mutex::new(BTreeMap::new());
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Why a property lookup failed
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Problem {
+    Missing,
+    BadIndex,
+    Unset,
+    WrongType,
+}
+
+/// A failed property lookup
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct MissingProperty {
+    pub property: String,
+    // None for lookups which don't use an index, like propGetDimension
+    pub index: Option<usize>,
+    pub property_set: String,
+    pub action: String,
+    pub problem: Problem,
+    pub count: u32,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Report {
+    property_audit: Vec<MissingProperty>,
+}
+
+/// Record a failed lookup of a property, if auditing is enabled
+pub fn lookup_failed(
+    property: &str,
+    index: Option<usize>,
+    property_set: &str,
+    problem: Problem,
+) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let action = crash::current_action().unwrap_or("no action");
+    *LOOKUPS
+        .lock()
+        .unwrap()
+        .entry((
+            property.to_string(),
+            index,
+            property_set.to_string(),
+            action.to_string(),
+            problem,
+        ))
+        .or_default() += 1;
+}
+
+/// Failed lookups recorded so far, without duplicates
+pub fn missing_properties() -> Vec<MissingProperty> {
+    LOOKUPS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(
+            |((property, index, property_set, action, problem), &count)| {
+                MissingProperty {
+                    property: property.clone(),
+                    index: *index,
+                    property_set: property_set.clone(),
+                    action: action.clone(),
+                    problem: *problem,
+                    count,
+                }
+            },
+        )
+        .collect()
+}
+
+/// Add the lookups from reports in the error output of another run,
+/// such as a test process. Other lines are ignored.
+pub fn add_reports(output: &str) {
+    let mut lookups = LOOKUPS.lock().unwrap();
+    for report in output
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Report>(line).ok())
+    {
+        for m in report.property_audit {
+            *lookups
+                .entry((m.property, m.index, m.property_set, m.action, m.problem))
+                .or_default() += m.count;
+        }
+    }
+}
+
+/// Print the failed lookups, if any, to the error stream so they stay
+/// out of the results
+pub fn report() {
+    let property_audit = missing_properties();
+    if !property_audit.is_empty() {
+        log_error!(
+            "{}",
+            serde_json::to_string(&Report { property_audit }).unwrap()
+        );
+    }
+}
@@ -0,0 +1,87 @@
+//! Read-only copies of input images. The pixel data passed to the
+//! plugin is copied into pages which are write-protected with
+//! mprotect, so a plugin which writes to a source image faults
+//! immediately. The crash handler reports which clip was written to.
+use crate::crash;
+use anyhow::Result;
+use std::ffi::{c_void, CString};
+
+/// Pixel data in read-only memory. The memory is unmapped when this is
+/// dropped.
+#[derive(Debug)]
+pub struct ReadOnlyBuffer {
+    ptr: *mut c_void,
+    len: usize,
+    // Kept here so the crash handler can use it
+    clip: CString,
+    slot: Option<usize>,
+}
+
+unsafe impl Send for ReadOnlyBuffer {}
+unsafe impl Sync for ReadOnlyBuffer {}
+
+impl ReadOnlyBuffer {
+    /// Copy data to a new read-only buffer, used by the given clip.
+    /// Only supported on Unix.
+    #[cfg(unix)]
+    pub fn new(data: &[u8], clip: &str) -> Result<Self> {
+        // Mapping zero bytes isn't allowed
+        let len = data.len().max(1);
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANON,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            anyhow::bail!(
+                "Mapping read-only buffer for {}: {}",
+                clip,
+                std::io::Error::last_os_error()
+            );
+        }
+        let mut buffer = Self {
+            ptr,
+            len,
+            clip: CString::new(clip)?,
+            slot: None,
+        };
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), ptr as *mut u8, data.len());
+            if libc::mprotect(ptr, len, libc::PROT_READ) != 0 {
+                anyhow::bail!(
+                    "Protecting read-only buffer for {}: {}",
+                    clip,
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+        buffer.slot = crash::register_read_only(ptr as usize, len, buffer.clip.as_ptr());
+        Ok(buffer)
+    }
+
+    #[cfg(not(unix))]
+    pub fn new(_data: &[u8], _clip: &str) -> Result<Self> {
+        anyhow::bail!("Read-only input images are only supported on Unix")
+    }
+
+    pub fn as_ptr(&self) -> *const c_void {
+        self.ptr
+    }
+}
+
+impl Drop for ReadOnlyBuffer {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            crash::unregister_read_only(slot);
+        }
+        #[cfg(unix)]
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
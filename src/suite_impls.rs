@@ -1,9 +1,13 @@
 #![allow(non_snake_case)]
+use crate::crash::suite_call;
 use crate::handles::{ToHandle, WithObject};
+use crate::property_audit::{self, Problem};
 use crate::{handles::*, Clip};
 use crate::{
-    log_error, output, FromProperty, OfxError, ParamValue, PropertySet, PropertyValue,
+    log_error, output, FromProperty, OfxError, Param, ParamValue, PropertySet,
+    PropertyValue,
 };
+use crate::{trace, unsupported};
 
 #[cfg(target_os = "windows")]
 use libc::{free, malloc};
@@ -24,26 +28,40 @@ use openfx_sys::{
 use std::collections::HashMap;
 use std::ffi::{c_char, c_double, c_int, c_uint, c_void, CStr};
 
+/// Return a handle through a pointer passed to a suite function,
+/// and record it in the trace
+unsafe fn return_handle<H: Handle, T: From<H>>(pointer: *mut T, handle: H) {
+    trace::handle_out(&handle);
+    unsafe { *pointer = handle.into() }
+}
+
 // ========= ImageEffectSuite =========
 extern "C" fn getPropertySet(
     imageEffect: openfx_rs::types::OfxImageEffectHandle,
     propHandle: *mut openfx_rs::types::OfxPropertySetHandle,
 ) -> OfxStatus {
-    unsafe {
-        *propHandle =
-            imageEffect.with_object(|effect| effect.properties.to_handle().into())
-    };
-    ofxstatus::OK
+    suite_call!("getPropertySet", {
+        imageEffect
+            .try_with_object(|effect| unsafe {
+                return_handle(propHandle, effect.properties.to_handle());
+                ofxstatus::OK
+            })
+            .unwrap_or_else(|status| status)
+    })
 }
 
 extern "C" fn getParamSet(
     imageEffect: openfx_rs::types::OfxImageEffectHandle,
     paramSet: *mut openfx_rs::types::OfxParamSetHandle,
 ) -> OfxStatus {
-    unsafe {
-        *paramSet = imageEffect.with_object(|effect| effect.param_set.to_handle().into())
-    };
-    ofxstatus::OK
+    suite_call!("getParamSet", {
+        imageEffect
+            .try_with_object(|effect| unsafe {
+                return_handle(paramSet, effect.param_set.to_handle());
+                ofxstatus::OK
+            })
+            .unwrap_or_else(|status| status)
+    })
 }
 
 extern "C" fn clipDefine(
@@ -51,19 +69,23 @@ extern "C" fn clipDefine(
     name: *const c_char,
     propertySet: *mut openfx_rs::types::OfxPropertySetHandle,
 ) -> OfxStatus {
-    let props = imageEffect.with_object(|effect| {
-        effect
-            .create_clip(unsafe { OfxStr::from_ptr(name) })
-            .lock()
-            .properties
-            .clone()
-    });
-    if !propertySet.is_null() {
-        unsafe {
-            *propertySet = props.to_handle().into();
+    suite_call!("clipDefine", {
+        let Ok(props) = imageEffect.try_with_object(|effect| {
+            effect
+                .create_clip(unsafe { OfxStr::from_ptr(name) })
+                .lock()
+                .properties
+                .clone()
+        }) else {
+            return ofxstatus::ErrBadHandle;
+        };
+        if !propertySet.is_null() {
+            unsafe {
+                return_handle(propertySet, props.to_handle());
+            }
         }
-    }
-    ofxstatus::OK
+        ofxstatus::OK
+    })
 }
 
 #[allow(unused_variables)]
@@ -73,21 +95,25 @@ extern "C" fn clipGetHandle(
     clip: *mut openfx_rs::types::OfxImageClipHandle,
     propertySet: *mut openfx_rs::types::OfxPropertySetHandle,
 ) -> OfxStatus {
-    imageEffect
-        .with_object(|effect| {
-            if let Some(c) = effect.clips.get(unsafe { OfxStr::from_ptr(name) }.as_str())
-            {
-                unsafe {
-                    *clip = c.to_handle().into();
-                    if !propertySet.is_null() {
-                        *propertySet = c.lock().properties.to_handle().into();
+    suite_call!("clipGetHandle", {
+        imageEffect
+            .try_with_object(|effect| {
+                if let Some(c) =
+                    effect.clips.get(unsafe { OfxStr::from_ptr(name) }.as_str())
+                {
+                    unsafe {
+                        return_handle(clip, c.to_handle());
+                        if !propertySet.is_null() {
+                            return_handle(propertySet, c.lock().properties.to_handle());
+                        }
                     }
+                    ofxstatus::OK
+                } else {
+                    ofxstatus::ErrUnknown
                 }
-                ofxstatus::OK
-            } else {
-                ofxstatus::ErrUnknown
-            }
-        })
+            })
+            .unwrap_or_else(|status| status)
+    })
 }
 
 #[allow(unused_variables)]
@@ -95,11 +121,13 @@ extern "C" fn clipGetPropertySet(
     clip: openfx_rs::types::OfxImageClipHandle,
     propHandle: *mut openfx_rs::types::OfxPropertySetHandle,
 ) -> OfxStatus {
-    clip.with_object(|c| {
-        let handle = c.properties.to_handle().into();
-        unsafe { *propHandle = handle }
-    });
-    ofxstatus::OK
+    suite_call!("clipGetPropertySet", {
+        clip.try_with_object(|c| {
+            unsafe { return_handle(propHandle, c.properties.to_handle()) }
+            ofxstatus::OK
+        })
+        .unwrap_or_else(|status| status)
+    })
 }
 
 #[allow(unused_variables)]
@@ -109,15 +137,18 @@ extern "C" fn clipGetImage(
     _region: *const OfxRectD,
     imageHandle: *mut openfx_rs::types::OfxPropertySetHandle,
 ) -> OfxStatus {
-    clip.with_object(|c| {
-        if let Some(handle) = c.get_image_handle_at_time(time) {
-            unsafe {
-                *imageHandle = handle.into();
+    suite_call!("clipGetImage", {
+        clip.try_with_object(|c| {
+            if let Some(handle) = c.get_image_handle_at_time(time) {
+                unsafe {
+                    return_handle(imageHandle, handle);
+                }
+                ofxstatus::OK
+            } else {
+                ofxstatus::Failed
             }
-            ofxstatus::OK
-        } else {
-            ofxstatus::Failed
-        }
+        })
+        .unwrap_or_else(|status| status)
     })
 }
 
@@ -125,8 +156,9 @@ extern "C" fn clipGetImage(
 extern "C" fn clipReleaseImage(
     imageHandle: openfx_rs::types::OfxPropertySetHandle,
 ) -> OfxStatus {
-    Clip::release_image_handle(imageHandle.into());
-    ofxstatus::OK
+    suite_call!("clipReleaseImage", {
+        Clip::release_image_handle(imageHandle.into())
+    })
 }
 
 #[allow(unused_variables)]
@@ -135,21 +167,31 @@ extern "C" fn clipGetRegionOfDefinition(
     time: OfxTime,
     bounds: *mut OfxRectD,
 ) -> OfxStatus {
-    clip.with_object(|c| {
-        if let Some(rod) = c.region_of_definition {
-            unsafe {
-                *bounds = rod;
+    suite_call!("clipGetRegionOfDefinition", {
+        clip.try_with_object(|c| {
+            if let Some(rod) = c.region_of_definition {
+                unsafe {
+                    *bounds = rod;
+                }
+                ofxstatus::OK
+            } else {
+                ofxstatus::Failed
             }
-            ofxstatus::OK
-        } else {
-            ofxstatus::Failed
-        }
+        })
+        .unwrap_or_else(|status| status)
     })
 }
 
-#[allow(unused_variables)]
 extern "C" fn abort(imageEffect: openfx_rs::types::OfxImageEffectHandle) -> c_int {
-    0
+    suite_call!("abort");
+    // Only returns true when a simulated abort has been configured
+    // for the current render
+    imageEffect
+        .try_with_object(|effect| match effect.abort.as_ref() {
+            Some(state) if state.lock().check() => 1,
+            _ => 0,
+        })
+        .unwrap_or(0)
 }
 
 #[allow(unused_variables)]
@@ -158,41 +200,45 @@ extern "C" fn imageMemoryAlloc(
     nBytes: usize,
     memoryHandle: *mut openfx_rs::types::OfxImageMemoryHandle,
 ) -> OfxStatus {
-    unsafe {
-        // Allocate memory directly and return the pointer as a
-        // handle.
-
-        // 16-byte alignment is required by the spec, but Windows
-        // doesn't have posix_memalign so use regular malloc for now
-        #[cfg(target_os = "windows")]
-        {
-            let ptr: *mut c_void = malloc(nBytes);
-            if ptr.is_null() {
-                return OfxStatus::ErrMemory;
+    suite_call!("imageMemoryAlloc", {
+        unsafe {
+            // Allocate memory directly and return the pointer as a
+            // handle.
+
+            // 16-byte alignment is required by the spec, but Windows
+            // doesn't have posix_memalign so use regular malloc for now
+            #[cfg(target_os = "windows")]
+            {
+                let ptr: *mut c_void = malloc(nBytes);
+                if ptr.is_null() {
+                    return OfxStatus::ErrMemory;
+                }
+                *memoryHandle = ptr.into();
             }
-            *memoryHandle = ptr.into();
-        }
 
-        #[cfg(not(target_os = "windows"))]
-        {
-            let mut ptr: *mut c_void = std::ptr::null_mut();
-            if posix_memalign(&mut ptr, 16, nBytes) != 0 {
-                return ofxstatus::ErrMemory;
+            #[cfg(not(target_os = "windows"))]
+            {
+                let mut ptr: *mut c_void = std::ptr::null_mut();
+                if posix_memalign(&mut ptr, 16, nBytes) != 0 {
+                    return ofxstatus::ErrMemory;
+                }
+                *memoryHandle = openfx_rs::types::OfxImageMemoryHandle(ptr as _);
             }
-            *memoryHandle = openfx_rs::types::OfxImageMemoryHandle(ptr as _);
-        }
-    };
-    ofxstatus::OK
+        };
+        ofxstatus::OK
+    })
 }
 
 #[allow(unused_variables)]
 extern "C" fn imageMemoryFree(
     memoryHandle: openfx_rs::types::OfxImageMemoryHandle,
 ) -> OfxStatus {
-    unsafe {
-        free(memoryHandle.0 as _);
-    };
-    ofxstatus::OK
+    suite_call!("imageMemoryFree", {
+        unsafe {
+            free(memoryHandle.0 as _);
+        };
+        ofxstatus::OK
+    })
 }
 
 #[allow(unused_variables)]
@@ -200,19 +246,23 @@ extern "C" fn imageMemoryLock(
     memoryHandle: openfx_rs::types::OfxImageMemoryHandle,
     returnedPtr: *mut *mut c_void,
 ) -> OfxStatus {
-    // The handle is already a pointer to allocated memory, just
-    // return it
-    unsafe {
-        *returnedPtr = memoryHandle.0 as _;
-    }
-    ofxstatus::OK
+    suite_call!("imageMemoryLock", {
+        // The handle is already a pointer to allocated memory, just
+        // return it
+        unsafe {
+            *returnedPtr = memoryHandle.0 as _;
+        }
+        ofxstatus::OK
+    })
 }
 #[allow(unused_variables)]
 extern "C" fn imageMemoryUnlock(
     memoryHandle: openfx_rs::types::OfxImageMemoryHandle,
 ) -> OfxStatus {
-    // Nothing to do
-    ofxstatus::OK
+    suite_call!("imageMemoryUnlock", {
+        // Nothing to do
+        ofxstatus::OK
+    })
 }
 
 pub const IMAGE_EFFECT_SUITE: OfxImageEffectSuiteV1 = OfxImageEffectSuiteV1 {
@@ -238,14 +288,15 @@ fn set_property(
     index: c_int,
     value: PropertyValue,
 ) -> OfxStatus {
-    properties.with_object(|props| {
-        props.set(
-            unsafe { OfxStr::from_ptr(name) }.as_str(),
-            index as usize,
-            value,
-        )
-    });
-    ofxstatus::OK
+    let key = unsafe { OfxStr::from_ptr(name) };
+    trace::property(key.as_str(), Some(index as usize));
+    trace::value_in(&value);
+    properties
+        .try_with_object(|props| {
+            props.set(key.as_str(), index as usize, value);
+            ofxstatus::OK
+        })
+        .unwrap_or_else(|status| status)
 }
 
 fn set_property_n<T: Into<PropertyValue> + Copy>(
@@ -256,7 +307,10 @@ fn set_property_n<T: Into<PropertyValue> + Copy>(
 ) -> OfxStatus {
     let s = unsafe { std::slice::from_raw_parts(value, count as usize) };
     for (i, v) in s.iter().enumerate() {
-        set_property(properties, name, i as i32, (*v).into());
+        let status = set_property(properties, name, i as i32, (*v).into());
+        if status != ofxstatus::OK {
+            return status;
+        }
     }
     ofxstatus::OK
 }
@@ -267,7 +321,9 @@ extern "C" fn propSetPointer(
     index: c_int,
     value: *mut c_void,
 ) -> OfxStatus {
-    set_property(properties, property, index, value.into())
+    suite_call!("propSetPointer", {
+        set_property(properties, property, index, value.into())
+    })
 }
 
 extern "C" fn propSetString(
@@ -276,7 +332,9 @@ extern "C" fn propSetString(
     index: c_int,
     value: *const c_char,
 ) -> OfxStatus {
-    set_property(properties, property, index, value.into())
+    suite_call!("propSetString", {
+        set_property(properties, property, index, value.into())
+    })
 }
 
 extern "C" fn propSetDouble(
@@ -285,7 +343,9 @@ extern "C" fn propSetDouble(
     index: c_int,
     value: c_double,
 ) -> OfxStatus {
-    set_property(properties, property, index, value.into())
+    suite_call!("propSetDouble", {
+        set_property(properties, property, index, value.into())
+    })
 }
 
 extern "C" fn propSetInt(
@@ -294,7 +354,9 @@ extern "C" fn propSetInt(
     index: c_int,
     value: c_int,
 ) -> OfxStatus {
-    set_property(properties, property, index, value.into())
+    suite_call!("propSetInt", {
+        set_property(properties, property, index, value.into())
+    })
 }
 
 #[allow(unused_variables)]
@@ -304,7 +366,9 @@ extern "C" fn propSetPointerN(
     count: c_int,
     value: *const *mut c_void,
 ) -> OfxStatus {
-    set_property_n(properties, property, count, value)
+    suite_call!("propSetPointerN", {
+        set_property_n(properties, property, count, value)
+    })
 }
 
 #[allow(unused_variables)]
@@ -314,7 +378,9 @@ extern "C" fn propSetStringN(
     count: c_int,
     value: *const *const c_char,
 ) -> OfxStatus {
-    set_property_n(properties, property, count, value)
+    suite_call!("propSetStringN", {
+        set_property_n(properties, property, count, value)
+    })
 }
 
 #[allow(unused_variables)]
@@ -324,7 +390,9 @@ extern "C" fn propSetDoubleN(
     count: c_int,
     value: *const c_double,
 ) -> OfxStatus {
-    set_property_n(properties, property, count, value)
+    suite_call!("propSetDoubleN", {
+        set_property_n(properties, property, count, value)
+    })
 }
 
 #[allow(unused_variables)]
@@ -334,7 +402,9 @@ extern "C" fn propSetIntN(
     count: c_int,
     value: *const c_int,
 ) -> OfxStatus {
-    set_property_n(properties, property, count, value)
+    suite_call!("propSetIntN", {
+        set_property_n(properties, property, count, value)
+    })
 }
 
 fn get_property<T: FromProperty>(
@@ -343,26 +413,49 @@ fn get_property<T: FromProperty>(
     key: OfxStr,
     index: usize,
 ) -> OfxError {
-    let r = props.get(key, index).and_then(|p| {
-        if let Some(v) = FromProperty::from_property(p) {
-            unsafe { *value = v };
-            Ok(())
-        } else {
-            match p {
-                PropertyValue::Unset => Err(OfxError {
-                    message: format!("{} {} not set in {}", key, index, props.name),
-                    status: ofxstatus::ErrUnknown,
-                }),
-                _ => Err(OfxError {
-                    message: format!(
-                        "{} {} unexpected type: {:?} in {}",
-                        key, index, p, props.name
-                    ),
-                    status: ofxstatus::ErrUnknown,
-                }),
+    trace::property(key.as_str(), Some(index));
+    let audit = |problem| {
+        property_audit::lookup_failed(key.as_str(), Some(index), &props.name, problem)
+    };
+    let r = props
+        .get(key, index)
+        .inspect_err(|e| {
+            audit(if e.status == ofxstatus::ErrBadIndex {
+                Problem::BadIndex
+            } else {
+                Problem::Missing
+            })
+        })
+        .and_then(|p| {
+            if let Some(v) = FromProperty::from_property(p) {
+                unsafe { *value = v };
+                trace::value_out(p);
+                Ok(())
+            } else {
+                match p {
+                    PropertyValue::Unset => {
+                        audit(Problem::Unset);
+                        Err(OfxError {
+                            message: format!(
+                                "{} {} not set in {}",
+                                key, index, props.name
+                            ),
+                            status: ofxstatus::ErrUnknown,
+                        })
+                    }
+                    _ => {
+                        audit(Problem::WrongType);
+                        Err(OfxError {
+                            message: format!(
+                                "{} {} unexpected type: {:?} in {}",
+                                key, index, p, props.name
+                            ),
+                            status: ofxstatus::ErrUnknown,
+                        })
+                    }
+                }
             }
-        }
-    });
+        });
 
     match r {
         Ok(_) => OfxError::ok(),
@@ -391,16 +484,19 @@ extern "C" fn propGetPointer(
     index: c_int,
     value: *mut *mut c_void,
 ) -> OfxStatus {
-    properties
-        .with_object(|props| {
-            get_property(
-                value,
-                props,
-                unsafe { OfxStr::from_ptr(property) },
-                index as usize,
-            )
-            .check_status("propGetPointer: ")
-        })
+    suite_call!("propGetPointer", {
+        properties
+            .try_with_object(|props| {
+                get_property(
+                    value,
+                    props,
+                    unsafe { OfxStr::from_ptr(property) },
+                    index as usize,
+                )
+                .check_status("propGetPointer: ")
+            })
+            .unwrap_or_else(|status| status)
+    })
 }
 
 extern "C" fn propGetString(
@@ -409,16 +505,19 @@ extern "C" fn propGetString(
     index: c_int,
     value: *mut *mut c_char,
 ) -> OfxStatus {
-    properties
-        .with_object(|props| {
-            get_property(
-                value,
-                props,
-                unsafe { OfxStr::from_ptr(property) },
-                index as usize,
-            )
-            .check_status("propGetString: ")
-        })
+    suite_call!("propGetString", {
+        properties
+            .try_with_object(|props| {
+                get_property(
+                    value,
+                    props,
+                    unsafe { OfxStr::from_ptr(property) },
+                    index as usize,
+                )
+                .check_status("propGetString: ")
+            })
+            .unwrap_or_else(|status| status)
+    })
 }
 
 extern "C" fn propGetDouble(
@@ -427,16 +526,19 @@ extern "C" fn propGetDouble(
     index: c_int,
     value: *mut c_double,
 ) -> OfxStatus {
-    properties
-        .with_object(|props| {
-            get_property(
-                value,
-                props,
-                unsafe { OfxStr::from_ptr(property) },
-                index as usize,
-            )
-            .check_status("propGetDouble: ")
-        })
+    suite_call!("propGetDouble", {
+        properties
+            .try_with_object(|props| {
+                get_property(
+                    value,
+                    props,
+                    unsafe { OfxStr::from_ptr(property) },
+                    index as usize,
+                )
+                .check_status("propGetDouble: ")
+            })
+            .unwrap_or_else(|status| status)
+    })
 }
 
 extern "C" fn propGetInt(
@@ -445,16 +547,19 @@ extern "C" fn propGetInt(
     index: c_int,
     value: *mut c_int,
 ) -> OfxStatus {
-    properties
-        .with_object(|props| {
-            get_property(
-                value,
-                props,
-                unsafe { OfxStr::from_ptr(property) },
-                index as usize,
-            )
-            .check_status("propGetInt: ")
-        })
+    suite_call!("propGetInt", {
+        properties
+            .try_with_object(|props| {
+                get_property(
+                    value,
+                    props,
+                    unsafe { OfxStr::from_ptr(property) },
+                    index as usize,
+                )
+                .check_status("propGetInt: ")
+            })
+            .unwrap_or_else(|status| status)
+    })
 }
 
 #[allow(unused_variables)]
@@ -464,16 +569,19 @@ extern "C" fn propGetPointerN(
     count: c_int,
     value: *mut *mut c_void,
 ) -> OfxStatus {
-    properties
-        .with_object(|props| {
-            get_property_array(
-                value,
-                props,
-                unsafe { OfxStr::from_ptr(property) },
-                count as usize,
-            )
-            .check_status("propGetPointerN: ")
-        })
+    suite_call!("propGetPointerN", {
+        properties
+            .try_with_object(|props| {
+                get_property_array(
+                    value,
+                    props,
+                    unsafe { OfxStr::from_ptr(property) },
+                    count as usize,
+                )
+                .check_status("propGetPointerN: ")
+            })
+            .unwrap_or_else(|status| status)
+    })
 }
 
 #[allow(unused_variables)]
@@ -483,16 +591,19 @@ extern "C" fn propGetStringN(
     count: c_int,
     value: *mut *mut c_char,
 ) -> OfxStatus {
-    properties
-        .with_object(|props| {
-            get_property_array(
-                value,
-                props,
-                unsafe { OfxStr::from_ptr(property) },
-                count as usize,
-            )
-            .check_status("propGetStringN: ")
-        })
+    suite_call!("propGetStringN", {
+        properties
+            .try_with_object(|props| {
+                get_property_array(
+                    value,
+                    props,
+                    unsafe { OfxStr::from_ptr(property) },
+                    count as usize,
+                )
+                .check_status("propGetStringN: ")
+            })
+            .unwrap_or_else(|status| status)
+    })
 }
 
 #[allow(unused_variables)]
@@ -502,16 +613,19 @@ extern "C" fn propGetDoubleN(
     count: c_int,
     value: *mut c_double,
 ) -> OfxStatus {
-    properties
-        .with_object(|props| {
-            get_property_array(
-                value,
-                props,
-                unsafe { OfxStr::from_ptr(property) },
-                count as usize,
-            )
-            .check_status("propGetDoubleN: ")
-        })
+    suite_call!("propGetDoubleN", {
+        properties
+            .try_with_object(|props| {
+                get_property_array(
+                    value,
+                    props,
+                    unsafe { OfxStr::from_ptr(property) },
+                    count as usize,
+                )
+                .check_status("propGetDoubleN: ")
+            })
+            .unwrap_or_else(|status| status)
+    })
 }
 
 #[allow(unused_variables)]
@@ -521,16 +635,19 @@ extern "C" fn propGetIntN(
     count: c_int,
     value: *mut c_int,
 ) -> OfxStatus {
-    properties
-        .with_object(|props| {
-            get_property_array(
-                value,
-                props,
-                unsafe { OfxStr::from_ptr(property) },
-                count as usize,
-            )
-            .check_status("propGetIntN: ")
-        })
+    suite_call!("propGetIntN", {
+        properties
+            .try_with_object(|props| {
+                get_property_array(
+                    value,
+                    props,
+                    unsafe { OfxStr::from_ptr(property) },
+                    count as usize,
+                )
+                .check_status("propGetIntN: ")
+            })
+            .unwrap_or_else(|status| status)
+    })
 }
 
 #[allow(unused_variables)]
@@ -538,7 +655,7 @@ extern "C" fn propReset(
     properties: openfx_rs::types::OfxPropertySetHandle,
     property: *const c_char,
 ) -> OfxStatus {
-    panic!("Not implemented!");
+    suite_call!("propReset", { unsupported::call("propReset") })
 }
 
 extern "C" fn propGetDimension(
@@ -546,17 +663,28 @@ extern "C" fn propGetDimension(
     property: *const c_char,
     count: *mut c_int,
 ) -> OfxStatus {
-    let key = unsafe { OfxStr::from_ptr(property) };
-    properties
-        .with_object(|props| {
-            if let Some(values) = props.values.get(key.as_str()) {
-                unsafe { *count = values.0.len() as i32 }
-                ofxstatus::OK
-            } else {
-                log_error!("propGetDimension: {} not found in {}", key, props.name);
-                ofxstatus::ErrUnknown
-            }
-        })
+    suite_call!("propGetDimension", {
+        let key = unsafe { OfxStr::from_ptr(property) };
+        trace::property(key.as_str(), None);
+        properties
+            .try_with_object(|props| {
+                if let Some(values) = props.values.get(key.as_str()) {
+                    unsafe { *count = values.0.len() as i32 }
+                    trace::value_out(values.0.len());
+                    ofxstatus::OK
+                } else {
+                    log_error!("propGetDimension: {} not found in {}", key, props.name);
+                    property_audit::lookup_failed(
+                        key.as_str(),
+                        None,
+                        &props.name,
+                        Problem::Missing,
+                    );
+                    ofxstatus::ErrUnknown
+                }
+            })
+            .unwrap_or_else(|status| status)
+    })
 }
 
 pub const PROPERTY_SUITE: OfxPropertySuiteV1 = OfxPropertySuiteV1 {
@@ -588,13 +716,17 @@ extern "C" fn paramDefine(
     name: *const c_char,
     propertySet: *mut openfx_rs::types::OfxPropertySetHandle,
 ) -> OfxStatus {
-    let props = paramSet.with_object(|p| {
-        p.create_param(unsafe { OfxStr::from_ptr(paramType) }, unsafe {
-            OfxStr::from_ptr(name)
-        })
-    });
-    unsafe { *propertySet = props.into() }
-    ofxstatus::OK
+    suite_call!("paramDefine", {
+        let Ok(props) = paramSet.try_with_object(|p| {
+            p.create_param(unsafe { OfxStr::from_ptr(paramType) }, unsafe {
+                OfxStr::from_ptr(name)
+            })
+        }) else {
+            return ofxstatus::ErrBadHandle;
+        };
+        unsafe { *propertySet = props.into() }
+        ofxstatus::OK
+    })
 }
 
 #[allow(unused_variables)]
@@ -604,28 +736,38 @@ extern "C" fn paramGetHandle(
     param: *mut openfx_rs::types::OfxParamHandle,
     propertySet: *mut openfx_rs::types::OfxPropertySetHandle,
 ) -> OfxStatus {
-    paramSet
-        .with_object(|ps| {
-            if let Some(p) = ps.params.get(unsafe { OfxStr::from_ptr(name) }.as_str()) {
-                unsafe {
-                    *param = p.to_handle().into();
-                    if !propertySet.is_null() {
-                        *propertySet = p.lock().properties.to_handle().into();
+    suite_call!("paramGetHandle", {
+        paramSet
+            .try_with_object(|ps| {
+                if let Some(p) = ps.params.get(unsafe { OfxStr::from_ptr(name) }.as_str())
+                {
+                    unsafe {
+                        return_handle(param, p.to_handle());
+                        if !propertySet.is_null() {
+                            return_handle(propertySet, p.lock().properties.to_handle());
+                        }
                     }
+                    ofxstatus::OK
+                } else {
+                    ofxstatus::ErrUnknown
                 }
-                ofxstatus::OK
-            } else {
-                ofxstatus::ErrUnknown
-            }
-        })
+            })
+            .unwrap_or_else(|status| status)
+    })
 }
 
 extern "C" fn paramSetGetPropertySet(
     paramSet: openfx_rs::types::OfxParamSetHandle,
     propHandle: *mut openfx_rs::types::OfxPropertySetHandle,
 ) -> OfxStatus {
-    unsafe { *propHandle = paramSet.with_object(|p| p.properties.to_handle().into()) };
-    ofxstatus::OK
+    suite_call!("paramSetGetPropertySet", {
+        paramSet
+            .try_with_object(|p| unsafe {
+                return_handle(propHandle, p.properties.to_handle());
+                ofxstatus::OK
+            })
+            .unwrap_or_else(|status| status)
+    })
 }
 
 #[allow(unused_variables)]
@@ -633,10 +775,14 @@ extern "C" fn paramGetPropertySet(
     paramHandle: openfx_rs::types::OfxParamHandle,
     propHandle: *mut openfx_rs::types::OfxPropertySetHandle,
 ) -> OfxStatus {
-    paramHandle.with_object(|param| unsafe {
-        *propHandle = param.properties.to_handle().into();
-    });
-    ofxstatus::OK
+    suite_call!("paramGetPropertySet", {
+        paramHandle
+            .try_with_object(|param| unsafe {
+                return_handle(propHandle, param.properties.to_handle());
+                ofxstatus::OK
+            })
+            .unwrap_or_else(|status| status)
+    })
 }
 
 unsafe extern "C" {
@@ -658,164 +804,169 @@ unsafe extern "C" {
 pub extern "C" fn param_value_count(
     paramHandle: openfx_rs::types::OfxParamHandle,
 ) -> c_int {
+    // Called by paramGetValue and paramGetValueAtTime to read their
+    // arguments. Returns -1 for a bad handle, which is reported by
+    // the suite function.
     use ParamValue::*;
-    paramHandle.with_object(|p| match p.value {
-        Double2D(..) | Integer2D(..) => 2,
-        Rgb { .. } | Double3D(..) | Integer3D(..) => 3,
-        Rgba { .. } => 4,
-        Boolean(_) | Choice(_) | Custom(_) | Double(_) | Integer(_) | String(_) => 1,
-        Group | Page | Parametric | PushButton => 0,
-    })
-}
-
-#[unsafe(no_mangle)]
-pub extern "C" fn param_get_value_1(
-    paramHandle: openfx_rs::types::OfxParamHandle,
-    value: *mut c_void,
-) -> OfxStatus {
-    use ParamValue::*;
-    paramHandle.with_object(|p| match p.value {
-        Boolean(b) => unsafe { *(value as *mut c_int) = if b { 1 } else { 0 } },
-        Choice(index) => unsafe { *(value as *mut c_int) = index as c_int },
-        Custom(ref s) | String(ref s) => unsafe {
-            *(value as *mut *const c_char) = s.as_ptr()
-        },
-        Double(v) => unsafe { *(value as *mut c_double) = v },
-        Integer(v) => unsafe { *(value as *mut c_int) = v },
-        ref x => panic!("unexpected param value {:?}", x),
-    });
-    ofxstatus::OK
+    ParamHandle::from(paramHandle)
+        .try_as_arc()
+        .map(|p| match p.lock().value {
+            Double2D(..) | Integer2D(..) => 2,
+            Rgb { .. } | Double3D(..) | Integer3D(..) => 3,
+            Rgba { .. } => 4,
+            Boolean(_) | Choice(_) | Custom(_) | Double(_) | Integer(_) | String(_) => 1,
+            Group | Page | Parametric | PushButton => 0,
+        })
+        .unwrap_or(-1)
 }
 
 #[unsafe(no_mangle)]
-#[allow(unused_variables)]
-pub extern "C" fn param_get_value_2(
-    paramHandle: openfx_rs::types::OfxParamHandle,
-    value1: *mut c_void,
-    value2: *mut c_void,
-) -> OfxStatus {
-    use ParamValue::*;
-    paramHandle.with_object(|p| match p.value {
-        Double2D(x, y) => unsafe {
-            *(value1 as *mut c_double) = x;
-            *(value2 as *mut c_double) = y;
-        },
-        Integer2D(x, y) => unsafe {
-            *(value1 as *mut c_int) = x;
-            *(value2 as *mut c_int) = y;
-        },
-        ref x => panic!("unexpected param value {:?}", x),
-    });
-    ofxstatus::OK
+pub extern "C" fn param_get_type(
+    handle: openfx_rs::types::OfxParamHandle,
+) -> *const c_char {
+    // Called by paramSetValue and paramSetValueAtTime to read their
+    // arguments. Returns null for a bad handle or a missing type, which
+    // are reported by the suite function.
+    ParamHandle::from(handle)
+        .try_as_arc()
+        .ok()
+        .and_then(|p| param_type(&p.lock()))
+        .unwrap_or(std::ptr::null())
+}
+
+/// The param's OfxParamPropType, if it has one
+fn param_type(param: &Param) -> Option<*const c_char> {
+    match param.properties.lock().get(constants::ParamPropType, 0) {
+        Ok(PropertyValue::String(s)) => Some(s.as_c_str().as_ptr()),
+        _ => None,
+    }
 }
 
-#[unsafe(no_mangle)]
-pub extern "C" fn param_get_value_3(
-    paramHandle: openfx_rs::types::OfxParamHandle,
-    value1: *mut c_void,
-    value2: *mut c_void,
-    value3: *mut c_void,
-) -> OfxStatus {
-    use ParamValue::*;
-    paramHandle.with_object(|p| match p.value {
-        Double3D(x, y, z) => unsafe {
-            *(value1 as *mut c_double) = x;
-            *(value2 as *mut c_double) = y;
-            *(value3 as *mut c_double) = z;
-        },
-        Integer3D(x, y, z) => unsafe {
-            *(value1 as *mut c_int) = x;
-            *(value2 as *mut c_int) = y;
-            *(value3 as *mut c_int) = z;
-        },
-        Rgb(r, g, b) => unsafe {
-            *(value1 as *mut c_double) = r;
-            *(value2 as *mut c_double) = g;
-            *(value3 as *mut c_double) = b;
-        },
-        ref x => panic!("unexpected param value {:?}", x),
-    });
-    ofxstatus::OK
+/// Write values of the given type through pointers passed to
+/// paramGetValue
+unsafe fn write_values<T: Copy>(pointers: &[*mut c_void], values: &[T]) {
+    for (pointer, value) in pointers.iter().zip(values) {
+        unsafe { *(*pointer as *mut T) = *value }
+    }
 }
 
-#[unsafe(no_mangle)]
-pub extern "C" fn param_get_value_4(
-    paramHandle: openfx_rs::types::OfxParamHandle,
-    value1: *mut c_void,
-    value2: *mut c_void,
-    value3: *mut c_void,
-    value4: *mut c_void,
+/// Implementation of paramGetValue and paramGetValueAtTime. The first
+/// param_value_count() of the values are the pointers passed by the
+/// plugin.
+fn get_param_value(
+    handle: openfx_rs::types::OfxParamHandle,
+    values: *const [*mut c_void; 4],
 ) -> OfxStatus {
     use ParamValue::*;
-    paramHandle.with_object(|p| match p.value {
-        Rgba(r, g, b, a) => unsafe {
-            *(value1 as *mut c_double) = r;
-            *(value2 as *mut c_double) = g;
-            *(value3 as *mut c_double) = b;
-            *(value4 as *mut c_double) = a;
-        },
-        ref x => panic!("unexpected param value {:?}", x),
-    });
-    ofxstatus::OK
-}
-
-#[unsafe(no_mangle)]
-pub extern "C" fn param_get_type(
-    handle: openfx_rs::types::OfxParamHandle,
-) -> *const c_char {
-    handle.with_object(|p| {
-        if let Ok(PropertyValue::String(s)) =
-            p.properties.lock().get(constants::ParamPropType, 0)
-        {
-            s.as_c_str().as_ptr()
-        } else {
-            panic!("OfxParamPropType not found on param")
-        }
-    })
+    handle
+        .try_with_object(|p| {
+            let pointers = unsafe { &*values };
+            unsafe {
+                match p.value {
+                    Boolean(b) => write_values(pointers, &[b as c_int]),
+                    Choice(index) => write_values(pointers, &[index as c_int]),
+                    Custom(ref s) | String(ref s) => {
+                        write_values(pointers, &[s.as_ptr()])
+                    }
+                    Double(v) => write_values(pointers, &[v]),
+                    Integer(v) => write_values(pointers, &[v]),
+                    Double2D(x, y) => write_values(pointers, &[x, y]),
+                    Integer2D(x, y) => write_values(pointers, &[x, y]),
+                    Double3D(x, y, z) | Rgb(x, y, z) => {
+                        write_values(pointers, &[x, y, z])
+                    }
+                    Integer3D(x, y, z) => write_values(pointers, &[x, y, z]),
+                    Rgba(r, g, b, a) => write_values(pointers, &[r, g, b, a]),
+                    Group | Page | Parametric | PushButton => return ofxstatus::Failed,
+                }
+            }
+            trace::value_out(&p.value);
+            ofxstatus::OK
+        })
+        .unwrap_or_else(|status| status)
 }
 
 #[unsafe(no_mangle)]
-pub extern "C" fn param_set_value_boolean(
+pub extern "C" fn param_get_value(
     handle: openfx_rs::types::OfxParamHandle,
-    value: i32,
-) {
-    handle.with_object(|p| p.value = ParamValue::Boolean(value != 0));
+    values: *const [*mut c_void; 4],
+) -> OfxStatus {
+    suite_call!("paramGetValue", { get_param_value(handle, values) })
 }
 
 #[unsafe(no_mangle)]
-pub extern "C" fn param_set_value_integer(
+#[allow(unused_variables)]
+pub extern "C" fn param_get_value_at_time(
     handle: openfx_rs::types::OfxParamHandle,
-    value: i32,
-) {
-    handle.with_object(|p| p.value = ParamValue::Integer(value));
+    time: OfxTime,
+    values: *const [*mut c_void; 4],
+) -> OfxStatus {
+    suite_call!("paramGetValueAtTime", { get_param_value(handle, values) })
 }
 
-#[unsafe(no_mangle)]
-pub extern "C" fn param_set_value_choice(
+/// Implementation of paramSetValue and paramSetValueAtTime. The
+/// caller reads the argument matching the param type, leaving the
+/// others unset.
+fn set_param_value(
     handle: openfx_rs::types::OfxParamHandle,
-    value: i32,
-) {
-    handle.with_object(|p| p.value = ParamValue::Choice(value as usize));
+    int_value: c_int,
+    double_value: c_double,
+    string_value: *const c_char,
+) -> OfxStatus {
+    use ParamValue::*;
+    handle
+        .try_with_object(|p| {
+            // Without a type, the caller couldn't read the value
+            if param_type(p).is_none() {
+                log_error!(
+                    "{} not found in {}",
+                    constants::ParamPropType,
+                    p.properties.lock().name
+                );
+                return ofxstatus::Failed;
+            }
+            p.value = match p.value {
+                Boolean(_) => Boolean(int_value != 0),
+                Choice(_) => Choice(int_value as usize),
+                Double(_) => Double(double_value),
+                Integer(_) => Integer(int_value),
+                // Note: not using OfxStr here. String param values are
+                // stored as CString and don't need to be UTF-8
+                String(_) if !string_value.is_null() => {
+                    String(unsafe { CStr::from_ptr(string_value) }.into())
+                }
+                _ => return ofxstatus::Failed,
+            };
+            trace::value_in(&p.value);
+            ofxstatus::OK
+        })
+        .unwrap_or_else(|status| status)
 }
 
 #[unsafe(no_mangle)]
-pub extern "C" fn param_set_value_double(
+pub extern "C" fn param_set_value(
     handle: openfx_rs::types::OfxParamHandle,
-    value: f64,
-) {
-    handle.with_object(|p| p.value = ParamValue::Double(value));
+    int_value: c_int,
+    double_value: c_double,
+    string_value: *const c_char,
+) -> OfxStatus {
+    suite_call!("paramSetValue", {
+        set_param_value(handle, int_value, double_value, string_value)
+    })
 }
 
 #[unsafe(no_mangle)]
-pub extern "C" fn param_set_value_string(
+#[allow(unused_variables)]
+pub extern "C" fn param_set_value_at_time(
     handle: openfx_rs::types::OfxParamHandle,
-    value: *const c_char,
-) {
-    handle.with_object(|p| {
-        // Note: not using OfxStr here. String param values are stored
-        // as CString and don't need to be UTF-8
-        p.value = ParamValue::String(unsafe { CStr::from_ptr(value) }.into())
-    });
+    time: OfxTime,
+    int_value: c_int,
+    double_value: c_double,
+    string_value: *const c_char,
+) -> OfxStatus {
+    suite_call!("paramSetValueAtTime", {
+        set_param_value(handle, int_value, double_value, string_value)
+    })
 }
 
 #[allow(unused_variables)]
@@ -823,7 +974,7 @@ extern "C" fn paramGetNumKeys(
     paramHandle: openfx_rs::types::OfxParamHandle,
     numberOfKeys: *mut c_uint,
 ) -> OfxStatus {
-    panic!("Not implemented!")
+    suite_call!("paramGetNumKeys", { unsupported::call("paramGetNumKeys") })
 }
 
 #[allow(unused_variables)]
@@ -832,7 +983,7 @@ extern "C" fn paramGetKeyTime(
     nthKey: c_uint,
     time: *mut OfxTime,
 ) -> OfxStatus {
-    panic!("Not implemented!")
+    suite_call!("paramGetKeyTime", { unsupported::call("paramGetKeyTime") })
 }
 
 #[allow(unused_variables)]
@@ -842,7 +993,9 @@ extern "C" fn paramGetKeyIndex(
     direction: c_int,
     index: *mut c_int,
 ) -> OfxStatus {
-    panic!("Not implemented!")
+    suite_call!("paramGetKeyIndex", {
+        unsupported::call("paramGetKeyIndex")
+    })
 }
 
 #[allow(unused_variables)]
@@ -850,14 +1003,14 @@ extern "C" fn paramDeleteKey(
     paramHandle: openfx_rs::types::OfxParamHandle,
     time: OfxTime,
 ) -> OfxStatus {
-    ofxstatus::OK
+    suite_call!("paramDeleteKey", { ofxstatus::OK })
 }
 
 #[allow(unused_variables)]
 extern "C" fn paramDeleteAllKeys(
     paramHandle: openfx_rs::types::OfxParamHandle,
 ) -> OfxStatus {
-    ofxstatus::OK
+    suite_call!("paramDeleteAllKeys", { ofxstatus::OK })
 }
 
 #[allow(unused_variables)]
@@ -867,7 +1020,7 @@ extern "C" fn paramCopy(
     dstOffset: OfxTime,
     frameRange: *const OfxRangeD,
 ) -> OfxStatus {
-    panic!("Not implemented!")
+    suite_call!("paramCopy", { unsupported::call("paramCopy") })
 }
 
 #[allow(unused_variables)]
@@ -875,12 +1028,12 @@ extern "C" fn paramEditBegin(
     paramSet: openfx_rs::types::OfxParamSetHandle,
     name: *const c_char,
 ) -> OfxStatus {
-    ofxstatus::OK
+    suite_call!("paramEditBegin", { ofxstatus::OK })
 }
 
 #[allow(unused_variables)]
 extern "C" fn paramEditEnd(paramSet: openfx_rs::types::OfxParamSetHandle) -> OfxStatus {
-    ofxstatus::OK
+    suite_call!("paramEditEnd", { ofxstatus::OK })
 }
 
 pub const PARAMETER_SUITE: OfxParameterSuiteV1 = OfxParameterSuiteV1 {
@@ -922,36 +1075,39 @@ extern "C" fn message_impl(
     messageId: *const c_char,
     message: *const c_char,
 ) -> OfxStatus {
-    let id_str = if messageId.is_null() {
-        OfxStr::from_str("(null)\0")
-    } else {
-        unsafe { OfxStr::from_ptr(messageId) }
-    };
-    output!(
-        "{}",
-        serde_json::to_string(&HashMap::from([
-            (
-                "message_type",
-                unsafe { OfxStr::from_ptr(messageType) }.as_str(),
-            ),
-            ("message_id", id_str.as_str()),
-            ("message", unsafe { OfxStr::from_ptr(message) }.as_str())
-        ]))
-        .unwrap()
-    );
-
-    // TODO: we're assuming handle is a valid effect instance
-    // handle. The spec also allows it to be an effect descriptor
-    // handle, or null.
-    ImageEffectHandle::from(handle)
-        .with_object(|effect| {
-            // Consume a configured response from the effect instance, or
-            // if there are no responses return OK
-            effect
-                .message_suite_responses
-                .pop()
-                .unwrap_or(ofxstatus::OK)
-        })
+    suite_call!("message", {
+        let id_str = if messageId.is_null() {
+            OfxStr::from_str("(null)\0")
+        } else {
+            unsafe { OfxStr::from_ptr(messageId) }
+        };
+        output!(
+            "{}",
+            serde_json::to_string(&HashMap::from([
+                (
+                    "message_type",
+                    unsafe { OfxStr::from_ptr(messageType) }.as_str(),
+                ),
+                ("message_id", id_str.as_str()),
+                ("message", unsafe { OfxStr::from_ptr(message) }.as_str())
+            ]))
+            .unwrap()
+        );
+
+        // TODO: we're assuming handle is a valid effect instance
+        // handle. The spec also allows it to be an effect descriptor
+        // handle, or null.
+        ImageEffectHandle::from(handle)
+            .try_with_object(|effect| {
+                // Consume a configured response from the effect instance, or
+                // if there are no responses return OK
+                effect
+                    .message_suite_responses
+                    .pop()
+                    .unwrap_or(ofxstatus::OK)
+            })
+            .unwrap_or_else(|status| status)
+    })
 }
 
 pub const MESSAGE_SUITE: OfxMessageSuiteV1 = OfxMessageSuiteV1 {
@@ -965,12 +1121,12 @@ extern "C" fn memoryAlloc(
     nBytes: usize,
     allocatedData: *mut *mut c_void,
 ) -> OfxStatus {
-    panic!("Not implemented!")
+    suite_call!("memoryAlloc", { unsupported::call("memoryAlloc") })
 }
 
 #[allow(unused_variables)]
 extern "C" fn memoryFree(allocatedData: *mut c_void) -> OfxStatus {
-    panic!("Not implemented!")
+    suite_call!("memoryFree", { unsupported::call("memoryFree") })
 }
 
 pub const MEMORY_SUITE: OfxMemorySuiteV1 = OfxMemorySuiteV1 {
@@ -986,22 +1142,29 @@ extern "C" fn multiThread(
     nThreads: c_uint,
     customArg: *mut c_void,
 ) -> OfxStatus {
-    panic!("Not implemented!")
+    suite_call!("multiThread", { unsupported::call("multiThread") })
 }
 
 #[allow(unused_variables)]
 extern "C" fn multiThreadNumCPUs(nCPUs: *mut u32) -> OfxStatus {
-    panic!("Not implemented!")
+    suite_call!("multiThreadNumCPUs", {
+        unsupported::call("multiThreadNumCPUs")
+    })
 }
 
 #[allow(unused_variables)]
 extern "C" fn multiThreadIndex(threadIndex: *mut u32) -> OfxStatus {
-    panic!("Not implemented!")
+    suite_call!("multiThreadIndex", {
+        unsupported::call("multiThreadIndex")
+    })
 }
 
 #[allow(unused_variables)]
 extern "C" fn multiThreadIsSpawnedThread() -> c_int {
-    panic!("Not implemented!")
+    suite_call!("multiThreadIsSpawnedThread");
+    unsupported::call("multiThreadIsSpawnedThread");
+    // Not a thread spawned by multiThread
+    0
 }
 
 #[allow(unused_variables)]
@@ -1009,27 +1172,27 @@ extern "C" fn mutexCreate(
     mutex: *mut openfx_sys::OfxMutexHandle,
     lockCount: c_int,
 ) -> OfxStatus {
-    panic!("Not implemented!")
+    suite_call!("mutexCreate", { unsupported::call("mutexCreate") })
 }
 
 #[allow(unused_variables)]
 extern "C" fn mutexDestroy(mutex: openfx_sys::OfxMutexHandle) -> OfxStatus {
-    panic!("Not implemented!")
+    suite_call!("mutexDestroy", { unsupported::call("mutexDestroy") })
 }
 
 #[allow(unused_variables)]
 extern "C" fn mutexLock(mutex: openfx_sys::OfxMutexHandle) -> OfxStatus {
-    panic!("Not implemented!")
+    suite_call!("mutexLock", { unsupported::call("mutexLock") })
 }
 
 #[allow(unused_variables)]
 extern "C" fn mutexUnLock(mutex: openfx_sys::OfxMutexHandle) -> OfxStatus {
-    panic!("Not implemented!")
+    suite_call!("mutexUnLock", { unsupported::call("mutexUnLock") })
 }
 
 #[allow(unused_variables)]
 extern "C" fn mutexTryLock(mutex: openfx_sys::OfxMutexHandle) -> OfxStatus {
-    panic!("Not implemented!")
+    suite_call!("mutexTryLock", { unsupported::call("mutexTryLock") })
 }
 
 pub const MULTI_THREAD_SUITE: OfxMultiThreadSuiteV1 = OfxMultiThreadSuiteV1 {
@@ -0,0 +1,115 @@
+//! Output filename templates, such as
+//! "renders/{instance}_{frame:04}.{ext}".
+use crate::Rect;
+use anyhow::{bail, Context, Result};
+use openfx_rs::types::OfxRectI;
+
+/// Values which can be substituted into a template
+pub struct TemplateValues<'a> {
+    pub instance: &'a str,
+    pub plugin: &'a str,
+    pub ext: &'a str,
+    pub frame: u32,
+    // Padding used for "{frame}" when the template doesn't specify one
+    pub frame_padding: usize,
+    // Bounds of the output image
+    pub bounds: OfxRectI,
+    pub project: OfxRectI,
+}
+
+impl TemplateValues<'_> {
+    fn string(&self, name: &str) -> Option<&str> {
+        match name {
+            "instance" => Some(self.instance),
+            "plugin" => Some(self.plugin),
+            "ext" => Some(self.ext),
+            _ => None,
+        }
+    }
+
+    fn integer(&self, name: &str) -> Option<i64> {
+        Some(match name {
+            "frame" => self.frame as i64,
+            "project_width" => self.project.width() as i64,
+            "project_height" => self.project.height() as i64,
+            "width" => self.bounds.width() as i64,
+            "height" => self.bounds.height() as i64,
+            "x1" => self.bounds.x1 as i64,
+            "y1" => self.bounds.y1 as i64,
+            "x2" => self.bounds.x2 as i64,
+            "y2" => self.bounds.y2 as i64,
+            _ => return None,
+        })
+    }
+
+    fn expand_token(&self, token: &str) -> Result<String> {
+        let (name, spec) = match token.split_once(':') {
+            Some((name, spec)) => (name, Some(spec)),
+            None => (token, None),
+        };
+
+        if let Some(value) = self.string(name) {
+            if spec.is_some() {
+                bail!("Token {{{}}} doesn't take a format", name);
+            }
+            return Ok(value.to_string());
+        }
+
+        let value = self
+            .integer(name)
+            .with_context(|| format!("Unknown token {{{}}}", name))?;
+        // Format is a width, zero-padded if it has a leading 0
+        let (zero_pad, width) = match spec {
+            Some(spec) => (
+                spec.starts_with('0'),
+                spec.parse::<usize>()
+                    .ok()
+                    .with_context(|| format!("Invalid format \"{}\"", spec))?,
+            ),
+            None if name == "frame" => (true, self.frame_padding),
+            None => (false, 0),
+        };
+        Ok(if zero_pad {
+            format!("{:0width$}", value)
+        } else {
+            format!("{:width$}", value)
+        })
+    }
+}
+
+/// Substitute values into a template. Tokens are written in braces,
+/// with an optional width for integers ("{frame:04}"). Use "{{" and
+/// "}}" for literal braces.
+pub fn expand(template: &str, values: &TemplateValues) -> Result<String> {
+    let mut result = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                let rest = chars.as_str();
+                if let Some(rest) = rest.strip_prefix('{') {
+                    result.push('{');
+                    chars = rest.chars();
+                    continue;
+                }
+                let (token, rest) = rest
+                    .split_once('}')
+                    .with_context(|| format!("Unclosed token in \"{}\"", template))?;
+                result.push_str(&values.expand_token(token)?);
+                chars = rest.chars();
+            }
+            '}' => {
+                let rest = chars.as_str();
+                match rest.strip_prefix('}') {
+                    Some(rest) => {
+                        result.push('}');
+                        chars = rest.chars();
+                    }
+                    None => bail!("Unmatched }} in \"{}\"", template),
+                }
+            }
+            c => result.push(c),
+        }
+    }
+    Ok(result)
+}
@@ -0,0 +1,217 @@
+//! Run a directory of command files as a test suite. Each file is run
+//! by a separate process, so it starts with a fresh CommandState and
+//! can't affect other files, even by crashing.
+use crate::crash::CRASH_MARKER;
+use crate::timeout::TIMEOUT_MARKER;
+use crate::{output, read_commands, run_status, Command, CommandResult, CommandStatus};
+use crate::{property_audit, unsupported};
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Results of running one command file
+pub struct TestFile {
+    pub name: String,
+    pub results: Vec<CommandResult>,
+}
+
+// Files which can't be read or parsed are included, so the error is
+// reported when they are run
+fn is_command_file(path: &Path) -> bool {
+    match fs::read_to_string(path).map(|s| serde_json::from_str::<serde_json::Value>(&s))
+    {
+        Ok(Ok(value)) => value.is_array(),
+        _ => true,
+    }
+}
+
+/// Find command files under a directory, in sorted order. JSON files
+/// which don't contain an array, such as presets and raw image
+/// sidecars, are skipped. If the path is a file, just that file is
+/// returned.
+pub fn find_command_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    if dir.is_file() {
+        return Ok(vec![dir.to_path_buf()]);
+    }
+    let mut files = Vec::new();
+    let entries = fs::read_dir(dir)
+        .with_context(|| format!("Reading directory {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(find_command_files(&path)?);
+        } else if path.extension().is_some_and(|e| e == "json") && is_command_file(&path)
+        {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Collect the result records written by a test process. Other lines
+/// (for example, if a plugin writes to stdout) are ignored. If the
+/// process failed without recording an error, for example because it
+/// crashed or timed out, an error record is added for the command
+/// which was running, with the report from stderr if there is one.
+pub fn collect_results(
+    file: &str,
+    commands: Option<&[Command]>,
+    stdout: &str,
+    stderr: &str,
+    exit: Result<(), String>,
+) -> Vec<CommandResult> {
+    let mut results: Vec<CommandResult> = stdout
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+    if let Err(exit) = exit {
+        if run_status(&results) == CommandStatus::Ok {
+            let mut error = vec![format!("Test process failed ({})", exit)];
+            let report = [CRASH_MARKER, TIMEOUT_MARKER]
+                .iter()
+                .filter_map(|marker| stderr.find(marker))
+                .min();
+            match report {
+                Some(start) => error.extend(stderr[start..].lines().map(str::to_string)),
+                None if !stderr.trim().is_empty() => {
+                    error.push(stderr.trim().to_string())
+                }
+                None => {}
+            }
+            let status = if stderr.contains(TIMEOUT_MARKER) {
+                CommandStatus::Timeout
+            } else {
+                CommandStatus::Error
+            };
+            let index = results.len();
+            let command = commands.and_then(|c| c.get(index));
+            results.push(CommandResult {
+                file: None,
+                index,
+                command_type: command.map(|c| c.type_name().to_string()),
+                name: command.and_then(Command::name).map(str::to_string),
+                status,
+                duration_ms: 0.0,
+                output: Vec::new(),
+                error,
+            });
+        }
+    }
+    for result in &mut results {
+        result.file = Some(file.to_string());
+    }
+    results
+}
+
+fn run_file(exe: &Path, file: &str, args: &[String]) -> Vec<CommandResult> {
+    let mut command = process::Command::new(exe);
+    command.args(args).args(["--results", "-", "run", file]);
+    // Only used to describe a command which crashed
+    let commands = read_commands(file).ok();
+    match command.output() {
+        Ok(out) => {
+            let stderr = String::from_utf8_lossy(&out.stderr);
+            unsupported::add_reports(&stderr);
+            property_audit::add_reports(&stderr);
+            collect_results(
+                file,
+                commands.as_deref(),
+                &String::from_utf8_lossy(&out.stdout),
+                &stderr,
+                if out.status.success() {
+                    Ok(())
+                } else {
+                    Err(out.status.to_string())
+                },
+            )
+        }
+        Err(e) => collect_results(
+            file,
+            None,
+            "",
+            "",
+            Err(format!("couldn't run {}: {}", exe.display(), e)),
+        ),
+    }
+}
+
+fn report_file(file: &TestFile, duration: Duration) {
+    let status = run_status(&file.results);
+    let label = match status {
+        CommandStatus::Ok => "PASS",
+        CommandStatus::Failed => "FAIL",
+        CommandStatus::Error => "ERROR",
+        CommandStatus::Timeout => "TIMEOUT",
+    };
+    output!(
+        "{} {} ({} commands, {:.2}s)",
+        label,
+        file.name,
+        file.results.len(),
+        duration.as_secs_f64()
+    );
+    for result in file
+        .results
+        .iter()
+        .filter(|r| r.status != CommandStatus::Ok)
+    {
+        output!("    {}: {}", result.test_name(), result.error.join(": "));
+    }
+}
+
+/// Run every command file under a directory with the runner
+/// executable and the given options, up to jobs at a time. Prints a
+/// line for each file as it finishes, and a summary at the end.
+/// Returns the results of each file, in sorted order.
+pub fn run_tests(
+    exe: &Path,
+    dir: &Path,
+    jobs: usize,
+    args: &[String],
+) -> Result<Vec<TestFile>> {
+    let files = find_command_files(dir)?;
+    if files.is_empty() {
+        bail!("No command files found in {}", dir.display());
+    }
+
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<TestFile>>> =
+        Mutex::new(files.iter().map(|_| None).collect());
+    thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, files.len()) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = files.get(index) else {
+                    break;
+                };
+                let name = path.display().to_string();
+                let start = Instant::now();
+                let file = TestFile {
+                    results: run_file(exe, &name, args),
+                    name,
+                };
+                report_file(&file, start.elapsed());
+                results.lock().unwrap()[index] = Some(file);
+            });
+        }
+    });
+
+    let results: Vec<TestFile> = results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .flatten()
+        .collect();
+    let passed = results
+        .iter()
+        .filter(|f| run_status(&f.results) == CommandStatus::Ok)
+        .count();
+    output!("{} of {} command files passed", passed, results.len());
+    Ok(results)
+}
@@ -0,0 +1,142 @@
+//! Per-action timeouts. If a plugin hangs in an action, the action
+//! and instance are reported and the run is aborted, instead of
+//! hanging until something else kills it.
+use crate::{crash, log_error};
+use std::collections::HashMap;
+use std::sync::{Condvar, LazyLock, Mutex, Once};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Exit status when an action times out
+pub const EXIT_TIMEOUT: i32 = 3;
+
+/// Start of a timeout report in stderr
+pub const TIMEOUT_MARKER: &str = "Timed out after ";
+
+#[derive(Default)]
+struct Timeouts {
+    default: Option<Duration>,
+    // Overrides for individual actions, keyed by action name
+    actions: HashMap<String, Duration>,
+}
+
+static TIMEOUTS: LazyLock<Mutex<Timeouts>> = LazyLock::new(Default::default);
+
+/// Set the timeout for all actions, replacing any previous settings.
+/// Actions in the map use their own timeout instead of the default.
+pub fn set_timeouts(default: Option<Duration>, actions: HashMap<String, Duration>) {
+    *TIMEOUTS.lock().unwrap() = Timeouts { default, actions };
+}
+
+fn timeout_for(action: &str) -> Option<Duration> {
+    let timeouts = TIMEOUTS.lock().unwrap();
+    timeouts.actions.get(action).copied().or(timeouts.default)
+}
+
+/// An action which is running with a timeout
+pub struct Deadline {
+    pub at: Instant,
+    pub timeout: Duration,
+    pub description: String,
+}
+
+/// Deadlines of the actions currently running, on any thread
+#[derive(Default)]
+pub struct Deadlines {
+    next_id: u64,
+    running: HashMap<u64, Deadline>,
+}
+
+impl Deadlines {
+    /// Add a deadline, returning an id to remove it with
+    pub fn add(&mut self, deadline: Deadline) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.running.insert(id, deadline);
+        id
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        self.running.remove(&id);
+    }
+
+    /// The deadline which expires first, if any
+    pub fn earliest(&self) -> Option<&Deadline> {
+        self.running.values().min_by_key(|d| d.at)
+    }
+
+    /// A deadline which has passed at the given time, if any
+    pub fn expired(&self, now: Instant) -> Option<&Deadline> {
+        self.earliest().filter(|d| d.at <= now)
+    }
+}
+
+static DEADLINES: LazyLock<Mutex<Deadlines>> = LazyLock::new(Default::default);
+// Signalled when a deadline is added, so the watchdog can wait for
+// the new earliest one
+static DEADLINE_ADDED: Condvar = Condvar::new();
+static START_WATCHDOG: Once = Once::new();
+
+/// Wait for deadlines to pass. Runs on a single thread for the life
+/// of the process.
+fn watchdog() {
+    let mut deadlines = DEADLINES.lock().unwrap();
+    loop {
+        let now = Instant::now();
+        if let Some(d) = deadlines.expired(now) {
+            log_error!(
+                "{}{} ms in {}",
+                TIMEOUT_MARKER,
+                d.timeout.as_millis(),
+                d.description
+            );
+            log_error!("Last suite calls: {}", crash::last_calls().join(", "));
+            std::process::exit(EXIT_TIMEOUT);
+        }
+        deadlines = match deadlines.earliest() {
+            Some(d) => {
+                let wait = d.at - now;
+                DEADLINE_ADDED.wait_timeout(deadlines, wait).unwrap().0
+            }
+            None => DEADLINE_ADDED.wait(deadlines).unwrap(),
+        };
+    }
+}
+
+/// Removes a deadline when the action returns or panics
+struct Running(u64);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        DEADLINES.lock().unwrap().remove(self.0);
+    }
+}
+
+/// Run an action. If it doesn't finish within the timeout, report it
+/// (using the description, which should identify the action and
+/// instance) and exit with EXIT_TIMEOUT.
+pub fn call_with_timeout<T>(
+    action: &str,
+    describe: impl FnOnce() -> String,
+    f: impl FnOnce() -> T,
+) -> T {
+    let Some(timeout) = timeout_for(action) else {
+        return f();
+    };
+
+    START_WATCHDOG.call_once(|| {
+        thread::Builder::new()
+            .name("watchdog".to_string())
+            .spawn(watchdog)
+            .unwrap();
+    });
+    let description = describe();
+    let id = DEADLINES.lock().unwrap().add(Deadline {
+        at: Instant::now() + timeout,
+        timeout,
+        description,
+    });
+    DEADLINE_ADDED.notify_one();
+    let _running = Running(id);
+    f()
+}
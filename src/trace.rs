@@ -0,0 +1,168 @@
+//! Trace of the suite calls made by plugins. Each call is written as
+//! a JSON line when it returns. Suite functions fill in the details
+//! they know about (handle, property, values and status) as they run.
+use crate::crash;
+use crate::handles::Handle;
+use anyhow::{Context, Result};
+use openfx_rs::types::OfxStatus;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TRACE_FILE: Mutex<Option<LineWriter<File>>> = Mutex::new(None);
+
+thread_local! {
+    static CURRENT: RefCell<Option<TraceEntry>> = const { RefCell::new(None) };
+}
+
+/// Objects which can be referred to by a handle, described by name
+/// in traces
+pub trait DebugName {
+    fn debug_name(&self) -> String;
+}
+
+/// Start tracing suite calls to a file
+pub fn open(filename: &str) -> Result<()> {
+    let file =
+        File::create(filename).with_context(|| format!("Creating {}", filename))?;
+    *TRACE_FILE.lock().unwrap() = Some(LineWriter::new(file));
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Stop tracing and close the file
+#[cfg(test)]
+pub fn close() {
+    ENABLED.store(false, Ordering::Relaxed);
+    *TRACE_FILE.lock().unwrap() = None;
+}
+
+#[derive(Serialize, Debug, Default)]
+struct TraceEntry {
+    suite: &'static str,
+    function: &'static str,
+    action: Option<&'static str>,
+    thread: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    handle: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    property: Option<String>,
+    // Index of the first value
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    values_in: Vec<serde_json::Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    values_out: Vec<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<String>,
+}
+
+fn suite_of(function: &str) -> &'static str {
+    match function {
+        f if f.starts_with("prop") => "property",
+        f if f.starts_with("param") => "parameter",
+        f if f.starts_with("memory") => "memory",
+        f if f.starts_with("multiThread") || f.starts_with("mutex") => "multiThread",
+        "message" => "message",
+        _ => "imageEffect",
+    }
+}
+
+/// A suite call being traced. The entry is written when this is
+/// dropped, at the end of the suite function.
+pub struct Call {
+    // Entry of an enclosing suite call, if any
+    outer: Option<TraceEntry>,
+}
+
+/// Start tracing a suite call. Returns None if tracing is disabled.
+pub fn call(function: &'static str) -> Option<Call> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    let thread = thread::current();
+    let entry = TraceEntry {
+        suite: suite_of(function),
+        function,
+        action: crash::current_action(),
+        thread: thread
+            .name()
+            .map_or_else(|| format!("{:?}", thread.id()), str::to_string),
+        ..Default::default()
+    };
+    let outer = CURRENT.with(|current| current.replace(Some(entry)));
+    Some(Call { outer })
+}
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        let entry = CURRENT.with(|current| current.replace(self.outer.take()));
+        if let Some(entry) = entry {
+            if let Some(file) = TRACE_FILE.lock().unwrap().as_mut() {
+                // Tracing shouldn't affect the plugin, so ignore
+                // write errors
+                let _ = writeln!(file, "{}", serde_json::to_string(&entry).unwrap());
+            }
+        }
+    }
+}
+
+/// Update the entry for the current suite call, if it's being traced
+fn with_entry(f: impl FnOnce(&mut TraceEntry)) {
+    CURRENT.with(|current| {
+        if let Some(entry) = current.borrow_mut().as_mut() {
+            f(entry)
+        }
+    })
+}
+
+/// Record the handle passed to the current call, if it's the first
+pub fn handle(object: &impl DebugName) {
+    with_entry(|e| {
+        if e.handle.is_none() {
+            e.handle = Some(object.debug_name())
+        }
+    })
+}
+
+/// Record the property accessed by the current call
+pub fn property(name: &str, index: Option<usize>) {
+    with_entry(|e| {
+        if e.property.is_none() {
+            e.property = Some(name.to_string());
+            e.index = index;
+        }
+    })
+}
+
+/// Record a value passed to the current call
+pub fn value_in(value: impl Serialize) {
+    with_entry(|e| e.values_in.push(serde_json::to_value(value).unwrap()))
+}
+
+/// Record a value returned by the current call
+pub fn value_out(value: impl Serialize) {
+    with_entry(|e| e.values_out.push(serde_json::to_value(value).unwrap()))
+}
+
+/// Record a handle returned by the current call, by the name of the
+/// object it refers to
+pub fn handle_out(handle: &impl Handle) {
+    with_entry(|e| {
+        let name = match handle.try_as_arc() {
+            Ok(object) => object.lock().debug_name(),
+            Err(message) => message,
+        };
+        e.values_out.push(serde_json::Value::String(name))
+    })
+}
+
+pub fn status(status: OfxStatus) {
+    with_entry(|e| e.status = Some(format!("{:?}", status)))
+}
@@ -0,0 +1,90 @@
+//! Suite functions which the runner doesn't implement. By default
+//! these abort the run. If unsupported calls are allowed, they return
+//! kOfxStatErrUnsupported instead and are listed in a report at the
+//! end of the run, showing which host functionality a plugin used
+//! that wasn't tested.
+use crate::{crash, log_error, trace};
+use openfx_rs::constants::ofxstatus;
+use openfx_rs::types::OfxStatus;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+static ALLOW_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+// Number of calls, keyed by function and the action it was called in
+static CALLS: Mutex<BTreeMap<(String, String), u32>> = Mutex::new(BTreeMap::new());
+
+pub fn set_allowed(allowed: bool) {
+    ALLOW_UNSUPPORTED.store(allowed, Ordering::Relaxed);
+}
+
+/// Handle a call to an unimplemented suite function
+pub fn call(function: &str) -> OfxStatus {
+    if !ALLOW_UNSUPPORTED.load(Ordering::Relaxed) {
+        panic!("{} is not implemented", function);
+    }
+    let action = crash::current_action().unwrap_or("no action");
+    log_error!("{} is not supported (called in {})", function, action);
+    *CALLS
+        .lock()
+        .unwrap()
+        .entry((function.to_string(), action.to_string()))
+        .or_default() += 1;
+    trace::status(ofxstatus::ErrUnsupported);
+    ofxstatus::ErrUnsupported
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct UnsupportedCall {
+    pub function: String,
+    pub action: String,
+    pub count: u32,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Report {
+    unsupported_calls: Vec<UnsupportedCall>,
+}
+
+/// Unsupported functions which have been called, sorted by function
+/// and action
+pub fn calls() -> Vec<UnsupportedCall> {
+    CALLS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|((function, action), &count)| UnsupportedCall {
+            function: function.clone(),
+            action: action.clone(),
+            count,
+        })
+        .collect()
+}
+
+/// Add the calls from reports in the error output of another run,
+/// such as a test process. Other lines are ignored.
+pub fn add_reports(output: &str) {
+    let mut calls = CALLS.lock().unwrap();
+    for report in output
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Report>(line).ok())
+    {
+        for call in report.unsupported_calls {
+            *calls.entry((call.function, call.action)).or_default() += call.count;
+        }
+    }
+}
+
+/// Print the unsupported functions which were called, if any, to the
+/// error stream so they stay out of the results
+pub fn report() {
+    let unsupported_calls = calls();
+    if !unsupported_calls.is_empty() {
+        log_error!(
+            "{}",
+            serde_json::to_string(&Report { unsupported_calls }).unwrap()
+        );
+    }
+}
@@ -6,114 +6,96 @@
 #include <stdlib.h>
 #include <string.h>
 
-int param_value_count(void *);
-int param_get_value_1(void *handle, void *value);
-int param_get_value_2(void *handle, void *value1, void *value2);
-int param_get_value_3(void *handle, void *value1, void *value2, void *value3);
-int param_get_value_4(void *handle, void *value1, void *value2, void *value3, void *value4);
+int param_value_count(void *handle);
 const char *param_get_type(void *handle);
-void param_set_value_boolean(void *handle, int value);
-void param_set_value_integer(void *handle, int value);
-void param_set_value_choice(void *handle, int value);
-void param_set_value_double(void *handle, double value);
-void param_set_value_string(void *handle, const char *value);
+/* values always has 4 elements, the most any param type has */
+int param_get_value(void *handle, void *values[4]);
+int param_get_value_at_time(void *handle, double time, void *values[4]);
+int param_set_value(void *handle, int int_value, double double_value,
+                    const char *string_value);
+int param_set_value_at_time(void *handle, double time, int int_value,
+                            double double_value, const char *string_value);
 int message_impl(void *handle, const char *messageType, const char *messageId,
                  const char *message);
 
-int paramGetValue (void *paramHandle, ...) {
+/* Read the value pointers passed to paramGetValue or
+   paramGetValueAtTime. Nothing is read for a bad handle, which is
+   reported by param_get_value. */
+static void read_value_pointers(void *paramHandle, va_list ap, void *vals[4]) {
   int count = param_value_count(paramHandle);
   assert(count <= 4);
-  void *vals[4];
-
-  va_list ap;
-  va_start (ap, paramHandle);
   for (int i = 0; i < count; i++) {
     vals[i] = va_arg (ap, void*);
   }
+}
 
-  switch (count) {
-  case 1:
-    return param_get_value_1(paramHandle, vals[0]);
-    break;
-  case 2:
-    return param_get_value_2(paramHandle, vals[0], vals[1]);
-    break;
-  case 3:
-    return param_get_value_3(paramHandle, vals[0], vals[1], vals[2]);
-    break;
-  case 4:
-    return param_get_value_4(paramHandle, vals[0], vals[1], vals[2], vals[3]);
-    break;
-  default:
-    return 1;                   /* OfxStatus::Failed */
-  }
+int paramGetValue (void *paramHandle, ...) {
+  void *vals[4] = {NULL};
+
+  va_list ap;
+  va_start (ap, paramHandle);
+  read_value_pointers(paramHandle, ap, vals);
+  va_end (ap);
+
+  return param_get_value(paramHandle, vals);
 }
 
 int paramGetValueAtTime (void *paramHandle, double time, ...) {
-  int count = param_value_count(paramHandle);
-  assert(count <= 3);
-  void *vals[3];
+  void *vals[4] = {NULL};
 
   va_list ap;
   va_start (ap, time);
-  for (int i = 0; i < count; i++) {
-    vals[i] = va_arg (ap, void*);
-  }
+  read_value_pointers(paramHandle, ap, vals);
+  va_end (ap);
 
-  switch (count) {
-  case 1:
-    return param_get_value_1(paramHandle, vals[0]);
-    break;
-  case 2:
-    return param_get_value_2(paramHandle, vals[0], vals[1]);
-    break;
-  case 3:
-    return param_get_value_3(paramHandle, vals[0], vals[1], vals[2]);
-  default:
-    return 1;                   /* OfxStatus::Failed */
-  }
+  return param_get_value_at_time(paramHandle, time, vals);
 }
 
-int paramSetValue(void *paramHandle, ...) {
-  va_list ap;
-  va_start (ap, paramHandle);
+/* Read the value passed to paramSetValue or paramSetValueAtTime,
+   according to the param type. Nothing is read for a bad handle or
+   an unsupported type, which are reported by param_set_value. */
+static void read_set_value(void *paramHandle, va_list ap, int *int_value,
+                           double *double_value, const char **string_value) {
   const char *type = param_get_type(paramHandle);
-  if (!strcmp(type, "OfxParamTypeBoolean")) {
-    param_set_value_boolean(paramHandle, va_arg(ap, int));
-  } else if (!strcmp(type, "OfxParamTypeInteger")) {
-    param_set_value_integer(paramHandle, va_arg(ap, int));
+  if (!type) {
+    return;
+  }
+  if (!strcmp(type, "OfxParamTypeBoolean") ||
+      !strcmp(type, "OfxParamTypeInteger") ||
+      !strcmp(type, "OfxParamTypeChoice")) {
+    *int_value = va_arg(ap, int);
   } else if (!strcmp(type, "OfxParamTypeDouble")) {
-    param_set_value_double(paramHandle, va_arg(ap, double));
+    *double_value = va_arg(ap, double);
   } else if (!strcmp(type, "OfxParamTypeString")) {
-    param_set_value_string(paramHandle, va_arg(ap, char*));
-  } else if (!strcmp(type, "OfxParamTypeChoice")) {
-    param_set_value_choice(paramHandle, va_arg(ap, int));
-  } else {
-    return 1;                   /* OfxStatus::Failed */
+    *string_value = va_arg(ap, char*);
   }
+}
+
+int paramSetValue(void *paramHandle, ...) {
+  int int_value = 0;
+  double double_value = 0.0;
+  const char *string_value = NULL;
 
-  return 0;
+  va_list ap;
+  va_start (ap, paramHandle);
+  read_set_value(paramHandle, ap, &int_value, &double_value, &string_value);
+  va_end (ap);
+
+  return param_set_value(paramHandle, int_value, double_value, string_value);
 }
 
 int paramSetValueAtTime(void *paramHandle, double time, ...) {
+  int int_value = 0;
+  double double_value = 0.0;
+  const char *string_value = NULL;
+
   va_list ap;
   va_start (ap, time);
-  const char *type = param_get_type(paramHandle);
-  if (!strcmp(type, "OfxParamTypeBoolean")) {
-    param_set_value_boolean(paramHandle, va_arg(ap, int));
-  } else if (!strcmp(type, "OfxParamTypeInteger")) {
-    param_set_value_integer(paramHandle, va_arg(ap, int));
-  } else if (!strcmp(type, "OfxParamTypeDouble")) {
-    param_set_value_double(paramHandle, va_arg(ap, double));
-  } else if (!strcmp(type, "OfxParamTypeString")) {
-    param_set_value_string(paramHandle, va_arg(ap, char*));
-  } else if (!strcmp(type, "OfxParamTypeChoice")) {
-    param_set_value_choice(paramHandle, va_arg(ap, int));
-  } else {
-    return 1;                   /* OfxStatus::Failed */
-  }
+  read_set_value(paramHandle, ap, &int_value, &double_value, &string_value);
+  va_end (ap);
 
-  return 0;
+  return param_set_value_at_time(paramHandle, time, int_value, double_value,
+                                 string_value);
 }
 
 int message(void *handle, const char *messageType, const char *messageId,